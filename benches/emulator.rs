@@ -0,0 +1,280 @@
+//! Runs the bundled test ROMs and a synthetic ALU heavy ROM headlessly, as fast as they go, and
+//! reports how fast the emulator ran each of them
+//!
+//! ```text
+//! cargo bench --bench emulator -- [FILTER] [--frames N] [--save FILE] [--baseline FILE] [--threshold PERCENT]
+//! ```
+//!
+//! `--save` writes the results to a file that a later run can be checked against with
+//! `--baseline`, which fails if any ROM got more than `--threshold` percent slower. Building with
+//! `--features profile` also splits the time up between the CPU, memory and graphics, which slows
+//! everything down, so only compare profiled runs with other profiled runs
+
+use JEmulator::cartridge::write_header_checksum;
+use JEmulator::gameboy::{ GameBoy, CYCLES_PER_FRAME };
+use JEmulator::profile::{ self, Subsystem, Timing };
+
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant };
+
+/// About ten seconds of Game Boy time
+const DEFAULT_FRAMES: u32 = 600;
+const DEFAULT_THRESHOLD: f64 = 10.0;
+
+const LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+struct Options {
+    filter: Option<String>,
+    frames: u32,
+    save: Option<PathBuf>,
+    baseline: Option<PathBuf>,
+    threshold: f64,
+}
+
+impl Options {
+
+    fn parse() -> Self {
+        let mut options = Self {
+            filter: None,
+            frames: DEFAULT_FRAMES,
+            save: None,
+            baseline: None,
+            threshold: DEFAULT_THRESHOLD,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().unwrap_or_else(|| panic!("{} needs a value", name));
+            match arg.as_str() {
+                "--frames" => options.frames = value("--frames").parse().expect("--frames is a number"),
+                "--save" => options.save = Some(value("--save").into()),
+                "--baseline" => options.baseline = Some(value("--baseline").into()),
+                "--threshold" => options.threshold = value("--threshold").parse().expect("--threshold is a number"),
+                // Passed along by cargo bench
+                "--bench" => (),
+                _ if arg.starts_with("--") => panic!("Unknown option: {}", arg),
+                _ => options.filter = Some(arg),
+            }
+        }
+
+        options
+    }
+
+}
+
+struct BenchResult {
+    name: String,
+    frames: u32,
+    elapsed: Duration,
+    timings: [Timing; 5],
+}
+
+impl BenchResult {
+
+    /// Game Boy clock cycles run per second of real time, in millions. A real Game Boy runs at
+    /// about 4.19
+    fn mhz(&self) -> f64 {
+        self.frames as f64 * CYCLES_PER_FRAME as f64 / self.elapsed.as_secs_f64() / 1_000_000.0
+    }
+
+    fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+}
+
+fn main() {
+    let options = Options::parse();
+
+    let mut roms: Vec<(String, Vec<u8>)> = vec![("synthetic ALU".to_string(), alu_rom())];
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .expect("the roms directory is readable")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        roms.push((name, std::fs::read(&path).expect("the ROM is readable")));
+    }
+
+    if let Some(filter) = &options.filter {
+        roms.retain(|(name, _)| name.contains(filter.as_str()));
+    }
+
+    let mut results = Vec::new();
+    for (name, rom) in roms {
+        match run(&name, rom, options.frames) {
+            Ok(result) => results.push(result),
+            Err(e) => eprintln!("Skipping {}: {}", name, e),
+        }
+    }
+
+    // Test ROMs print their results over serial, so the table goes after all of that
+    println!();
+    report(&results);
+
+    if let Some(path) = &options.save {
+        let lines: String = results.iter().map(|r| format!("{}\t{:.3}\n", r.name, r.mhz())).collect();
+        std::fs::write(path, lines).expect("the results can be saved");
+        println!("Saved results to {}", path.display());
+    }
+
+    if let Some(path) = &options.baseline {
+        if !compare(&results, path, options.threshold) {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(name: &str, rom: Vec<u8>, frames: u32) -> JEmulator::error::Result<BenchResult> {
+    let mut gameboy = GameBoy::new(rom, None)?;
+
+    profile::take();
+    let start = Instant::now();
+    for _ in 0..frames {
+        gameboy.run_frame();
+    }
+    let elapsed = start.elapsed();
+
+    Ok(BenchResult { name: name.to_string(), frames, elapsed, timings: profile::take() })
+}
+
+fn report(results: &[BenchResult]) {
+    println!("{:<28} {:>9} {:>9} {:>9}", "ROM", "MHz", "fps", "speed");
+    for result in results {
+        println!(
+            "{:<28} {:>9.2} {:>9.1} {:>8.1}x",
+            result.name,
+            result.mhz(),
+            result.fps(),
+            result.fps() * CYCLES_PER_FRAME as f64 / JEmulator::apu::CLOCK_SPEED as f64,
+        );
+
+        if profile::ENABLED {
+            let mut measured = Duration::ZERO;
+            for (subsystem, timing) in Subsystem::ALL.iter().zip(result.timings.iter()) {
+                measured += timing.time;
+                breakdown(subsystem.name(), timing.time, result.elapsed, Some(timing.calls));
+            }
+            breakdown("CPU execute and the rest", result.elapsed.saturating_sub(measured), result.elapsed, None);
+        }
+    }
+}
+
+fn breakdown(name: &str, time: Duration, total: Duration, calls: Option<u64>) {
+    let share = time.as_secs_f64() / total.as_secs_f64() * 100.0;
+    match calls {
+        Some(calls) if calls > 0 => println!(
+            "    {:<26} {:>5.1}%  {:>12} calls  {:>6.1}ns each",
+            name,
+            share,
+            calls,
+            time.as_nanos() as f64 / calls as f64,
+        ),
+        _ => println!("    {:<26} {:>5.1}%", name, share),
+    }
+}
+
+/// Checks the results against a file written by `--save`. Returns false if anything got slower
+/// by more than `threshold` percent
+fn compare(results: &[BenchResult], path: &Path, threshold: f64) -> bool {
+    let baseline = std::fs::read_to_string(path).expect("the baseline is readable");
+
+    let mut passed = true;
+    println!();
+    for line in baseline.lines() {
+        let Some((name, mhz)) = line.split_once('\t') else { continue };
+        let Ok(before) = mhz.parse::<f64>() else { continue };
+        let Some(result) = results.iter().find(|r| r.name == name) else { continue };
+
+        let change = (result.mhz() - before) / before * 100.0;
+        let regressed = change < -threshold;
+        passed &= !regressed;
+        println!(
+            "{:<28} {:>9.2} -> {:>9.2} MHz {:>+7.1}%{}",
+            name,
+            before,
+            result.mhz(),
+            change,
+            if regressed { "  REGRESSED" } else { "" },
+        );
+    }
+
+    if !passed {
+        println!("Slower than the baseline by more than {}%", threshold);
+    }
+    passed
+}
+
+/// A 32KB ROM that spins through arithmetic, logic, rotates and a little memory traffic forever,
+/// so the CPU is exercised without the test ROMs waiting on the screen or timer
+fn alu_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+
+    // Entry point, jumps over the header
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x104..0x134].copy_from_slice(&LOGO);
+    rom[0x134..0x134 + 9].copy_from_slice(b"ALU BENCH");
+    // Marked as working on a CGB, which starts the cartridge straight away instead of spending the
+    // first couple of seconds in the DMG boot ROM
+    rom[0x143] = 0x80;
+
+    let program: &[u8] = &[
+        0xF3,             // DI
+        0x31, 0xFE, 0xFF, // LD SP, 0xFFFE
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        // Loop
+        0x80,             // ADD A, B
+        0x89,             // ADC A, C
+        0x92,             // SUB D
+        0x9B,             // SBC A, E
+        0xA4,             // AND H
+        0xAD,             // XOR L
+        0xB0,             // OR B
+        0xB9,             // CP C
+        0x27,             // DAA
+        0x3C,             // INC A
+        0x0C,             // INC C
+        0x14,             // INC D
+        0x1D,             // DEC E
+        0xC6, 0x37,       // ADD A, 0x37
+        0xEE, 0x5A,       // XOR 0x5A
+        0xCB, 0x37,       // SWAP A
+        0xCB, 0x11,       // RL C
+        0xCB, 0x7A,       // BIT 7, D
+        0x07,             // RLCA
+        0x1F,             // RRA
+        0x86,             // ADD A, (HL)
+        0x77,             // LD (HL), A
+        0x2C,             // INC L
+        0x03,             // INC BC
+        0x09,             // ADD HL, BC
+        0x26, 0xC0,       // LD H, 0xC0
+        0xC5,             // PUSH BC
+        0xC1,             // POP BC
+    ];
+    let start = 0x150;
+    let loop_start = start + 7;
+    rom[start..start + program.len()].copy_from_slice(program);
+
+    // JR back to the start of the loop
+    let jr = start + program.len();
+    rom[jr] = 0x18;
+    rom[jr + 1] = (loop_start as isize - (jr + 2) as isize) as i8 as u8;
+
+    // The boot ROM won't start a cartridge with the wrong header checksum
+    write_header_checksum(&mut rom);
+    let global = rom.iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+    rom[0x14E..0x150].copy_from_slice(&global.to_be_bytes());
+
+    rom
+}
@@ -0,0 +1,171 @@
+//! Generates the opcode decode tables from `src/opcodes.json`, so the emulator indexes a static
+//! array per instruction instead of parsing the table when it starts up
+
+use serde_json::Value;
+
+use std::fmt::Write;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/opcodes.json");
+
+    let json = std::fs::read_to_string("src/opcodes.json").expect("src/opcodes.json is readable");
+    let v: Value = serde_json::from_str(&json).expect("src/opcodes.json is valid json");
+
+    // Opcodes missing from the json don't exist on the Game Boy
+    let mut unprefixed = vec![None; 256];
+    let mut prefixed = vec![None; 256];
+
+    for object in v.as_array().expect("the opcode table is an array") {
+        let code = u8::from_str_radix(&object["opcode"].as_str().unwrap().replace("0x", ""), 16).unwrap();
+        let opcode = opcode(code, object);
+
+        if object.get("prefix").is_none() {
+            unprefixed[code as usize] = Some(opcode);
+        } else {
+            prefixed[code as usize] = Some(opcode);
+        }
+    }
+
+    let mut out = String::new();
+    write_table(&mut out, "UNPREFIXED", "Decoded opcodes, indexed by their first byte", unprefixed);
+    write_table(&mut out, "PREFIXED", "Decoded opcodes that follow a 0xCB prefix", prefixed);
+
+    let path = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("opcodes.rs");
+    std::fs::write(path, out).expect("the decode table can be written to OUT_DIR");
+}
+
+fn write_table(out: &mut String, name: &str, doc: &str, table: Vec<Option<String>>) {
+    writeln!(out, "/// {}", doc).unwrap();
+    writeln!(out, "pub static {}: [OpCode; 256] = [", name).unwrap();
+    for (code, opcode) in table.into_iter().enumerate() {
+        let opcode = opcode.unwrap_or_else(|| format!(
+            "OpCode {{ code: {:#04x}, instruction: Instruction::ILLEGAL, flags: {}, cycles: 4, extra_data: Take::None }}",
+            code,
+            flags(&["-", "-", "-", "-"]),
+        ));
+        writeln!(out, "    {},", opcode).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+}
+
+/// Rust source for the `OpCode` a json object describes
+fn opcode(code: u8, object: &Value) -> String {
+    let operand = |i: usize| object["operands"][i].as_str().unwrap();
+    let mnemonic = object["mnemonic"].as_str().unwrap();
+
+    let flags_array: Vec<&str> = object["flagsZNHC"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|flag| flag.as_str().unwrap())
+        .collect();
+
+    let cycles = object["cycles"].as_u64().unwrap();
+
+    let instruction = match mnemonic {
+        "NOP" | "STOP" | "HALT" | "EI" | "DI" | "RLCA" | "RLA" | "RRCA" | "RRA" | "DAA" | "SCF"
+        | "CPL" | "CCF" | "RETI" | "PREFIX" => format!("Instruction::{}", mnemonic),
+        "INC" | "DEC" | "SUB" | "AND" | "XOR" | "OR" | "CP" | "POP" | "PUSH" | "RLC" | "RRC"
+        | "RL" | "RR" | "SLA" | "SRA" | "SWAP" | "SRL" => {
+            format!("Instruction::{}({})", mnemonic, register(operand(0)))
+        },
+        // The A these take is implied, only the second operand is used
+        "ADC" | "SBC" => format!("Instruction::{}({})", mnemonic, register(operand(1))),
+        "LD" if operand(1) == "SP+r8" => format!("Instruction::LDASP({})", empty()),
+        "LD" | "LDH" | "LDINC" | "LDDEC" | "ADD" => format!(
+            "Instruction::{}({}, {})",
+            mnemonic,
+            register(operand(0)),
+            register(operand(1)),
+        ),
+        "JR" => format!("Instruction::JR({}, {})", condition(operand(0)), reg("Const8(0)", false)),
+        "JP" if code == 0xE9 => format!("Instruction::JP(Condition::Always, {})", reg("HL", false)),
+        "JP" | "CALL" => format!("Instruction::{}({}, {})", mnemonic, condition(operand(0)), empty()),
+        "RET" if object["operands"].get(0).is_none() => "Instruction::RET(Condition::Always)".to_string(),
+        "RET" => format!("Instruction::RET({})", condition(operand(0))),
+        "RST" => format!("Instruction::RST({:#06x})", u16::from_str_radix(&operand(0).replace('H', ""), 16).unwrap()),
+        "BIT" | "RES" | "SET" => format!(
+            "Instruction::{}({}, {})",
+            mnemonic,
+            operand(0).parse::<u8>().unwrap(),
+            register(operand(1)),
+        ),
+        _ => panic!("Instruction doesn't exist: {}", mnemonic),
+    };
+
+    // Which operand the bytes after the opcode replace. Only loads ever take them as the first
+    let slot = match mnemonic {
+        "LDH" | "LD" if operand(1) == "SP+r8" || is_const(operand(0)) => "Operand::First",
+        _ => "Operand::Second",
+    };
+
+    let extra_data = match object["bytes"].as_u64().unwrap() {
+        1 => "Take::None".to_string(),
+        2 if object.get("prefix").is_none() => format!("Take::Eight({})", slot),
+        2 => "Take::None".to_string(),
+        3 => format!("Take::Sixteen({})", slot),
+        _ => unreachable!(),
+    };
+
+    format!(
+        "OpCode {{ code: {:#04x}, instruction: {}, flags: {}, cycles: {}, extra_data: {} }}",
+        code,
+        instruction,
+        flags(&flags_array),
+        cycles,
+        extra_data,
+    )
+}
+
+fn reg(register: &str, pointer: bool) -> String {
+    format!("RegisterData {{ register: Register::{}, pointer: {} }}", register, pointer)
+}
+
+fn empty() -> String {
+    reg("None", false)
+}
+
+/// An operand such as `(HL+)` or `d8`. Constants are left as `Register::None` until they are read
+fn register(value: &str) -> String {
+    let pointer = value.contains('(');
+
+    let register = match value.replace(['(', ')', '-', '+'], "").as_str() {
+        r @ ("A" | "F" | "B" | "C" | "D" | "E" | "H" | "L" | "AF" | "BC" | "DE" | "HL" | "SP" | "PC") => {
+            r.to_string()
+        },
+        "d16" | "a16" | "d8" | "r8" | "a8" => "None".to_string(),
+        _ => panic!("Register couldn't be deciphered: {}", value),
+    };
+
+    reg(&register, pointer)
+}
+
+/// The condition of a jump, which is left out of the operands when it always happens
+fn condition(value: &str) -> String {
+    match value {
+        "NC" | "C" | "NZ" | "Z" => format!("Condition::{}", value),
+        _ if is_const(value) => "Condition::Always".to_string(),
+        _ => panic!("Condition couldn't be deciphered: {}", value),
+    }
+}
+
+fn is_const(value: &str) -> bool {
+    matches!(value.replace(['(', ')'], "").as_str(), "d16" | "a16" | "d8" | "r8" | "a8")
+}
+
+fn flags(flags: &[&str]) -> String {
+    let flag = |str: &str| match str {
+        "0" => "Some((false, false))",
+        "1" => "Some((false, true))",
+        _ => "Some((true, false))",
+    };
+
+    format!(
+        "Flags {{ z: {}, n: {}, hc: {}, c: {} }}",
+        flag(flags[0]),
+        flag(flags[1]),
+        flag(flags[2]),
+        flag(flags[3]),
+    )
+}
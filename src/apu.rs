@@ -1,4 +1,15 @@
 // TODO: Constants
+mod noise;
+mod square;
+mod units;
+mod wave;
+
+use noise::Noise;
+use square::Square;
+use wave::Wave;
+
+use crate::audio::{ AudioOutput, AudioSink };
+
 /// Only bit 7 can be written to, Bits 0-3 can be read only
 const MASTER_CONTROL_LOC: u16 = 0xFF26;
 const MASTER_PANNING_LOC: u16 = 0xFF25;
@@ -50,135 +61,231 @@ pub mod C4 {
     pub const CONTROL_LOC: u16 = 0xFF23;
 }
 
-const WAVE_PATTERN_RAM_SIZE: usize = 0x10;
+/// Clock cycles per second, the APU always runs at this speed even in CGB double speed
+pub const CLOCK_SPEED: u64 = 4_194_304;
+
+/// Bits of each register from NR10 to NR52 that always read back as 1, the write only and
+/// unused ones
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+];
 
 /// Represents the state related to the audio processing unit
-/// This is *not* implemented currently
 pub struct APU {
-    c1_sweep: u8,
-    c1_length: u8,
-    c1_volume_envelope: u8,
-    c1_frequency_lo: u8,
-    c1_frequency_hi: u8,
-
-    c2_lenght: u8,
-    c2_volume_envelope: u8,
-    c2_frequency_lo: u8,
-    c2_frequency_hi: u8,
-
-    c3_toggle: u8,
-    c3_length: u8,
-    c3_volume: u8,
-    c3_frequncy_lo: u8,
-    c3_frequency_hi: u8,
-    c3_pattern_ram: [u8; WAVE_PATTERN_RAM_SIZE],
-
-    c4_length: u8,
-    c4_volume_envelope: u8,
-    c4_frequency: u8,
-    c4_control: u8,
+    channel1: Square,
+    channel2: Square,
+    channel3: Wave,
+    channel4: Noise,
 
+    /// NR50, bits 4-6 are the left volume and bits 0-2 the right
     master_volume: u8,
+    /// NR51, bits 4-7 send channels 1-4 to the left and bits 0-3 to the right
     sound_output: u8,
-    sound_toggle: u8,
+    /// NR52 bit 7, every register but wave RAM is cleared and read only while it is off
+    powered: bool,
+
+    /// Which of the 8 steps of the frame sequencer runs next
+    sequencer_step: u8,
+    /// Last level of the DIV bit whose falling edge clocks the frame sequencer
+    div_bit: bool,
+
+    /// Where the audio goes, nothing is mixed while there isn't a sink
+    output: Option<AudioOutput>,
 }
 
 impl std::default::Default for APU {
     fn default() -> Self {
         Self {
-            c1_sweep: 0,
-            c1_length: 0,
-            c1_volume_envelope: 0,
-            c1_frequency_lo: 0,
-            c1_frequency_hi: 0,
-
-            c2_lenght: 0,
-            c2_volume_envelope: 0,
-            c2_frequency_lo: 0,
-            c2_frequency_hi: 0,
-
-            c3_toggle: 0,
-            c3_length: 0,
-            c3_volume: 0,
-            c3_frequncy_lo: 0,
-            c3_frequency_hi: 0,
-            c3_pattern_ram: [0; WAVE_PATTERN_RAM_SIZE],
-
-            c4_length: 0,
-            c4_volume_envelope: 0,
-            c4_frequency: 0,
-            c4_control: 0,
+            channel1: Square::default(),
+            channel2: Square::default(),
+            channel3: Wave::default(),
+            channel4: Noise::default(),
 
             master_volume: 0,
             sound_output: 0,
-            sound_toggle: 0,
+            powered: true,
+
+            sequencer_step: 0,
+            div_bit: false,
+
+            output: None,
+        }
+    }
+}
+
+impl APU {
+
+    /// Runs the channels for some clock cycles. `div_bit` is DIV bit 4 (bit 5 in double speed),
+    /// the frame sequencer steps each time it falls
+    pub fn tick(&mut self, ticks: u8, div_bit: bool) {
+        if self.powered {
+            if self.div_bit && !div_bit {
+                self.step_sequencer();
+            }
+
+            self.channel1.step(ticks as u16);
+            self.channel2.step(ticks as u16);
+            self.channel3.step(ticks as u16);
+            self.channel4.step(ticks as u16);
+        }
+        self.div_bit = div_bit;
+
+        if self.output.is_some() {
+            let levels = self.mix();
+            if let Some(output) = &mut self.output {
+                output.update(ticks, levels);
+            }
         }
     }
+
+    /// Sends the audio to `sink` from now on, or stops making audio at all with `None`
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        self.output = sink.map(AudioOutput::new);
+    }
+
+    /// Makes `scale` times as many samples for every second of emulated time. Frontends nudge
+    /// this around 1.0 to keep their audio buffer from slowly filling up or running dry
+    pub fn set_audio_rate_scale(&mut self, scale: f64) {
+        if let Some(output) = &mut self.output {
+            output.set_rate_scale(scale);
+        }
+    }
+
+    /// Plays the audio back `speed` times as fast without changing its pitch, for when the
+    /// emulator isn't running at full speed
+    pub fn set_audio_time_stretch(&mut self, speed: f64) {
+        if let Some(output) = &mut self.output {
+            output.set_time_stretch(speed);
+        }
+    }
+
+    /*
+     *  Frame sequencer steps, one every 512Hz:
+     *  Step   0    1    2    3    4    5    6    7
+     *  Length Clk  -    Clk  -    Clk  -    Clk  -
+     *  Sweep  -    -    Clk  -    -    -    Clk  -
+     *  Volume -    -    -    -    -    -    -    Clk
+     */
+    fn step_sequencer(&mut self) {
+        if self.sequencer_step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.channel1.clock_sweep();
+        }
+
+        if self.sequencer_step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    /// Mixes the channels into a left and right sample between 0.0 and 1.0
+    fn mix(&self) -> (f32, f32) {
+        let outputs = [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ];
+
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, output) in outputs.into_iter().enumerate() {
+            let level = output as f32 / 15.0;
+            if self.sound_output & (0b0001_0000 << i) != 0 {
+                left += level;
+            }
+            if self.sound_output & (0b0000_0001 << i) != 0 {
+                right += level;
+            }
+        }
+
+        let left_volume = (((self.master_volume >> 4) & 0b111) + 1) as f32 / 8.0;
+        let right_volume = ((self.master_volume & 0b111) + 1) as f32 / 8.0;
+
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            // Wave RAM is the only thing that survives the APU turning off
+            let pattern_ram = self.channel3.pattern_ram;
+            self.channel1 = Square::default();
+            self.channel2 = Square::default();
+            self.channel3 = Wave::default();
+            self.channel3.pattern_ram = pattern_ram;
+            self.channel4 = Noise::default();
+            self.master_volume = 0;
+            self.sound_output = 0;
+        } else if !self.powered && powered {
+            self.sequencer_step = 0;
+        }
+
+        self.powered = powered;
+    }
+
 }
 
 impl crate::mmu::Memory for APU {
     
     fn handle_read(&self, index: u16) -> u8 {
-        match index {
+        let value = match index {
             MASTER_VOLUME_LOC => self.master_volume,
             MASTER_PANNING_LOC => self.sound_output,
-            MASTER_CONTROL_LOC => self.sound_toggle,
-
-            C1::SWEEP_LOC => self.c1_sweep,
-            C1::LENGTH_LOC => self.c1_length,
-            C1::VOLUME_ENVELOPE_LOC => self.c1_volume_envelope,
-            C1::FREQUENCY_LOW_LOC => 0xFF,
-            C1::FREQUENCY_HIGH_LOC => self.c1_frequency_hi & 0b0010_0000,
-
-            C2::LENGTH_LOC => self.c2_lenght & 0b1100_0000,
-            C2::VOLUME_ENVELOPE_LOC => self.c2_volume_envelope,
-            C2::FREQUENCY_LOW_LOC => 0xFF,
-            C2::FREQUENCY_HIGH_LOC => self.c2_frequency_hi & 0b0010_0000,
-
-            C3::TOGGLE_LOC => self.c3_toggle,
-            C3::LENGTH_LOC => 0xFF,
-            C3::VOLUME_LOC => self.c3_volume,
-            C3::FREQUENCY_LOW_LOC => 0xFF,
-            C3::FREQUENCY_HIGH_LOC => self.c3_frequency_hi & 0b0100_0000,
-            C3::PATTERN_RAM_START..=C3::PATTERN_RAM_STOP => self.c3_pattern_ram[(index - C3::PATTERN_RAM_START) as usize],
-            
-            C4::LENGTH_LOC => self.c4_length,
-            C4::VOLUME_ENVELOPE_LOC => self.c4_volume_envelope,
-            C4::FREQUENCY_LOC => self.c4_frequency,
-            C4::CONTROL_LOC => self.c4_control & 0b1000_0000,
+            MASTER_CONTROL_LOC => {
+                (self.powered as u8) << 7
+                    | (self.channel4.enabled as u8) << 3
+                    | (self.channel3.enabled as u8) << 2
+                    | (self.channel2.enabled as u8) << 1
+                    | self.channel1.enabled as u8
+            },
+
+            C1::SWEEP_LOC..=C1::FREQUENCY_HIGH_LOC => self.channel1.read(index - C1::SWEEP_LOC),
+            C2::LENGTH_LOC..=C2::FREQUENCY_HIGH_LOC => self.channel2.read(index - C2::LENGTH_LOC + 1),
+            C3::TOGGLE_LOC..=C3::FREQUENCY_HIGH_LOC => self.channel3.read(index - C3::TOGGLE_LOC),
+            C3::PATTERN_RAM_START..=C3::PATTERN_RAM_STOP => return self.channel3.pattern_ram[(index - C3::PATTERN_RAM_START) as usize],
+            C4::LENGTH_LOC..=C4::CONTROL_LOC => self.channel4.read(index - C4::LENGTH_LOC + 1),
 
             _ => unreachable!("APU doesn't handle this memory")
-        }
+        };
+
+        value | READ_MASKS[(index - C1::SWEEP_LOC) as usize]
     }
 
     fn handle_write(&mut self, index: u16, val: u8) {
+        if index == MASTER_CONTROL_LOC {
+            self.set_power(val & 0b1000_0000 == 0b1000_0000);
+            return;
+        }
+
+        if let C3::PATTERN_RAM_START..=C3::PATTERN_RAM_STOP = index {
+            self.channel3.pattern_ram[(index - C3::PATTERN_RAM_START) as usize] = val;
+            return;
+        }
+
+        if !self.powered {
+            return;
+        }
+
         match index {
             MASTER_VOLUME_LOC => self.master_volume = val,
             MASTER_PANNING_LOC => self.sound_output = val,
-            MASTER_CONTROL_LOC => self.sound_toggle = (self.sound_toggle & 0b0111_1111) + (val & 0b1000_0000) ,
-
-            C1::SWEEP_LOC => self.c1_sweep = val,
-            C1::LENGTH_LOC => self.c1_length = val,
-            C1::VOLUME_ENVELOPE_LOC => self.c1_volume_envelope = val,
-            C1::FREQUENCY_LOW_LOC => self.c1_frequency_lo = val,
-            C1::FREQUENCY_HIGH_LOC => self.c1_frequency_hi = val,
-
-            C2::LENGTH_LOC => self.c2_lenght = val,
-            C2::VOLUME_ENVELOPE_LOC => self.c2_volume_envelope = val,
-            C2::FREQUENCY_LOW_LOC => self.c2_frequency_lo = val,
-            C2::FREQUENCY_HIGH_LOC => self.c2_frequency_hi = val,
-
-            C3::TOGGLE_LOC => self.c3_toggle = val,
-            C3::LENGTH_LOC => self.c3_length = val,
-            C3::VOLUME_LOC => self.c3_volume = val,
-            C3::FREQUENCY_LOW_LOC => self.c3_frequncy_lo = val,
-            C3::FREQUENCY_HIGH_LOC => self.c3_frequency_hi = val,
-            C3::PATTERN_RAM_START..=C3::PATTERN_RAM_STOP => self.c3_pattern_ram[(index - C3::PATTERN_RAM_START) as usize] = val,
-            
-            C4::LENGTH_LOC => self.c4_length = val,
-            C4::VOLUME_ENVELOPE_LOC => self.c4_volume_envelope = val,
-            C4::FREQUENCY_LOC => self.c4_frequency = val,
-            C4::CONTROL_LOC => self.c4_control = val,
+
+            C1::SWEEP_LOC..=C1::FREQUENCY_HIGH_LOC => self.channel1.write(index - C1::SWEEP_LOC, val),
+            C2::LENGTH_LOC..=C2::FREQUENCY_HIGH_LOC => self.channel2.write(index - C2::LENGTH_LOC + 1, val),
+            C3::TOGGLE_LOC..=C3::FREQUENCY_HIGH_LOC => self.channel3.write(index - C3::TOGGLE_LOC, val),
+            C4::LENGTH_LOC..=C4::CONTROL_LOC => self.channel4.write(index - C4::LENGTH_LOC + 1, val),
 
             _ => unreachable!("APU doesn't handle this memory")
         }
@@ -186,3 +293,154 @@ impl crate::mmu::Memory for APU {
     
 }
 
+impl crate::state::SaveState for APU {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.sound_output);
+        writer.write_bool(self.powered);
+        writer.write_u8(self.sequencer_step);
+        writer.write_bool(self.div_bit);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+
+        self.master_volume = reader.read_u8()?;
+        self.sound_output = reader.read_u8()?;
+        self.powered = reader.read_bool()?;
+        self.sequencer_step = reader.read_u8()? % 8;
+        self.div_bit = reader.read_bool()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{ write_header_checksum, RtcClock };
+    use crate::mmu::{ Memory, MMU };
+
+    /// An MMU for a blank cartridge, with the registers the boot ROM leaves behind
+    fn mmu() -> MMU {
+        let mut rom = vec![0; 0x8000];
+        write_header_checksum(&mut rom);
+        MMU::new(rom, None, RtcClock::default()).unwrap()
+    }
+
+    /// Runs the whole machine for `cycles` clock cycles, returning the loudest left and right
+    /// levels the APU mixed along the way
+    fn run(mmu: &mut MMU, cycles: u32) -> (f32, f32) {
+        let (mut left, mut right) = (0.0f32, 0.0f32);
+        for _ in 0..cycles / 4 {
+            mmu.tick(4);
+            let (l, r) = mmu.apu.mix();
+            left = left.max(l);
+            right = right.max(r);
+        }
+        (left, right)
+    }
+
+    #[test]
+    fn frame_sequencer_follows_div_through_the_mmu() {
+        let mut mmu = mmu();
+        mmu.write_8(MASTER_VOLUME_LOC, 0x77);
+        mmu.write_8(MASTER_PANNING_LOC, 0x00);
+
+        // Channel 1 at full volume on a 50% duty, for 2 length clocks
+        mmu.write_8(C1::SWEEP_LOC, 0x00);
+        mmu.write_8(C1::LENGTH_LOC, 0b1000_0000 | 62);
+        mmu.write_8(C1::VOLUME_ENVELOPE_LOC, 0xF0);
+        mmu.write_8(C1::FREQUENCY_LOW_LOC, 0xC0);
+        mmu.write_8(C1::FREQUENCY_HIGH_LOC, 0b1100_0111);
+        // Channel 2 for 1 length clock, sent to the right
+        mmu.write_8(C2::LENGTH_LOC, 63);
+        mmu.write_8(C2::VOLUME_ENVELOPE_LOC, 0xF0);
+        mmu.write_8(C2::FREQUENCY_HIGH_LOC, 0b1100_0111);
+        assert_eq!(mmu.read_8(MASTER_CONTROL_LOC), 0b1111_0011);
+
+        // Length is clocked at 256Hz, every 16384 clock cycles
+        run(&mut mmu, 16384);
+        assert_eq!(mmu.read_8(MASTER_CONTROL_LOC), 0b1111_0001);
+        run(&mut mmu, 16384);
+        assert_eq!(mmu.read_8(MASTER_CONTROL_LOC), 0b1111_0000);
+
+        // Nothing is panned anywhere, so nothing is heard
+        mmu.write_8(C1::FREQUENCY_HIGH_LOC, 0b1000_0111);
+        assert_eq!(run(&mut mmu, 2048), (0.0, 0.0));
+
+        // Channel 1 on the left only, a quarter of the mix at full volume
+        mmu.write_8(MASTER_PANNING_LOC, 0b0001_0000);
+        assert_eq!(run(&mut mmu, 2048), (0.25, 0.0));
+
+        // And on both, with the right turned down to 4/8
+        mmu.write_8(MASTER_PANNING_LOC, 0b0001_0001);
+        mmu.write_8(MASTER_VOLUME_LOC, 0x73);
+        assert_eq!(run(&mut mmu, 2048), (0.25, 0.125));
+    }
+
+    #[test]
+    fn envelope_is_clocked_at_64hz() {
+        let mut mmu = mmu();
+        mmu.write_8(MASTER_PANNING_LOC, 0b0001_0000);
+        mmu.write_8(MASTER_VOLUME_LOC, 0x70);
+        // Channel 1 from volume 15 down every envelope clock
+        mmu.write_8(C1::VOLUME_ENVELOPE_LOC, 0xF1);
+        mmu.write_8(C1::FREQUENCY_LOW_LOC, 0xC0);
+        mmu.write_8(C1::FREQUENCY_HIGH_LOC, 0x87);
+
+        // Each window holds a whole wave, so its loudest level is the channel's volume
+        let levels: Vec<u8> = (0..100).map(|_| (run(&mut mmu, 2048).0 * 60.0).round() as u8).collect();
+        let changes: Vec<usize> = (1..levels.len()).filter(|&i| levels[i] != levels[i - 1]).collect();
+        assert_eq!(levels[0], 15);
+        assert_eq!(levels[changes[0]], 14);
+        assert_eq!(levels[changes[1]], 13);
+        // 65536 clock cycles apart
+        assert_eq!(changes[1] - changes[0], 32);
+        assert_eq!(changes[2] - changes[1], 32);
+    }
+
+    #[test]
+    fn powering_off_clears_the_registers_and_ignores_writes() {
+        let mut apu = APU::default();
+        apu.handle_write(MASTER_VOLUME_LOC, 0x77);
+        apu.handle_write(MASTER_PANNING_LOC, 0xFF);
+        apu.handle_write(C1::VOLUME_ENVELOPE_LOC, 0xF0);
+        apu.handle_write(C1::FREQUENCY_HIGH_LOC, 0x80);
+        apu.handle_write(C3::PATTERN_RAM_START, 0x12);
+        assert_eq!(apu.handle_read(MASTER_CONTROL_LOC), 0b1111_0001);
+
+        apu.handle_write(MASTER_CONTROL_LOC, 0x00);
+        assert_eq!(apu.handle_read(MASTER_CONTROL_LOC), 0b0111_0000);
+        let registers = (C1::SWEEP_LOC..=C1::FREQUENCY_HIGH_LOC)
+            .chain(C2::LENGTH_LOC..=C3::FREQUENCY_HIGH_LOC)
+            .chain(C4::LENGTH_LOC..=MASTER_PANNING_LOC);
+        for index in registers {
+            let mask = READ_MASKS[(index - C1::SWEEP_LOC) as usize];
+            assert_eq!(apu.handle_read(index), mask, "{:#06x}", index);
+        }
+
+        // Only wave RAM can still be written, and survives
+        apu.handle_write(MASTER_VOLUME_LOC, 0x77);
+        apu.handle_write(C1::VOLUME_ENVELOPE_LOC, 0xF0);
+        apu.handle_write(C3::PATTERN_RAM_START + 1, 0x34);
+        assert_eq!(apu.handle_read(MASTER_VOLUME_LOC), 0x00);
+        assert_eq!(apu.handle_read(C1::VOLUME_ENVELOPE_LOC), 0x00);
+        assert_eq!(apu.handle_read(C3::PATTERN_RAM_START), 0x12);
+        assert_eq!(apu.handle_read(C3::PATTERN_RAM_START + 1), 0x34);
+
+        apu.handle_write(MASTER_CONTROL_LOC, 0x80);
+        apu.handle_write(MASTER_VOLUME_LOC, 0x77);
+        assert_eq!(apu.handle_read(MASTER_VOLUME_LOC), 0x77);
+    }
+
+}
@@ -0,0 +1,198 @@
+use super::units::{ Envelope, LengthCounter };
+use crate::state::{ SaveState, StateReader, StateWriter, StateError };
+
+/// Clock cycles between LFSR shifts for each divisor code in NR43 bits 0-2, before the shift
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Channel 4, plays the output of a linear feedback shift register
+pub(super) struct Noise {
+    envelope: Envelope,
+    length: LengthCounter,
+    /// NR43, bits 4-7 shift the divisor, bit 3 shortens the LFSR to 7 bits and bits 0-2 pick
+    /// the divisor
+    polynomial: u8,
+    pub(super) enabled: bool,
+
+    /// Clock cycles left until the LFSR shifts
+    timer: u32,
+    lfsr: u16,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            envelope: Envelope::default(),
+            length: LengthCounter::new(64),
+            polynomial: 0,
+            enabled: false,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+}
+
+impl Noise {
+
+    /// Reads one of the channel's registers, 1 being NR41
+    pub(super) fn read(&self, register: u16) -> u8 {
+        match register {
+            0 | 1 => 0,
+            2 => self.envelope.register,
+            3 => self.polynomial,
+            _ => (self.length.enabled as u8) << 6,
+        }
+    }
+
+    /// Writes one of the channel's registers, 1 being NR41
+    pub(super) fn write(&mut self, register: u16, val: u8) {
+        match register {
+            0 => (),
+            1 => self.length.load(val & 0b0011_1111),
+            2 => {
+                self.envelope.register = val;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.polynomial = val,
+            _ => {
+                self.length.enabled = val & 0b0100_0000 == 0b0100_0000;
+                if val & 0b1000_0000 == 0b1000_0000 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.polynomial & 0b111) as usize] << (self.polynomial >> 4)
+    }
+
+    /// Runs the LFSR for some clock cycles
+    pub(super) fn step(&mut self, ticks: u16) {
+        let mut ticks = ticks as u32;
+        while ticks >= self.timer {
+            ticks -= self.timer;
+            self.timer = self.period();
+
+            // Shifts of 14 and 15 leave the LFSR without a clock, so it holds its last value
+            if self.polynomial >> 4 >= 14 {
+                continue;
+            }
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.polynomial & 0b0000_1000 == 0b0000_1000 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.timer -= ticks;
+    }
+
+    /// The current level of the channel, 0-15
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 { self.envelope.volume } else { 0 }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+}
+
+impl SaveState for Noise {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.envelope.save_state(writer);
+        self.length.save_state(writer);
+        writer.write_u8(self.polynomial);
+        writer.write_bool(self.enabled);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(reader)?;
+        self.length.load_state(reader)?;
+        self.polynomial = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()? & 0x7FFF;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A channel at full volume that has just been triggered with NR43 set to `polynomial`
+    fn triggered(polynomial: u8) -> Noise {
+        let mut noise = Noise::default();
+        noise.write(2, 0xF0);
+        noise.write(3, polynomial);
+        noise.write(4, 0x80);
+        noise
+    }
+
+    /// Shifts the LFSR until it is back where it started, returning how many shifts it took
+    fn sequence_length(noise: &mut Noise, mask: u16) -> usize {
+        let start = noise.lfsr & mask;
+        (1..=0x8000).find(|_| { noise.step(8); noise.lfsr & mask == start }).unwrap()
+    }
+
+    #[test]
+    fn lfsr_is_15_or_7_bits_wide() {
+        let mut noise = triggered(0x00);
+        assert_eq!(sequence_length(&mut noise, 0x7FFF), 0x7FFF);
+
+        let mut noise = triggered(0x08);
+        assert_eq!(sequence_length(&mut noise, 0x7F), 0x7F);
+    }
+
+    #[test]
+    fn output_is_the_volume_while_bit_0_is_clear() {
+        let mut noise = triggered(0x00);
+        assert_eq!(noise.output(), 0);
+        // The first shift feeds in a 0 from the top, the low bits stay set for a while
+        for _ in 0..14 {
+            noise.step(8);
+            assert_eq!(noise.output(), 0);
+        }
+        noise.step(8);
+        assert_eq!(noise.output(), 15);
+    }
+
+    #[test]
+    fn divisor_and_shift_set_the_clock() {
+        // Divisor code 2 is 32 clock cycles, shifted left by 3
+        let mut noise = triggered(0b0011_0010);
+        noise.step(255);
+        assert_eq!(noise.lfsr, 0x7FFF);
+        noise.step(1);
+        assert_eq!(noise.lfsr, 0x3FFF);
+    }
+
+    #[test]
+    fn shifts_of_14_and_15_hold_the_lfsr() {
+        for polynomial in [0xE0, 0xF0] {
+            let mut noise = triggered(polynomial);
+            for _ in 0..200 {
+                noise.step(u16::MAX);
+            }
+            assert_eq!(noise.lfsr, 0x7FFF);
+        }
+    }
+
+}
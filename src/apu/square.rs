@@ -0,0 +1,305 @@
+use super::units::{ Envelope, LengthCounter };
+use crate::state::{ SaveState, StateReader, StateWriter, StateError };
+
+/// Which of the 8 steps of a square wave are high for each of the duties in NRx1 bits 6-7
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+/// Highest frequency value that fits in NRx3 and NRx4, the sweep stops the channel past it
+const MAX_FREQUENCY: u16 = 0x7FF;
+
+/// Square wave channels 1 and 2. Only channel 1 can write NR10, so channel 2 always has its
+/// sweep turned off
+pub(super) struct Square {
+    /// NR10, bits 4-6 are the sweep period, bit 3 the direction and bits 0-2 the shift
+    sweep_register: u8,
+    /// NRx1, only the duty in bits 6-7 is kept, the length goes to the counter
+    duty: u8,
+    envelope: Envelope,
+    /// 11 bit value from NRx3 and the low bits of NRx4, the wave repeats at
+    /// 131072 / (2048 - frequency) Hz
+    frequency: u16,
+    length: LengthCounter,
+    pub(super) enabled: bool,
+
+    /// Clock cycles left until the wave moves to its next step
+    timer: u16,
+    duty_step: u8,
+    /// Copy of the frequency the sweep works on
+    shadow_frequency: u16,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+}
+
+impl Default for Square {
+    fn default() -> Self {
+        Self {
+            sweep_register: 0,
+            duty: 0,
+            envelope: Envelope::default(),
+            frequency: 0,
+            length: LengthCounter::new(64),
+            enabled: false,
+            timer: 0,
+            duty_step: 0,
+            shadow_frequency: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+        }
+    }
+}
+
+impl Square {
+
+    /// Reads one of the channel's registers, 0 being NRx0
+    pub(super) fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => self.sweep_register,
+            1 => self.duty << 6,
+            2 => self.envelope.register,
+            3 => self.frequency as u8,
+            _ => (self.length.enabled as u8) << 6,
+        }
+    }
+
+    /// Writes one of the channel's registers, 0 being NRx0
+    pub(super) fn write(&mut self, register: u16, val: u8) {
+        match register {
+            0 => self.sweep_register = val & 0b0111_1111,
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0b0011_1111);
+            },
+            2 => {
+                self.envelope.register = val;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((val & 0b111) as u16) << 8;
+                self.length.enabled = val & 0b0100_0000 == 0b0100_0000;
+                if val & 0b1000_0000 == 0b1000_0000 {
+                    self.trigger();
+                }
+            },
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        let period = (self.sweep_register >> 4) & 0b111;
+        let shift = self.sweep_register & 0b111;
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = if period == 0 { 8 } else { period };
+        self.sweep_enabled = period != 0 || shift != 0;
+        if shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    /// Runs the wave for some clock cycles
+    pub(super) fn step(&mut self, ticks: u16) {
+        let mut ticks = ticks;
+        while ticks >= self.timer {
+            ticks -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= ticks;
+    }
+
+    /// The current level of the channel, 0-15
+    pub(super) fn output(&self) -> u8 {
+        let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_step)) & 1 == 1;
+        if self.enabled && high { self.envelope.volume } else { 0 }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Clocked at 128Hz, moves the frequency up or down every sweep period
+    pub(super) fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+
+        let period = (self.sweep_register >> 4) & 0b111;
+        self.sweep_timer = if period == 0 { 8 } else { period };
+
+        if !self.sweep_enabled || period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep_frequency();
+        if frequency <= MAX_FREQUENCY && self.sweep_register & 0b111 != 0 {
+            self.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked for overflow straight away as well
+            self.sweep_frequency();
+        }
+    }
+
+    /// Works out the next frequency of the sweep, turning the channel off if it goes past what
+    /// the registers can hold
+    fn sweep_frequency(&mut self) -> u16 {
+        let change = self.shadow_frequency >> (self.sweep_register & 0b111);
+        let frequency = if self.sweep_register & 0b0000_1000 == 0b0000_1000 {
+            self.shadow_frequency - change
+        } else {
+            self.shadow_frequency + change
+        };
+
+        if frequency > MAX_FREQUENCY {
+            self.enabled = false;
+        }
+
+        frequency
+    }
+
+}
+
+impl SaveState for Square {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.sweep_register);
+        writer.write_u8(self.duty);
+        self.envelope.save_state(writer);
+        writer.write_u16(self.frequency);
+        self.length.save_state(writer);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.duty_step);
+        writer.write_u16(self.shadow_frequency);
+        writer.write_u8(self.sweep_timer);
+        writer.write_bool(self.sweep_enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.sweep_register = reader.read_u8()? & 0b0111_1111;
+        self.duty = reader.read_u8()? & 0b11;
+        self.envelope.load_state(reader)?;
+        self.frequency = reader.read_u16()? & MAX_FREQUENCY;
+        self.length.load_state(reader)?;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u16()?;
+        self.duty_step = reader.read_u8()? % 8;
+        self.shadow_frequency = reader.read_u16()? & MAX_FREQUENCY;
+        self.sweep_timer = reader.read_u8()?;
+        self.sweep_enabled = reader.read_bool()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A channel at full volume that has just been triggered at `frequency` with NR10 set to
+    /// `sweep`
+    fn triggered(sweep: u8, frequency: u16) -> Square {
+        let mut square = Square::default();
+        square.write(0, sweep);
+        square.write(2, 0xF0);
+        square.write(3, frequency as u8);
+        square.write(4, 0x80 | (frequency >> 8) as u8);
+        square
+    }
+
+    #[test]
+    fn sweep_moves_the_frequency_every_period() {
+        // Period 1, up, shift 1
+        let mut square = triggered(0b0001_0001, 0x100);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x180);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x240);
+        assert!(square.enabled);
+
+        // Period 2, down, shift 2
+        let mut square = triggered(0b0010_1010, 0x100);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x100);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0xC0);
+
+        // A shift of 0 works out the frequency but never writes it back
+        let mut square = triggered(0b0001_0000, 0x100);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x100);
+    }
+
+    #[test]
+    fn sweep_past_0x7ff_turns_the_channel_off() {
+        // Checked when the channel starts
+        let square = triggered(0b0001_0001, 0x700);
+        assert!(!square.enabled);
+
+        // And straight after every new frequency, 0x500 goes to 0x780 which would go to 0xB40
+        let mut square = triggered(0b0001_0001, 0x500);
+        assert!(square.enabled);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x780);
+        assert!(!square.enabled);
+    }
+
+    #[test]
+    fn length_turns_the_channel_off_when_enabled() {
+        let mut square = triggered(0, 0x100);
+        square.write(1, 0x3E);
+        square.clock_length();
+        square.clock_length();
+        assert!(square.enabled);
+
+        square.write(4, 0b0100_0000);
+        square.clock_length();
+        assert!(square.enabled);
+        square.clock_length();
+        assert!(!square.enabled);
+        assert_eq!(square.output(), 0);
+    }
+
+    #[test]
+    fn envelope_sets_the_output_level() {
+        let mut square = triggered(0, 0x7FF);
+        square.write(2, 0x91);
+        square.write(4, 0x87);
+        square.step(4 * 7);
+        assert_eq!(square.output(), 9);
+        square.clock_envelope();
+        assert_eq!(square.output(), 8);
+
+        // Turning the DAC off turns the channel off, and triggering doesn't bring it back
+        square.write(2, 0x00);
+        assert!(!square.enabled);
+        square.write(4, 0x87);
+        assert!(!square.enabled);
+    }
+
+    #[test]
+    fn duty_sets_how_many_steps_are_high() {
+        for (duty, high) in [(0, 1), (1, 2), (2, 4), (3, 6)] {
+            let mut square = triggered(0, 0x7FF);
+            square.write(1, duty << 6);
+            let count = (0..8).filter(|_| { square.step(4); square.output() == 15 }).count();
+            assert_eq!(count, high, "duty {}", duty);
+        }
+    }
+
+}
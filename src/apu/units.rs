@@ -0,0 +1,184 @@
+use crate::state::{ SaveState, StateReader, StateWriter, StateError };
+
+/// Turns a channel off once it has played for the time set in its length register, clocked at
+/// 256Hz by the frame sequencer
+pub(super) struct LengthCounter {
+    /// What the counter reloads with when its channel starts with it run out, 256 for the wave
+    /// channel and 64 for the others
+    max: u16,
+    counter: u16,
+    /// NRx4 bit 6, the counter only runs while it is set
+    pub(super) enabled: bool,
+}
+
+impl LengthCounter {
+
+    pub(super) fn new(max: u16) -> Self {
+        Self { max, counter: 0, enabled: false }
+    }
+
+    /// Loads the length bits of NRx1, the channel plays for `max - length` clocks
+    pub(super) fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    /// A channel starting with its counter run out plays for the longest length
+    pub(super) fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Counts down one step, returning true when the channel should turn off
+    pub(super) fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+}
+
+impl SaveState for LengthCounter {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.read_u16()?.min(self.max);
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+
+}
+
+#[derive(Default)]
+/// Moves the volume of a channel up or down at 64Hz as set up through NRx2
+pub(super) struct Envelope {
+    /// NRx2, bits 4-7 are the starting volume, bit 3 the direction and bits 0-2 the period
+    pub(super) register: u8,
+    pub(super) volume: u8,
+    /// Envelope clocks left until the volume moves
+    timer: u8,
+}
+
+impl Envelope {
+
+    /// The channel's DAC is on as long as the top 5 bits of NRx2 aren't all 0
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.register & 0b1111_1000 != 0
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.register & 0b111;
+    }
+
+    pub(super) fn clock(&mut self) {
+        let period = self.register & 0b111;
+        if period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = period;
+
+        if self.register & 0b0000_1000 == 0b0000_1000 {
+            if self.volume < 15 {
+                self.volume += 1;
+            }
+        } else if self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+
+}
+
+impl SaveState for Envelope {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&[self.register, self.volume, self.timer]);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.register = reader.read_u8()?;
+        self.volume = reader.read_u8()? & 0x0F;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_counts_down_only_while_enabled() {
+        let mut length = LengthCounter::new(64);
+        length.load(62);
+        assert!(!length.clock());
+
+        length.enabled = true;
+        assert!(!length.clock());
+        assert!(length.clock());
+        // Stays run out without turning the channel off again
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn triggering_a_run_out_counter_reloads_the_longest_length() {
+        let mut length = LengthCounter::new(256);
+        length.enabled = true;
+        length.trigger();
+        for _ in 0..255 {
+            assert!(!length.clock());
+        }
+        assert!(length.clock());
+
+        // One that hasn't run out keeps going from where it was
+        length.load(250);
+        length.clock();
+        length.trigger();
+        for _ in 0..4 {
+            assert!(!length.clock());
+        }
+        assert!(length.clock());
+    }
+
+    #[test]
+    fn envelope_moves_the_volume_every_period() {
+        // Starting at 2, going down every 2 clocks
+        let mut envelope = Envelope { register: 0x22, ..Default::default() };
+        envelope.trigger();
+        let volumes: Vec<u8> = (0..6).map(|_| { envelope.clock(); envelope.volume }).collect();
+        assert_eq!(volumes, [2, 1, 1, 0, 0, 0]);
+
+        // Starting at 14 and going up stops at 15
+        let mut envelope = Envelope { register: 0xE9, ..Default::default() };
+        envelope.trigger();
+        let volumes: Vec<u8> = (0..3).map(|_| { envelope.clock(); envelope.volume }).collect();
+        assert_eq!(volumes, [15, 15, 15]);
+
+        // A period of 0 holds the volume
+        let mut envelope = Envelope { register: 0x78, ..Default::default() };
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.volume, 7);
+    }
+
+    #[test]
+    fn dac_is_off_only_with_the_top_5_bits_clear() {
+        assert!(!Envelope { register: 0x07, ..Default::default() }.dac_enabled());
+        assert!(Envelope { register: 0x08, ..Default::default() }.dac_enabled());
+        assert!(Envelope { register: 0x10, ..Default::default() }.dac_enabled());
+    }
+
+}
@@ -0,0 +1,203 @@
+use super::units::LengthCounter;
+use crate::state::{ SaveState, StateReader, StateWriter, StateError };
+
+const WAVE_PATTERN_RAM_SIZE: usize = 0x10;
+
+/// Channel 3, plays back the 32 4 bit samples in wave pattern RAM
+pub(super) struct Wave {
+    /// NR30 bit 7
+    dac_enabled: bool,
+    length: LengthCounter,
+    /// NR32 bits 5-6, 0 mutes the channel and 1-3 shift the samples right by 0-2
+    volume: u8,
+    /// 11 bit value from NR33 and the low bits of NR34, the wave repeats at
+    /// 65536 / (2048 - frequency) Hz
+    frequency: u16,
+    pub(super) enabled: bool,
+    /// Two samples a byte, high nibble first
+    pub(super) pattern_ram: [u8; WAVE_PATTERN_RAM_SIZE],
+
+    /// Clock cycles left until the next sample
+    timer: u16,
+    /// Which of the 32 samples is playing
+    position: u8,
+}
+
+impl Default for Wave {
+    fn default() -> Self {
+        Self {
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume: 0,
+            frequency: 0,
+            enabled: false,
+            pattern_ram: [0; WAVE_PATTERN_RAM_SIZE],
+            timer: 0,
+            position: 0,
+        }
+    }
+}
+
+impl Wave {
+
+    /// Reads one of the channel's registers, 0 being NR30
+    pub(super) fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => (self.dac_enabled as u8) << 7,
+            1 => 0,
+            2 => self.volume << 5,
+            3 => self.frequency as u8,
+            _ => (self.length.enabled as u8) << 6,
+        }
+    }
+
+    /// Writes one of the channel's registers, 0 being NR30
+    pub(super) fn write(&mut self, register: u16, val: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = val & 0b1000_0000 == 0b1000_0000;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.load(val),
+            2 => self.volume = (val >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | ((val & 0b111) as u16) << 8;
+                self.length.enabled = val & 0b0100_0000 == 0b0100_0000;
+                if val & 0b1000_0000 == 0b1000_0000 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            },
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    /// Runs the wave for some clock cycles
+    pub(super) fn step(&mut self, ticks: u16) {
+        let mut ticks = ticks;
+        while ticks >= self.timer {
+            ticks -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= ticks;
+    }
+
+    /// The current level of the channel, 0-15
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled || self.volume == 0 {
+            return 0;
+        }
+
+        let byte = self.pattern_ram[self.position as usize / 2];
+        let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        sample >> (self.volume - 1)
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+}
+
+impl SaveState for Wave {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.dac_enabled);
+        self.length.save_state(writer);
+        writer.write_u8(self.volume);
+        writer.write_u16(self.frequency);
+        writer.write_bool(self.enabled);
+        writer.write_bytes(&self.pattern_ram);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.position);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.dac_enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.volume = reader.read_u8()? & 0b11;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.enabled = reader.read_bool()?;
+        reader.read_bytes(&mut self.pattern_ram)?;
+        self.timer = reader.read_u16()?;
+        self.position = reader.read_u8()? % 32;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A channel playing the samples 0-15 then 15-0 at the fastest frequency, with NR32 set to
+    /// `volume`
+    fn playing(volume: u8) -> Wave {
+        let mut wave = Wave::default();
+        for i in 0..8 {
+            wave.pattern_ram[i] = ((i as u8 * 2) << 4) | (i as u8 * 2 + 1);
+            wave.pattern_ram[15 - i] = ((i as u8 * 2 + 1) << 4) | (i as u8 * 2);
+        }
+        wave.write(0, 0x80);
+        wave.write(2, volume);
+        wave.write(3, 0xFF);
+        wave.write(4, 0x87);
+        wave
+    }
+
+    /// The next 32 samples the channel plays
+    fn samples(wave: &mut Wave) -> Vec<u8> {
+        (0..32).map(|_| { let sample = wave.output(); wave.step(2); sample }).collect()
+    }
+
+    #[test]
+    fn plays_pattern_ram_high_nibble_first() {
+        let mut wave = playing(0x20);
+        let expected: Vec<u8> = (0..16).chain((0..16).rev()).collect();
+        assert_eq!(samples(&mut wave), expected);
+        // And round again
+        assert_eq!(samples(&mut wave), expected);
+    }
+
+    #[test]
+    fn volume_shifts_the_samples() {
+        let mut wave = playing(0x40);
+        assert_eq!(samples(&mut wave)[12..16], [6, 6, 7, 7]);
+        let mut wave = playing(0x60);
+        assert_eq!(samples(&mut wave)[12..16], [3, 3, 3, 3]);
+        let mut wave = playing(0x00);
+        assert!(samples(&mut wave).iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn length_is_up_to_256_and_nr30_is_the_dac() {
+        let mut wave = playing(0x20);
+        wave.write(1, 0x00);
+        wave.write(4, 0xC7);
+        for _ in 0..255 {
+            wave.clock_length();
+        }
+        assert!(wave.enabled);
+        wave.clock_length();
+        assert!(!wave.enabled);
+
+        let mut wave = playing(0x20);
+        wave.write(0, 0x00);
+        assert!(!wave.enabled);
+        wave.write(4, 0x87);
+        assert!(!wave.enabled);
+        assert_eq!(wave.read(0), 0);
+    }
+
+}
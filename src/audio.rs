@@ -0,0 +1,220 @@
+mod blip;
+#[cfg(feature = "audio")]
+mod device;
+mod stretch;
+mod wav;
+
+#[cfg(feature = "audio")]
+pub use device::{ AudioDevice, DeviceSink };
+pub use wav::WavSink;
+
+use blip::BlipBuffer;
+use stretch::TimeStretch;
+use crate::apu::CLOCK_SPEED;
+
+/// Clock cycles between each time the resampled audio is handed to the sink, 64 times a second
+const FLUSH_INTERVAL: u64 = CLOCK_SPEED / 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a sink wants each sample
+pub enum SampleFormat {
+    /// Signed 16 bit integers
+    I16,
+    /// Floats between -1.0 and 1.0
+    F32,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// A batch of stereo samples, interleaved left then right
+pub enum Samples<'a> {
+    I16(&'a [i16]),
+    F32(&'a [f32]),
+}
+
+impl Samples<'_> {
+
+    /// Number of samples in the batch, counting left and right separately
+    pub fn len(&self) -> usize {
+        match self {
+            Self::I16(samples) => samples.len(),
+            Self::F32(samples) => samples.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+}
+
+/// Somewhere for the APU to send its audio, like a host audio device or a file. The APU calls
+/// `write` a few times every emulated frame with however many samples are ready
+pub trait AudioSink {
+
+    /// Stereo samples per second the sink plays back at, usually 44100 or 48000
+    fn sample_rate(&self) -> u32;
+
+    fn format(&self) -> SampleFormat;
+
+    /// Takes the next batch of samples, always in the format asked for
+    fn write(&mut self, samples: Samples<'_>);
+
+}
+
+/// Turns the levels the APU mixes every clock cycle into samples at the sink's rate
+pub(crate) struct AudioOutput {
+    sink: Box<dyn AudioSink>,
+    left: BlipBuffer,
+    right: BlipBuffer,
+    stretch: TimeStretch,
+    /// Levels the last time the APU was mixed, only changes get added to the buffers
+    last: (f32, f32),
+    /// Clock cycles since the last flush
+    clock: u64,
+
+    /// Scratch space for the samples of a flush
+    left_samples: Vec<f32>,
+    right_samples: Vec<f32>,
+    interleaved_f32: Vec<f32>,
+    interleaved_i16: Vec<i16>,
+}
+
+impl AudioOutput {
+
+    pub(crate) fn new(sink: Box<dyn AudioSink>) -> Self {
+        let sample_rate = sink.sample_rate().max(1);
+
+        Self {
+            sink,
+            left: BlipBuffer::new(CLOCK_SPEED, sample_rate),
+            right: BlipBuffer::new(CLOCK_SPEED, sample_rate),
+            stretch: TimeStretch::default(),
+            last: (0.0, 0.0),
+            clock: 0,
+            left_samples: Vec::new(),
+            right_samples: Vec::new(),
+            interleaved_f32: Vec::new(),
+            interleaved_i16: Vec::new(),
+        }
+    }
+
+    /// Records the APU's levels after it ran for `ticks` clock cycles
+    pub(crate) fn update(&mut self, ticks: u8, (left, right): (f32, f32)) {
+        if left != self.last.0 {
+            self.left.add_delta(self.clock, left - self.last.0);
+        }
+        if right != self.last.1 {
+            self.right.add_delta(self.clock, right - self.last.1);
+        }
+        self.last = (left, right);
+
+        self.clock += ticks as u64;
+        if self.clock >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Makes `scale` times as many samples for every emulated second
+    pub(crate) fn set_rate_scale(&mut self, scale: f64) {
+        self.left.set_rate_scale(scale);
+        self.right.set_rate_scale(scale);
+    }
+
+    /// Makes the audio last `1 / speed` as long without changing its pitch
+    pub(crate) fn set_time_stretch(&mut self, speed: f64) {
+        self.stretch.set_speed(speed);
+    }
+
+    /// Resamples everything since the last flush and sends it to the sink
+    fn flush(&mut self) {
+        self.left.end_frame(self.clock);
+        self.right.end_frame(self.clock);
+        self.clock = 0;
+
+        self.left.read_samples(&mut self.left_samples);
+        self.right.read_samples(&mut self.right_samples);
+        if self.stretch.speed() != 1.0 {
+            self.stretch.process(&mut self.left_samples, &mut self.right_samples);
+        }
+
+        let interleaved = self.left_samples.iter().zip(self.right_samples.iter());
+        match self.sink.format() {
+            SampleFormat::F32 => {
+                self.interleaved_f32.clear();
+                self.interleaved_f32.extend(interleaved.flat_map(|(l, r)| [*l, *r]));
+                self.sink.write(Samples::F32(&self.interleaved_f32));
+            },
+            SampleFormat::I16 => {
+                let convert = |sample: f32| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                self.interleaved_i16.clear();
+                self.interleaved_i16.extend(interleaved.flat_map(|(l, r)| [convert(*l), convert(*r)]));
+                self.sink.write(Samples::I16(&self.interleaved_i16));
+            },
+        }
+
+        self.left_samples.clear();
+        self.right_samples.clear();
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Counts the stereo samples it's given
+    struct CountingSink(Rc<Cell<usize>>);
+
+    impl AudioSink for CountingSink {
+
+        fn sample_rate(&self) -> u32 {
+            48000
+        }
+
+        fn format(&self) -> SampleFormat {
+            SampleFormat::F32
+        }
+
+        fn write(&mut self, samples: Samples<'_>) {
+            self.0.set(self.0.get() + samples.len() / 2);
+        }
+
+    }
+
+    #[test]
+    fn held_level_keeps_the_sink_fed() {
+        let count = Rc::new(Cell::new(0));
+        let mut output = AudioOutput::new(Box::new(CountingSink(count.clone())));
+
+        // Silence, then a level that doesn't move, then the level changing all the time
+        for second in 0..3 {
+            count.set(0);
+            for step in 0..CLOCK_SPEED / 4 {
+                let level = match second {
+                    0 => 0.0,
+                    1 => 0.25,
+                    _ => (step / 64 % 2) as f32 * 0.25,
+                };
+                output.update(4, (level, -level));
+            }
+            assert!(count.get().abs_diff(48000) < 100, "second {} made {} samples", second, count.get());
+        }
+    }
+
+    #[test]
+    fn stretched_silence_still_plays_in_real_time() {
+        let count = Rc::new(Cell::new(0));
+        let mut output = AudioOutput::new(Box::new(CountingSink(count.clone())));
+        output.set_time_stretch(2.0);
+
+        // Two emulated seconds go by in one real second at 2x
+        for _ in 0..CLOCK_SPEED / 2 {
+            output.update(4, (0.0, 0.0));
+        }
+        // Grains come out whole, so it can be a grain either side
+        assert!(count.get().abs_diff(48000) < 2048, "made {} samples", count.get());
+    }
+
+}
@@ -0,0 +1,178 @@
+/*
+ *  Band-limited synthesis in the style of blip_buf: instead of sampling the APU's output, every
+ *  change in level is drawn into the output as a band-limited step. The buffer holds the
+ *  derivative of the signal, so each change only touches `KERNEL_WIDTH` samples, and reading
+ *  integrates it back up.
+ */
+
+/// Fractional positions a step can land on between two output samples
+const PHASES: usize = 32;
+/// Output samples each step is spread over
+const KERNEL_WIDTH: usize = 16;
+/// Fraction of the output's Nyquist frequency that is let through, a bit under 1 leaves room for
+/// the window to roll off before anything aliases
+const CUTOFF: f64 = 0.9;
+/// How much of the high pass filter's charge is kept every clock cycle, the same as the capacitor
+/// on the Game Boy's audio output
+const CHARGE_PER_CYCLE: f64 = 0.999958;
+
+/// Resamples a signal that only changes in steps from the Game Boy's clock rate to the host's
+/// sample rate
+pub(super) struct BlipBuffer {
+    /// Output samples per clock cycle
+    ratio: f64,
+    /// `ratio` before being scaled by `set_rate_scale`
+    base_ratio: f64,
+    /// Position in output samples of the first clock cycle of the current frame
+    offset: f64,
+    /// Derivative of the output, waiting to be integrated
+    buffer: Vec<f32>,
+    integrator: f32,
+    /// Charge of the high pass filter that takes out the DC offset
+    capacitor: f32,
+    charge: f32,
+    /// Impulse responses of the low pass filter for each phase
+    kernel: [[f32; KERNEL_WIDTH]; PHASES],
+}
+
+impl BlipBuffer {
+
+    pub(super) fn new(clock_rate: u64, sample_rate: u32) -> Self {
+        let ratio = sample_rate as f64 / clock_rate as f64;
+
+        Self {
+            ratio,
+            base_ratio: ratio,
+            offset: 0.0,
+            buffer: Vec::new(),
+            integrator: 0.0,
+            capacitor: 0.0,
+            charge: CHARGE_PER_CYCLE.powf(1.0 / ratio) as f32,
+            kernel: Self::kernel(),
+        }
+    }
+
+    /// Windowed sinc impulses, one for each phase, normalised so a step of 1 comes out as
+    /// exactly 1 once integrated
+    fn kernel() -> [[f32; KERNEL_WIDTH]; PHASES] {
+        let mut kernel = [[0.0; KERNEL_WIDTH]; PHASES];
+        let half = (KERNEL_WIDTH / 2) as f64;
+
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let shift = phase as f64 / PHASES as f64;
+
+            let raw: Vec<f64> = (0..KERNEL_WIDTH)
+                .map(|i| {
+                    let t = i as f64 - half - shift;
+                    let sinc = if t == 0.0 {
+                        1.0
+                    } else {
+                        let x = std::f64::consts::PI * t * CUTOFF;
+                        x.sin() / x
+                    };
+                    // Blackman window over the width of the kernel
+                    let w = (t + half) / KERNEL_WIDTH as f64;
+                    let window = 0.42
+                        - 0.5 * (2.0 * std::f64::consts::PI * w).cos()
+                        + 0.08 * (4.0 * std::f64::consts::PI * w).cos();
+                    sinc * window
+                })
+                .collect();
+
+            let sum: f64 = raw.iter().sum();
+            for (tap, value) in taps.iter_mut().zip(raw) {
+                *tap = (value / sum) as f32;
+            }
+        }
+
+        kernel
+    }
+
+    /// Makes `scale` times as many samples for each clock cycle, without touching the filters
+    pub(super) fn set_rate_scale(&mut self, scale: f64) {
+        self.ratio = self.base_ratio * scale;
+    }
+
+    /// Adds a change in level `clock` cycles into the current frame
+    pub(super) fn add_delta(&mut self, clock: u64, delta: f32) {
+        let position = self.offset + clock as f64 * self.ratio;
+        let index = position as usize;
+        let phase = ((position - index as f64) * PHASES as f64) as usize;
+
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize(index + KERNEL_WIDTH, 0.0);
+        }
+
+        for (sample, tap) in self.buffer[index..index + KERNEL_WIDTH].iter_mut().zip(self.kernel[phase]) {
+            *sample += delta * tap;
+        }
+    }
+
+    /// Ends the current frame after `clocks` cycles, the samples it covers can then be read
+    pub(super) fn end_frame(&mut self, clocks: u64) {
+        self.offset += clocks as f64 * self.ratio;
+
+        // Stretches without any changes still need samples, holding whatever level came before
+        let needed = self.offset as usize + KERNEL_WIDTH;
+        if self.buffer.len() < needed {
+            self.buffer.resize(needed, 0.0);
+        }
+    }
+
+    /// Integrates the finished samples into `out`, removing them from the buffer
+    pub(super) fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.offset as usize;
+
+        for delta in self.buffer.drain(..count) {
+            self.integrator += delta;
+            let sample = self.integrator - self.capacitor;
+            self.capacitor = self.integrator - sample * self.charge;
+            out.push(sample);
+        }
+
+        self.offset -= count as f64;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: u64 = 4_194_304;
+
+    #[test]
+    fn every_elapsed_sample_comes_out_without_any_changes() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, 48000);
+        let mut out = Vec::new();
+
+        for _ in 0..64 {
+            blip.end_frame(CLOCK_RATE / 64);
+            blip.read_samples(&mut out);
+        }
+        assert_eq!(out.len(), 48000);
+        assert!(out.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn step_settles_on_its_level_and_then_drains_away() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, 48000);
+        let mut out = Vec::new();
+
+        blip.add_delta(100, 0.5);
+        blip.end_frame(CLOCK_RATE / 64);
+        blip.read_samples(&mut out);
+        assert_eq!(out.len(), 750);
+        // The kernel centres the step 8 samples late, and the high pass filter has barely
+        // started to take it out by then
+        assert!((out[12] - 0.5).abs() < 0.02);
+
+        for _ in 0..64 {
+            blip.end_frame(CLOCK_RATE / 64);
+            blip.read_samples(&mut out);
+        }
+        assert_eq!(out.len(), 750 + 48000);
+        assert!(out.last().unwrap().abs() < 0.01);
+    }
+
+}
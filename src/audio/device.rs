@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
+use cpal::{ FromSample, SizedSample, Stream, StreamConfig };
+
+use super::{ AudioSink, SampleFormat, Samples };
+use crate::error::{ Error, Result };
+
+/// Interleaved stereo samples on their way to the device
+type SharedBuffer = Arc<Mutex<VecDeque<f32>>>;
+
+/// The host's default audio output. The stream keeps playing for as long as this is alive,
+/// pulling samples out of a buffer that the sinks made by `sink` fill up
+pub struct AudioDevice {
+    /// Never read, dropping it stops playback
+    _stream: Stream,
+    buffer: SharedBuffer,
+    sample_rate: u32,
+    /// Most samples the buffer holds, anything written past this is dropped
+    capacity: usize,
+}
+
+impl AudioDevice {
+
+    /// Opens the default output device with room for `latency` worth of audio in its buffer
+    pub fn open(latency: Duration) -> Result<Self> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| Error::Audio("no output device".to_string()))?;
+        let supported = device.default_output_config().map_err(|e| Error::Audio(e.to_string()))?;
+
+        let sample_rate = supported.sample_rate().0;
+        let capacity = (latency.as_secs_f64() * sample_rate as f64) as usize * 2;
+        let buffer: SharedBuffer = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let config = supported.config();
+
+        let stream = match supported.sample_format() {
+            cpal::SampleFormat::I16 => Self::build_stream::<i16>(&device, &config, &buffer),
+            cpal::SampleFormat::U16 => Self::build_stream::<u16>(&device, &config, &buffer),
+            _ => Self::build_stream::<f32>(&device, &config, &buffer),
+        }?;
+        stream.play().map_err(|e| Error::Audio(e.to_string()))?;
+
+        Ok(Self { _stream: stream, buffer, sample_rate, capacity })
+    }
+
+    fn build_stream<T>(device: &cpal::Device, config: &StreamConfig, buffer: &SharedBuffer) -> Result<Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let buffer = Arc::clone(buffer);
+        let channels = config.channels as usize;
+        let mut last = (0.0, 0.0);
+
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut buffer = buffer.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    // Running dry repeats the last sample, which clicks a lot less than silence
+                    if buffer.len() >= 2 {
+                        last = (buffer.pop_front().unwrap(), buffer.pop_front().unwrap());
+                    }
+
+                    match frame {
+                        [mono] => *mono = T::from_sample((last.0 + last.1) / 2.0),
+                        [left, right, rest @ ..] => {
+                            *left = T::from_sample(last.0);
+                            *right = T::from_sample(last.1);
+                            for sample in rest {
+                                *sample = T::from_sample(0.0f32);
+                            }
+                        },
+                        [] => (),
+                    }
+                }
+            },
+            |e| log::error!("Audio stream error: {}", e),
+            None,
+        ).map_err(|e| Error::Audio(e.to_string()))
+    }
+
+    /// A sink that feeds this device
+    pub fn sink(&self) -> DeviceSink {
+        DeviceSink {
+            buffer: Arc::clone(&self.buffer),
+            sample_rate: self.sample_rate,
+            capacity: self.capacity,
+        }
+    }
+
+    /// How full the buffer is, from 0.0 when it has run dry to 1.0 when samples are being dropped
+    pub fn fill(&self) -> f32 {
+        self.buffer.lock().unwrap().len() as f32 / self.capacity.max(1) as f32
+    }
+
+    /// Throws away everything waiting to be played
+    pub fn clear(&self) {
+        self.buffer.lock().unwrap().clear();
+    }
+
+}
+
+/// Sends samples to an `AudioDevice`
+pub struct DeviceSink {
+    buffer: SharedBuffer,
+    sample_rate: u32,
+    capacity: usize,
+}
+
+impl AudioSink for DeviceSink {
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn format(&self) -> SampleFormat {
+        SampleFormat::F32
+    }
+
+    fn write(&mut self, samples: Samples<'_>) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let room = self.capacity.saturating_sub(buffer.len()) & !1;
+
+        match samples {
+            Samples::F32(samples) => buffer.extend(samples.iter().take(room)),
+            Samples::I16(samples) => buffer.extend(samples.iter().take(room).map(|s| *s as f32 / i16::MAX as f32)),
+        }
+    }
+
+}
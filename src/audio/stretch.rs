@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+/// Frames of audio played back in one piece, about 20ms at 48kHz
+const GRAIN: usize = 1024;
+/// Frames at the start of each grain that fade in over the end of the last one
+const FADE: usize = 128;
+
+/// Changes how long audio lasts without changing its pitch, so fast-forward and slow motion
+/// still sound like the game. The audio is cut into short grains that are played back one after
+/// the other, skipping over or going back through the input between them depending on the speed
+pub(super) struct TimeStretch {
+    /// Input frames played for every output frame
+    speed: f64,
+    input: VecDeque<(f32, f32)>,
+    /// Input frames to drop before the next grain starts, carries over between calls
+    skip: f64,
+    /// What would have come after the last grain, faded out under the start of the next
+    tail: Vec<(f32, f32)>,
+}
+
+impl Default for TimeStretch {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            input: VecDeque::new(),
+            skip: 0.0,
+            tail: vec![(0.0, 0.0); FADE],
+        }
+    }
+}
+
+impl TimeStretch {
+
+    pub(super) fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub(super) fn set_speed(&mut self, speed: f64) {
+        if speed != self.speed {
+            *self = Self { speed, ..Self::default() };
+        }
+    }
+
+    /// Takes the next stereo samples, adding whatever grains are finished to the output
+    pub(super) fn process(&mut self, left: &mut Vec<f32>, right: &mut Vec<f32>) {
+        self.input.extend(left.iter().copied().zip(right.iter().copied()));
+        left.clear();
+        right.clear();
+
+        loop {
+            // Drop what the last grain skipped over, which might not have all arrived yet
+            let skipped = (self.skip as usize).min(self.input.len());
+            self.input.drain(..skipped);
+            self.skip -= skipped as f64;
+
+            if self.skip >= 1.0 || self.input.len() < GRAIN + FADE {
+                break;
+            }
+
+            for (i, (l, r)) in self.input.iter().take(GRAIN).enumerate() {
+                let (l, r) = if i < FADE {
+                    let fade = i as f32 / FADE as f32;
+                    let (tail_l, tail_r) = self.tail[i];
+                    (l * fade + tail_l * (1.0 - fade), r * fade + tail_r * (1.0 - fade))
+                } else {
+                    (*l, *r)
+                };
+                left.push(l);
+                right.push(r);
+            }
+
+            for (tail, frame) in self.tail.iter_mut().zip(self.input.range(GRAIN..GRAIN + FADE)) {
+                *tail = *frame;
+            }
+            self.skip += GRAIN as f64 * self.speed;
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds a second of audio at 48kHz through in flush sized pieces, returning how many frames
+    /// came out
+    fn stretch_second(speed: f64) -> usize {
+        let mut stretch = TimeStretch::default();
+        stretch.set_speed(speed);
+
+        let mut frames = 0;
+        for chunk in 0..64 {
+            let mut left: Vec<f32> = (0..750).map(|i| ((chunk * 750 + i) as f32 * 0.05).sin()).collect();
+            let mut right = left.clone();
+            stretch.process(&mut left, &mut right);
+            assert_eq!(left.len(), right.len());
+            frames += left.len();
+        }
+        frames
+    }
+
+    #[test]
+    fn output_lasts_the_input_over_the_speed() {
+        // Grains come out whole, and the last one can still be waiting for more input
+        for speed in [2.0, 0.5] {
+            let expected = 48000.0 / speed;
+            let frames = stretch_second(speed) as f64;
+            let slack = (GRAIN + FADE) as f64 / speed + GRAIN as f64;
+            assert!((frames - expected).abs() < slack, "{}x made {} frames", speed, frames);
+        }
+    }
+
+    #[test]
+    fn grains_are_copied_through_at_full_speed_apart_from_the_fade() {
+        let mut stretch = TimeStretch::default();
+        stretch.set_speed(2.0);
+
+        let mut left: Vec<f32> = (0..GRAIN + FADE).map(|i| i as f32).collect();
+        let mut right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+        stretch.process(&mut left, &mut right);
+
+        assert_eq!(left.len(), GRAIN);
+        assert_eq!(left[0], 0.0);
+        assert_eq!(left[FADE..], (FADE..GRAIN).map(|i| i as f32).collect::<Vec<_>>()[..]);
+        assert_eq!(right[GRAIN - 1], -((GRAIN - 1) as f32));
+    }
+
+}
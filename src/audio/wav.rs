@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::{ self, BufWriter, Seek, SeekFrom, Write };
+use std::path::Path;
+
+use super::{ AudioSink, SampleFormat, Samples };
+
+/// Size of the RIFF and fmt chunks plus the data chunk's header
+const HEADER_SIZE: u32 = 44;
+const FORMAT_PCM: u16 = 1;
+const FORMAT_IEEE_FLOAT: u16 = 3;
+const CHANNELS: u16 = 2;
+
+/// Writes the audio into a WAV file, so it can be listened to or compared without an audio
+/// device. The sizes in the header are filled in when the sink is finished or dropped
+pub struct WavSink {
+    file: Option<BufWriter<File>>,
+    sample_rate: u32,
+    format: SampleFormat,
+    /// Bytes of samples written so far
+    data_size: u32,
+}
+
+impl WavSink {
+
+    /// Creates the file at `path`, replacing anything already there
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32, format: SampleFormat) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let (tag, bits) = match format {
+            SampleFormat::I16 => (FORMAT_PCM, 16),
+            SampleFormat::F32 => (FORMAT_IEEE_FLOAT, 32),
+        };
+        let block_align = CHANNELS * bits / 8;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&tag.to_le_bytes())?;
+        file.write_all(&CHANNELS.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&bits.to_le_bytes())?;
+
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file: Some(file), sample_rate, format, data_size: 0 })
+    }
+
+    /// Fills in the header and closes the file
+    pub fn finish(mut self) -> io::Result<()> {
+        self.write_sizes()
+    }
+
+    fn write_sizes(&mut self) -> io::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        file.write_all(&self.data_size.to_le_bytes())?;
+        file.flush()
+    }
+
+    fn write_samples(file: &mut BufWriter<File>, samples: Samples<'_>) -> io::Result<()> {
+        match samples {
+            Samples::I16(samples) => samples.iter().try_for_each(|s| file.write_all(&s.to_le_bytes())),
+            Samples::F32(samples) => samples.iter().try_for_each(|s| file.write_all(&s.to_le_bytes())),
+        }
+    }
+
+}
+
+impl AudioSink for WavSink {
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn format(&self) -> SampleFormat {
+        self.format
+    }
+
+    fn write(&mut self, samples: Samples<'_>) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        match Self::write_samples(file, samples) {
+            Ok(()) => {
+                let bytes = match samples {
+                    Samples::I16(samples) => samples.len() * 2,
+                    Samples::F32(samples) => samples.len() * 4,
+                };
+                self.data_size = self.data_size.saturating_add(bytes as u32);
+            },
+            Err(e) => {
+                log::error!("Could not write audio: {}", e);
+                // Stop writing rather than logging every batch, the header still gets fixed up
+                let _ = self.write_sizes();
+            },
+        }
+    }
+
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_sizes() {
+            log::error!("Could not finish WAV file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    /// Writes two batches through a sink and returns the finished file
+    fn record(name: &str, format: SampleFormat, first: Samples<'_>, second: Samples<'_>, finish: bool) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("jemulator-{}-{}.wav", name, std::process::id()));
+        let mut sink = WavSink::create(&path, 48000, format).unwrap();
+        sink.write(first);
+        sink.write(second);
+        if finish {
+            sink.finish().unwrap();
+        } else {
+            drop(sink);
+        }
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn pcm_header_describes_the_samples() {
+        let wav = record("pcm", SampleFormat::I16, Samples::I16(&[1, -1, 2, -2]), Samples::I16(&[0x1234, 0]), true);
+        assert_eq!(wav.len(), HEADER_SIZE as usize + 12);
+
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4), wav.len() as u32 - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&wav, 16), 16);
+        assert_eq!(u16_at(&wav, 20), FORMAT_PCM);
+        assert_eq!(u16_at(&wav, 22), 2);
+        assert_eq!(u32_at(&wav, 24), 48000);
+        assert_eq!(u32_at(&wav, 28), 48000 * 4);
+        assert_eq!(u16_at(&wav, 32), 4);
+        assert_eq!(u16_at(&wav, 34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 12);
+
+        assert_eq!(&wav[44..48], &[1, 0, 0xFF, 0xFF]);
+        assert_eq!(&wav[52..54], &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn float_header_is_filled_in_when_dropped() {
+        let wav = record("float", SampleFormat::F32, Samples::F32(&[0.5, -0.5]), Samples::F32(&[1.0, -1.0]), false);
+        assert_eq!(wav.len(), HEADER_SIZE as usize + 16);
+
+        assert_eq!(u32_at(&wav, 4), wav.len() as u32 - 8);
+        assert_eq!(u16_at(&wav, 20), FORMAT_IEEE_FLOAT);
+        assert_eq!(u32_at(&wav, 28), 48000 * 8);
+        assert_eq!(u16_at(&wav, 32), 8);
+        assert_eq!(u16_at(&wav, 34), 32);
+        assert_eq!(u32_at(&wav, 40), 16);
+        assert_eq!(&wav[44..48], &0.5f32.to_le_bytes());
+    }
+
+}
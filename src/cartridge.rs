@@ -1,26 +1,138 @@
+pub mod header;
 mod mbc1;
 mod mbc2;
+mod mbc3;
+mod mbc5;
 mod default;
 
+use std::path::{ Path, PathBuf };
+
+pub use header::{ write_header_checksum, CartridgeHeader, HeaderError };
+use crate::error::Error;
+use crate::state::{ SaveState, StateError, StateReader, StateWriter };
+use header::ControllerKind;
+
 const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
 
-const ROM_BANK_MODE_LOC: usize = 0x147;
-const RAM_AMOUNT_LOC: usize = 0x148;
+const RAM_AMOUNT_LOC: usize = 0x149;
 
-pub trait MemController {
+pub trait MemController: SaveState {
     fn read(&self, index: u16) -> u8;
-    fn write(&mut self, index: u16, val: u8);
-    fn load_rom(&mut self, bytes: Vec<u8>);
+    /// Returns true if the write changed something kept in the .sav file, the RAM or the clock
+    fn write(&mut self, index: u16, val: u8) -> bool;
+    /// Loads the whole ROM image (including bank 0) into the controller
+    fn load_rom(&mut self, bytes: Vec<u8>) -> crate::error::Result<()>;
+    /// Advances any hardware on the cartridge that runs off the system clock
+    fn tick(&mut self, _ticks: u8) {}
+    /// Changes what drives the real time clock, only used by controllers that have one
+    fn set_rtc_clock(&mut self, _clock: RtcClock) {}
+    /// Returns true while the rumble motor is switched on
+    fn rumble(&self) -> bool {
+        false
+    }
+    /// Returns the contents of the external RAM in the raw .sav layout
+    fn save_ram(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Restores the external RAM from a raw .sav file
+    fn load_save_ram(&mut self, _bytes: &[u8]) {}
+}
+
+/// Called with the new motor state whenever a rumble cartridge turns its motor on or off
+pub type RumbleCallback = Box<dyn FnMut(bool) + Send>;
+
+/// What the cartridge real time clock counts seconds with
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcClock {
+    /// Seconds pass with the emulated CPU clock, so they speed up and pause with the emulator
+    #[default]
+    Cycles,
+    /// Seconds pass with the host clock, even while the emulator is paused or closed
+    WallTime,
+}
+
+/// Returns the amount of external RAM in bytes declared by the cartridge header
+fn ram_size(rom: &[u8]) -> usize {
+    rom.get(RAM_AMOUNT_LOC).and_then(|code| header::ram_size(*code)).unwrap_or(0)
+}
+
+/// Joins RAM banks into one buffer of the size declared by the header
+fn flatten_ram(banks: &[Vec<u8>], size: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = banks.concat();
+    bytes.truncate(size);
+    bytes
+}
+
+/// Copies a flat buffer back into RAM banks, ignoring anything that doesn't fit
+fn fill_ram(banks: &mut [Vec<u8>], bytes: &[u8]) {
+    for (bank, chunk) in banks.iter_mut().zip(bytes.chunks(RAM_BANK_SIZE)) {
+        bank[..chunk.len()].copy_from_slice(chunk);
+    }
+}
+
+/// Writes every RAM bank to a save state as one block
+fn save_ram_banks(banks: &[Vec<u8>], writer: &mut StateWriter) {
+    writer.write_vec(&banks.concat());
+}
+
+/// Restores RAM banks written by `save_ram_banks`
+fn load_ram_banks(banks: &mut [Vec<u8>], reader: &mut StateReader) -> Result<(), StateError> {
+    let bytes = reader.read_vec()?;
+    if bytes.len() != banks.len() * RAM_BANK_SIZE {
+        return Err(StateError::Invalid("cartridge RAM size"));
+    }
+
+    fill_ram(banks, &bytes);
+    Ok(())
+}
+
+/// Fails if a ROM is larger than the memory controller can address
+fn check_rom_size(bytes: &[u8], max: usize) -> crate::error::Result<()> {
+    if bytes.len() > max {
+        Err(Error::BadSize { expected: max, actual: bytes.len() })
+    } else {
+        Ok(())
+    }
+}
+
+/// Splits a ROM image into 16KB banks, padding the last one and always returning at least the
+/// fixed bank and one switchable bank
+fn split_rom_banks(bytes: &[u8]) -> Vec<Box<[u8]>> {
+    let mut banks: Vec<Box<[u8]>> = bytes
+        .chunks(ROM_BANK_SIZE)
+        .map(|chunk| {
+            let mut bank = vec![0xFF; ROM_BANK_SIZE];
+            bank[..chunk.len()].copy_from_slice(chunk);
+            bank.into_boxed_slice()
+        })
+        .collect();
+
+    while banks.len() < 2 {
+        banks.push(vec![0xFF; ROM_BANK_SIZE].into_boxed_slice());
+    }
+
+    banks
 }
 
 /// Handles cartridge related state
 pub struct Cartridge {
     // Memory Related State
-    /// Fixed ROM Memory; No Banking
-    fixed_rom: [u8; ROM_BANK_SIZE],
     boot_rom: [u8; 0x100],
     /// ROM Bank Controler
     controller: Box<dyn MemController>,
+    /// Header of the loaded ROM
+    header: Option<CartridgeHeader>,
+    /// Clock source handed to controllers with a real time clock
+    rtc_clock: RtcClock,
+    /// Notified when the rumble motor changes state
+    rumble_callback: Option<RumbleCallback>,
+    /// True if the loaded cartridge keeps its RAM when switched off
+    battery: bool,
+    /// Where the battery backed RAM gets written to
+    save_path: Option<PathBuf>,
+    /// Set when RAM was written since the last flush
+    save_dirty: bool,
 
     pub booting: bool,
 }
@@ -34,9 +146,14 @@ impl std::default::Default for Cartridge {
         }
         
         Self {
-            fixed_rom: [0; ROM_BANK_SIZE],
             boot_rom,
             controller: Box::new(default::NoMbc::default()),
+            header: None,
+            rtc_clock: RtcClock::default(),
+            rumble_callback: None,
+            battery: false,
+            save_path: None,
+            save_dirty: false,
             booting: true,
         }
     }
@@ -45,27 +162,99 @@ impl std::default::Default for Cartridge {
 
 impl Cartridge {
     
-    // TODO: Implement ROM banking
-    /// Loads ROM data onto the cartridge rom and memory controller
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        for (i, byte) in rom.iter().enumerate() {
-            if i < ROM_BANK_SIZE {
-                self.fixed_rom[i] = *byte;
-            } else {
-                break;
+    /// Loads ROM data onto the cartridge rom and memory controller. If the cartridge has a battery
+    /// and the path of the ROM is known the matching .sav file next to it is loaded as well
+    pub fn load_rom(&mut self, rom: Vec<u8>, rom_path: Option<&Path>) -> crate::error::Result<()> {
+        let header = CartridgeHeader::parse(&rom)?;
+
+        // Real hardware never checks this so plenty of homebrew gets it wrong
+        if let Err(e) = header.verify_global_checksum(&rom) {
+            log::warn!("{}", e);
+        }
+
+        let mut controller: Box<dyn MemController> = match header.controller()? {
+            ControllerKind::None => Box::new(self::default::NoMbc::default()),
+            ControllerKind::Mbc1 => Box::new(self::mbc1::Mbc1::default()),
+            ControllerKind::Mbc2 => Box::new(self::mbc2::Mbc2::default()),
+            ControllerKind::Mbc3 => Box::new(self::mbc3::Mbc3::new(header.has_timer(), self.rtc_clock)),
+            ControllerKind::Mbc5 => Box::new(self::mbc5::Mbc5::new(header.has_rumble())),
+            _ => return Err(Error::UnsupportedMbc(header.cartridge_type)),
+        };
+        
+        // Only swap controllers once the new one has taken the ROM, so a bad ROM leaves the
+        // previous game running
+        controller.load_rom(rom)?;
+        self.controller = controller;
+
+        self.battery = header.has_battery();
+        self.save_dirty = false;
+        self.save_path = match rom_path {
+            Some(path) if self.battery => Some(path.with_extension("sav")),
+            _ => None,
+        };
+
+        if let Some(path) = &self.save_path {
+            match std::fs::read(path) {
+                Ok(bytes) => {
+                    self.controller.load_save_ram(&bytes);
+                    log::info!("Loaded save file {}", path.display());
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => log::warn!("Could not read save file {}: {}", path.display(), e),
             }
         }
 
-        match self.fixed_rom[ROM_BANK_MODE_LOC] {
-            0..=1 => self.controller = Box::new(self::default::NoMbc::default()),
-            1..=3 => self.controller = Box::new(self::mbc1::Mbc1::default()),
-            5..=6 => self.controller = Box::new(self::mbc2::Mbc2::default()),
-            _ => unreachable!(),
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Returns the header of the loaded ROM
+    pub fn header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
+    }
+
+    /// Returns the battery backed RAM in the raw .sav layout, or None if the cartridge has no
+    /// battery
+    pub fn export_save(&self) -> Option<Vec<u8>> {
+        if self.battery {
+            Some(self.controller.save_ram())
+        } else {
+            None
         }
-        
-        self.controller.load_rom(
-            rom.iter().enumerate().filter(|(i, _)| *i >= ROM_BANK_SIZE).map(|(_, x)| *x).collect()
-        );
+    }
+
+    /// Replaces the cartridge RAM with the contents of a raw .sav file
+    pub fn import_save(&mut self, bytes: &[u8]) {
+        self.controller.load_save_ram(bytes);
+    }
+
+    /// Writes the battery backed RAM to its .sav file if it changed since the last flush
+    pub fn flush_save(&mut self) -> std::io::Result<()> {
+        if !self.save_dirty {
+            return Ok(());
+        }
+
+        if let (Some(path), Some(bytes)) = (&self.save_path, self.export_save()) {
+            std::fs::write(path, bytes)?;
+        }
+
+        self.save_dirty = false;
+        Ok(())
+    }
+
+    pub fn tick(&mut self, ticks: u8) {
+        self.controller.tick(ticks);
+    }
+
+    /// Sets what drives the real time clock of the current and any later loaded cartridge
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.rtc_clock = clock;
+        self.controller.set_rtc_clock(clock);
+    }
+
+    /// Sets the function that gets told when the rumble motor turns on or off
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
     }
     
 }
@@ -73,10 +262,15 @@ impl Cartridge {
 impl crate::mmu::Memory for Cartridge {
     
     fn handle_write(&mut self, index: u16, val: u8) {
-        if (0..ROM_BANK_SIZE).contains(&(index as usize)) {
-            ()
-        } else {
-            self.controller.write(index, val);
+        let rumble = self.controller.rumble();
+        if self.controller.write(index, val) {
+            self.save_dirty = true;
+        }
+
+        if rumble != self.controller.rumble() {
+            if let Some(callback) = &mut self.rumble_callback {
+                callback(!rumble);
+            }
         }
     }
     
@@ -85,11 +279,117 @@ impl crate::mmu::Memory for Cartridge {
             return self.boot_rom[index as usize];
         }
         
-        if (0..ROM_BANK_SIZE).contains(&(index as usize)) {
-            self.fixed_rom[index as usize]
-        } else {
-            self.controller.read(index)
-        }
+        self.controller.read(index)
     }
     
 }
+
+impl SaveState for Cartridge {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.booting);
+        self.controller.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.booting = reader.read_bool()?;
+        self.controller.load_state(reader)
+    }
+
+}
+
+#[cfg(test)]
+/// A ROM of `banks` 16KB banks that each start with their own bank number, declaring the RAM
+/// size code `ram_code`
+fn banked_rom(banks: usize, ram_code: u8) -> Vec<u8> {
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for (number, bank) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+        bank[0] = number as u8;
+    }
+    rom[RAM_AMOUNT_LOC] = ram_code;
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Memory;
+
+    /// A 64KB ROM of the given cartridge type and RAM size code, with a valid header
+    fn rom_of_type(cartridge_type: u8, ram_code: u8) -> Vec<u8> {
+        let mut rom = banked_rom(4, ram_code);
+        rom[0x147] = cartridge_type;
+        rom[0x148] = 0x01;
+        write_header_checksum(&mut rom);
+        rom
+    }
+
+    /// An MBC1 ROM with 8KB of battery backed RAM
+    fn battery_rom() -> Vec<u8> {
+        rom_of_type(0x03, 0x02)
+    }
+
+    #[test]
+    fn save_file_is_written_when_dirty_and_loaded_with_the_rom() {
+        let dir = std::env::temp_dir().join(format!("jemulator-cartridge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gb");
+        let save_path = dir.join("game.sav");
+
+        let mut cartridge = Cartridge::default();
+        cartridge.load_rom(battery_rom(), Some(&rom_path)).unwrap();
+
+        // Writes the controller ignores don't make the save dirty
+        cartridge.handle_write(0x2000, 0x02);
+        cartridge.handle_write(0xA010, 0x99);
+        cartridge.flush_save().unwrap();
+        assert!(!save_path.exists());
+
+        cartridge.handle_write(0x0000, 0x0A);
+        cartridge.handle_write(0xA010, 0x99);
+        cartridge.flush_save().unwrap();
+        let save = std::fs::read(&save_path).unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x10], 0x99);
+
+        let mut reloaded = Cartridge::default();
+        reloaded.load_rom(battery_rom(), Some(&rom_path)).unwrap();
+        reloaded.handle_write(0x0000, 0x0A);
+        assert_eq!(reloaded.handle_read(0xA010), 0x99);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rumble_callback_hears_the_motor_change() {
+        use std::sync::{ Arc, Mutex };
+
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let mut cartridge = Cartridge::default();
+        // MBC5 with rumble and RAM
+        cartridge.load_rom(rom_of_type(0x1D, 0x03), None).unwrap();
+        let sink = heard.clone();
+        cartridge.set_rumble_callback(Box::new(move |on| sink.lock().unwrap().push(on)));
+
+        cartridge.handle_write(0x4000, 0x08);
+        // Changing the bank with the motor left on isn't a change
+        cartridge.handle_write(0x4000, 0x09);
+        cartridge.handle_write(0x4000, 0x01);
+        cartridge.handle_write(0x4000, 0x00);
+        cartridge.handle_write(0x4000, 0x0A);
+        assert_eq!(*heard.lock().unwrap(), [true, false, true]);
+    }
+
+    #[test]
+    fn rom_the_controller_rejects_keeps_the_previous_game() {
+        let mut cartridge = Cartridge::default();
+        cartridge.load_rom(rom_of_type(0x01, 0x00), None).unwrap();
+        cartridge.handle_write(0x2000, 0x02);
+
+        // A 64KB ROM is too big for a cartridge without a controller
+        assert!(matches!(cartridge.load_rom(rom_of_type(0x00, 0x00), None), Err(Error::BadSize { .. })));
+        assert_eq!(cartridge.handle_read(0x4000), 2);
+        assert_eq!(cartridge.header().unwrap().cartridge_type, 0x01);
+    }
+
+}
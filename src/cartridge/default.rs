@@ -1,10 +1,13 @@
 use super::MemController;
 
+const ROM_SIZE: usize = 0x8000;
 const RAM_SIZE: usize = 0x2000;
 
 pub struct NoMbc {
     rom: Box<[u8]>,
     ram: Vec<u8>,
+    /// Amount of RAM declared by the header, what ends up in a save file
+    ram_size: usize,
 }
 
 impl std::default::Default for NoMbc {
@@ -12,6 +15,7 @@ impl std::default::Default for NoMbc {
         Self {
             rom: Box::new([0]),
             ram: Vec::new(),
+            ram_size: 0,
         }
     }
 }
@@ -20,36 +24,56 @@ impl MemController for NoMbc {
     
     fn read(&self, index: u16) -> u8 {
         match index {
-            0x4000..=0x7FFF => self.rom[(index - 0x4000) as usize],
+            0x0000..=0x7FFF => self.rom[index as usize],
             0xA000..=0xBFFF => self.ram[(index - 0xA000) as usize],
             _ => unreachable!("{}", index),
         }
     }
 
-    fn write(&mut self, index: u16, val: u8) {
+    fn write(&mut self, index: u16, val: u8) -> bool {
         match index {
-            0x4000..=0x7FFF => (),
-            0xA000..=0xBFFF => self.ram[(index - 0xA000) as usize] = val,
+            0x0000..=0x7FFF => false,
+            0xA000..=0xBFFF => {
+                let offset = (index - 0xA000) as usize;
+                self.ram[offset] = val;
+                offset < self.ram_size
+            },
             _ => unreachable!("Area in memory should not try to be accessed by this funtion")
         }
     }
 
-    fn load_rom(&mut self, bytes: Vec<u8>) {
-        let mut vec_rom = Vec::with_capacity(RAM_SIZE);
+    fn load_rom(&mut self, bytes: Vec<u8>) -> crate::error::Result<()> {
+        super::check_rom_size(&bytes, ROM_SIZE)?;
 
-        for _ in 0..RAM_SIZE {
-            self.ram.push(0);
-        }
-        
-        for byte in bytes {
-            vec_rom.push(byte);
-        }
+        self.ram = vec![0; RAM_SIZE];
+        self.ram_size = super::ram_size(&bytes).min(RAM_SIZE);
 
-        if vec_rom.len() > 0x4000 {
-            panic!("Rom Vector exceeds max size");
-        }
+        let mut vec_rom = bytes;
+        vec_rom.resize(ROM_SIZE, 0);
+
+        self.rom = vec_rom.into_boxed_slice();
+        Ok(())
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.ram[..self.ram_size].to_vec()
+    }
 
-        self.rom = vec_rom.as_slice().into();
+    fn load_save_ram(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&bytes[..len]);
     }
     
 }
+
+impl crate::state::SaveState for NoMbc {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        reader.read_vec_into(&mut self.ram)
+    }
+
+}
@@ -0,0 +1,377 @@
+/*
+ *  Cartridge header layout from the pandocs:
+ *  0134-0143 Title (0134-013E on newer carts)
+ *  013F-0142 Manufacturer Code (newer carts)
+ *  0143      CGB Flag
+ *  0144-0145 New Licensee Code
+ *  0146      SGB Flag
+ *  0147      Cartridge Type
+ *  0148      ROM Size
+ *  0149      RAM Size
+ *  014A      Destination Code
+ *  014B      Old Licensee Code
+ *  014C      Mask ROM Version Number
+ *  014D      Header Checksum
+ *  014E-014F Global Checksum
+ */
+
+const TITLE_START: usize = 0x134;
+/// One past the last title byte on older carts
+const TITLE_END: usize = 0x144;
+const MANUFACTURER_START: usize = 0x13F;
+const CGB_FLAG_LOC: usize = 0x143;
+const NEW_LICENSEE_START: usize = 0x144;
+const SGB_FLAG_LOC: usize = 0x146;
+const CARTRIDGE_TYPE_LOC: usize = 0x147;
+const ROM_SIZE_LOC: usize = 0x148;
+const RAM_SIZE_LOC: usize = 0x149;
+const DESTINATION_LOC: usize = 0x14A;
+const OLD_LICENSEE_LOC: usize = 0x14B;
+const VERSION_LOC: usize = 0x14C;
+const HEADER_CHECKSUM_LOC: usize = 0x14D;
+const GLOBAL_CHECKSUM_LOC: usize = 0x14E;
+/// First byte after the header, the smallest a ROM can be
+const HEADER_END: usize = 0x150;
+
+/// Old licensee value that means the new licensee code should be used
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Reasons a ROM can be rejected when reading its header
+pub enum HeaderError {
+    /// The ROM ends before the header does
+    TooSmall(usize),
+    /// The ROM is smaller than the size declared in the header
+    SizeMismatch { declared: usize, actual: usize },
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    /// The cartridge uses a memory controller that isn't emulated
+    UnsupportedCartridgeType(u8),
+    HeaderChecksum { expected: u8, actual: u8 },
+    GlobalChecksum { expected: u16, actual: u16 },
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooSmall(len) => write!(f, "ROM is only {} bytes, too small to hold a header", len),
+            Self::SizeMismatch { declared, actual } => {
+                write!(f, "header declares a {} byte ROM but the file is {} bytes", declared, actual)
+            },
+            Self::InvalidRomSize(code) => write!(f, "invalid ROM size code {:#04x}", code),
+            Self::InvalidRamSize(code) => write!(f, "invalid RAM size code {:#04x}", code),
+            Self::UnsupportedCartridgeType(code) => write!(f, "unsupported cartridge type {:#04x}", code),
+            Self::HeaderChecksum { expected, actual } => {
+                write!(f, "header checksum is {:#04x} but the header sums to {:#04x}", expected, actual)
+            },
+            Self::GlobalChecksum { expected, actual } => {
+                write!(f, "global checksum is {:#06x} but the ROM sums to {:#06x}", expected, actual)
+            },
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How the cartridge uses Game Boy Color features
+pub enum CgbSupport {
+    /// Made for the original Game Boy
+    None,
+    /// Works on both, with extra features on a Game Boy Color
+    Enhanced,
+    /// Only runs on a Game Boy Color
+    Only,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where the cartridge was meant to be sold
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The memory bank controllers a cartridge type can use
+pub enum ControllerKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Parsed contents of the cartridge header at 0x0100-0x014F
+pub struct CartridgeHeader {
+    pub title: String,
+    /// Four character code only present on newer cartridges
+    pub manufacturer_code: Option<String>,
+    pub cgb: CgbSupport,
+    /// True if the cartridge supports Super Game Boy functions
+    pub sgb: bool,
+    pub cartridge_type: u8,
+    /// Size of the ROM in bytes
+    pub rom_size: usize,
+    /// Size of the external RAM in bytes
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub old_licensee_code: u8,
+    /// Two character code used when the old licensee code is 0x33
+    pub new_licensee_code: Option<String>,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    /// Sum of all 16 bytes of the title area, the CGB boot ROM picks palettes for older games by it
+    pub title_checksum: u8,
+}
+
+/// Returns the ROM size in bytes for the size code at 0x148
+pub fn rom_size(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some(0x8000 << code),
+        _ => None,
+    }
+}
+
+/// Returns the external RAM size in bytes for the size code at 0x149
+pub fn ram_size(code: u8) -> Option<usize> {
+    match code {
+        0x00 => Some(0),
+        0x01 => Some(0x800),
+        0x02 => Some(0x2000),
+        0x03 => Some(0x8000),
+        0x04 => Some(0x20000),
+        0x05 => Some(0x10000),
+        _ => None,
+    }
+}
+
+/// Fills in the header checksum for the title through the version byte, which the boot ROM
+/// checks before it will start a cartridge. Meant for building ROMs to test with
+pub fn write_header_checksum(rom: &mut [u8]) {
+    rom[HEADER_CHECKSUM_LOC] = CartridgeHeader::compute_header_checksum(rom);
+}
+
+impl CartridgeHeader {
+
+    /// Reads the header out of a ROM image and checks that it is consistent. The global checksum
+    /// isn't checked here since real hardware ignores it, see `verify_global_checksum`
+    pub fn parse(rom: &[u8]) -> Result<Self, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooSmall(rom.len()));
+        }
+
+        let actual = Self::compute_header_checksum(rom);
+        if actual != rom[HEADER_CHECKSUM_LOC] {
+            return Err(HeaderError::HeaderChecksum { expected: rom[HEADER_CHECKSUM_LOC], actual });
+        }
+
+        let rom_size = rom_size(rom[ROM_SIZE_LOC]).ok_or(HeaderError::InvalidRomSize(rom[ROM_SIZE_LOC]))?;
+        let ram_size = ram_size(rom[RAM_SIZE_LOC]).ok_or(HeaderError::InvalidRamSize(rom[RAM_SIZE_LOC]))?;
+
+        if rom.len() < rom_size {
+            return Err(HeaderError::SizeMismatch { declared: rom_size, actual: rom.len() });
+        }
+
+        let cgb = match rom[CGB_FLAG_LOC] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+
+        let manufacturer = &rom[MANUFACTURER_START..CGB_FLAG_LOC];
+        let manufacturer_code = if cgb != CgbSupport::None && manufacturer.iter().all(u8::is_ascii_uppercase) {
+            Some(String::from_utf8_lossy(manufacturer).into_owned())
+        } else {
+            None
+        };
+
+        // Newer cartridges shortened the title to fit the manufacturer code and CGB flag
+        let title_end = match (&manufacturer_code, cgb) {
+            (Some(_), _) => MANUFACTURER_START,
+            (None, CgbSupport::None) => TITLE_END,
+            (None, _) => CGB_FLAG_LOC,
+        };
+
+        let title = rom[TITLE_START..title_end]
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let old_licensee_code = rom[OLD_LICENSEE_LOC];
+        let new_licensee_code = if old_licensee_code == USE_NEW_LICENSEE {
+            Some(String::from_utf8_lossy(&rom[NEW_LICENSEE_START..SGB_FLAG_LOC]).into_owned())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            title,
+            manufacturer_code,
+            cgb,
+            sgb: rom[SGB_FLAG_LOC] == 0x03,
+            cartridge_type: rom[CARTRIDGE_TYPE_LOC],
+            rom_size,
+            ram_size,
+            destination: if rom[DESTINATION_LOC] == 0 { Destination::Japan } else { Destination::Overseas },
+            old_licensee_code,
+            new_licensee_code,
+            version: rom[VERSION_LOC],
+            header_checksum: rom[HEADER_CHECKSUM_LOC],
+            global_checksum: ((rom[GLOBAL_CHECKSUM_LOC] as u16) << 8) | rom[GLOBAL_CHECKSUM_LOC + 1] as u16,
+            title_checksum: rom[TITLE_START..TITLE_END].iter().fold(0, |sum, byte| sum.wrapping_add(*byte)),
+        })
+    }
+
+    /// Checks the 16 bit sum of every ROM byte except the checksum itself
+    pub fn verify_global_checksum(&self, rom: &[u8]) -> Result<(), HeaderError> {
+        let actual = rom
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != GLOBAL_CHECKSUM_LOC && *i != GLOBAL_CHECKSUM_LOC + 1)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+
+        if actual == self.global_checksum {
+            Ok(())
+        } else {
+            Err(HeaderError::GlobalChecksum { expected: self.global_checksum, actual })
+        }
+    }
+
+    /// The memory bank controller used by the cartridge type
+    pub fn controller(&self) -> Result<ControllerKind, HeaderError> {
+        let kind = match self.cartridge_type {
+            0x00 | 0x08 | 0x09 => ControllerKind::None,
+            0x01..=0x03 => ControllerKind::Mbc1,
+            0x05 | 0x06 => ControllerKind::Mbc2,
+            0x0B..=0x0D => ControllerKind::Mmm01,
+            0x0F..=0x13 => ControllerKind::Mbc3,
+            0x19..=0x1E => ControllerKind::Mbc5,
+            0x20 => ControllerKind::Mbc6,
+            0x22 => ControllerKind::Mbc7,
+            0xFC => ControllerKind::PocketCamera,
+            0xFD => ControllerKind::Tama5,
+            0xFE => ControllerKind::HuC3,
+            0xFF => ControllerKind::HuC1,
+            x => return Err(HeaderError::UnsupportedCartridgeType(x)),
+        };
+
+        Ok(kind)
+    }
+
+    /// True if the cartridge keeps its RAM alive with a battery
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFD | 0xFE | 0xFF)
+    }
+
+    /// True if the cartridge has a real time clock
+    pub fn has_timer(&self) -> bool {
+        matches!(self.cartridge_type, 0x0F | 0x10 | 0xFE)
+    }
+
+    /// True if the cartridge has a rumble motor
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.cartridge_type, 0x1C..=0x1E)
+    }
+
+    fn compute_header_checksum(rom: &[u8]) -> u8 {
+        rom[TITLE_START..HEADER_CHECKSUM_LOC]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32KB ROM with `title` and a header checksum that matches
+    fn rom_with_title(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        write_header_checksum(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn parses_a_real_header() {
+        let rom = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/drMario.gb")).unwrap();
+        let header = CartridgeHeader::parse(&rom).unwrap();
+
+        assert_eq!(header.title, "DR.MARIO");
+        assert_eq!(header.cgb, CgbSupport::None);
+        assert_eq!(header.controller(), Ok(ControllerKind::None));
+        assert_eq!(header.rom_size, 0x8000);
+        assert_eq!(header.ram_size, 0);
+        assert_eq!(header.destination, Destination::Japan);
+        assert_eq!(header.old_licensee_code, 0x01);
+        assert_eq!(header.header_checksum, 0xAA);
+        assert_eq!(header.global_checksum, 0x01FD);
+        assert_eq!(header.verify_global_checksum(&rom), Ok(()));
+    }
+
+    #[test]
+    fn header_checksum_covers_the_title_through_the_version() {
+        let mut rom = rom_with_title(b"CHECKSUM");
+        assert!(CartridgeHeader::parse(&rom).is_ok());
+
+        rom[VERSION_LOC] = 1;
+        assert_eq!(
+            CartridgeHeader::parse(&rom),
+            Err(HeaderError::HeaderChecksum { expected: rom[HEADER_CHECKSUM_LOC], actual: rom[HEADER_CHECKSUM_LOC].wrapping_sub(1) }),
+        );
+    }
+
+    #[test]
+    fn global_checksum_skips_its_own_bytes() {
+        let mut rom = rom_with_title(b"GLOBAL");
+        rom[0x4000] = 0x12;
+        let sum = rom.iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        rom[GLOBAL_CHECKSUM_LOC..GLOBAL_CHECKSUM_LOC + 2].copy_from_slice(&sum.to_be_bytes());
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.verify_global_checksum(&rom), Ok(()));
+
+        rom[0x7FFF] = 1;
+        assert_eq!(
+            header.verify_global_checksum(&rom),
+            Err(HeaderError::GlobalChecksum { expected: sum, actual: sum + 1 }),
+        );
+    }
+
+    #[test]
+    fn rejects_roms_smaller_than_declared() {
+        assert_eq!(CartridgeHeader::parse(&[0; 0x100]), Err(HeaderError::TooSmall(0x100)));
+
+        let mut rom = rom_with_title(b"SMALL");
+        rom[ROM_SIZE_LOC] = 0x01;
+        write_header_checksum(&mut rom);
+        assert_eq!(
+            CartridgeHeader::parse(&rom),
+            Err(HeaderError::SizeMismatch { declared: 0x10000, actual: 0x8000 }),
+        );
+    }
+
+    #[test]
+    fn cgb_titles_make_room_for_the_manufacturer_code() {
+        let rom = rom_with_title(b"POKEMON_\0\0\0AAXE\x80");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+
+        assert_eq!(header.title, "POKEMON_");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("AAXE"));
+        assert_eq!(header.cgb, CgbSupport::Enhanced);
+    }
+
+}
@@ -1,36 +1,270 @@
+use super::{ MemController, ROM_BANK_SIZE, RAM_BANK_SIZE };
+
+/// Largest ROM the controller can address
+const MAX_ROM_SIZE: usize = 0x20_0000;
+
+/// Size of a ROM that could be an MBC1M multicart
+const MULTICART_ROM_SIZE: usize = 0x10_0000;
+/// Start and end of the nintendo logo that is checked in every game of a multicart
+const LOGO_START: usize = 0x104;
+const LOGO_END: usize = 0x134;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BankingMode {
+    /// 0x0000-0x3FFF and 0xA000-0xBFFF are locked to bank 0
+    Simple,
+    /// The secondary register also selects the bank for 0x0000-0x3FFF and 0xA000-0xBFFF
+    Advanced,
+}
+
 pub struct Mbc1 {
-    current_rom: Box<[u8]>,
-    current_ram: Vec<u8>,
     rom_bank: Vec<Box<[u8]>>,
     ram_bank: Vec<Vec<u8>>,
-    rom_index: usize,
-    ram_index: usize,
+    /// Size of the RAM in bytes, smaller than a bank for 2KB carts
+    ram_size: usize,
+    ram_enabled: bool,
+    /// 5 bit ROM bank register at 0x2000-0x3FFF
+    rom_index: u8,
+    /// 2 bit secondary register at 0x4000-0x5FFF, upper ROM bits or RAM bank
+    secondary_index: u8,
+    mode: BankingMode,
+    /// MBC1M wiring: the secondary register lands on bit 4 instead of bit 5
+    multicart: bool,
 }
 
 impl Default for Mbc1 {
     fn default() -> Self {
         Self {
-            current_rom: Box::new([0]),
-            current_ram: Vec::new(),
             rom_bank: Vec::new(),
             ram_bank: Vec::new(),
-            ..Default::default()
+            ram_size: 0,
+            ram_enabled: false,
+            rom_index: 1,
+            secondary_index: 0,
+            mode: BankingMode::Simple,
+            multicart: false,
         }
     }
 }
 
-impl super::MemController for Mbc1 {
+impl Mbc1 {
+
+    /// Bank mapped to 0x0000-0x3FFF
+    fn low_bank(&self) -> usize {
+        match self.mode {
+            BankingMode::Simple => 0,
+            BankingMode::Advanced => self.upper_bits() % self.rom_bank.len(),
+        }
+    }
+
+    /// Bank mapped to 0x4000-0x7FFF
+    fn high_bank(&self) -> usize {
+        // The zero check is done on all 5 bits even when less are wired up
+        let index = if self.rom_index == 0 { 1 } else { self.rom_index as usize };
+        let lower = if self.multicart { index & 0x0F } else { index };
 
-    fn write(&mut self, index: u16, val: u8) {
-        todo!()
+        (self.upper_bits() | lower) % self.rom_bank.len()
+    }
+
+    fn upper_bits(&self) -> usize {
+        let shift = if self.multicart { 4 } else { 5 };
+        (self.secondary_index as usize) << shift
+    }
+
+    fn ram_bank_index(&self) -> usize {
+        match self.mode {
+            BankingMode::Simple => 0,
+            BankingMode::Advanced => self.secondary_index as usize % self.ram_bank.len(),
+        }
+    }
+
+    /// Multicarts are 1MB and repeat the logo at the start of each of their 4 games
+    fn detect_multicart(bytes: &[u8]) -> bool {
+        if bytes.len() != MULTICART_ROM_SIZE {
+            return false;
+        }
+
+        let logo = &bytes[LOGO_START..LOGO_END];
+        (1..4).all(|game| {
+            let start = game * 0x10 * ROM_BANK_SIZE;
+            &bytes[start + LOGO_START..start + LOGO_END] == logo
+        })
+    }
+
+}
+
+impl MemController for Mbc1 {
+
+    fn write(&mut self, index: u16, val: u8) -> bool {
+        match index {
+            0x0000..=0x1FFF => self.ram_enabled = (val & 0x0F) == 0x0A,
+            0x2000..=0x3FFF => self.rom_index = val & 0b0001_1111,
+            0x4000..=0x5FFF => self.secondary_index = val & 0b0000_0011,
+            0x6000..=0x7FFF => {
+                self.mode = if val & 1 == 1 { BankingMode::Advanced } else { BankingMode::Simple };
+            },
+            0xA000..=0xBFFF => {
+                if self.ram_enabled && !self.ram_bank.is_empty() {
+                    let bank = self.ram_bank_index();
+                    let offset = (index - 0xA000) as usize % self.ram_size.min(RAM_BANK_SIZE);
+                    self.ram_bank[bank][offset] = val;
+                    return true;
+                }
+            },
+            _ => unreachable!("Area in memory should not try to be accessed by this funtion")
+        }
+
+        false
     }
 
     fn read(&self, index: u16) -> u8 {
-        todo!()
+        match index {
+            0x0000..=0x3FFF => self.rom_bank[self.low_bank()][index as usize],
+            0x4000..=0x7FFF => self.rom_bank[self.high_bank()][(index - 0x4000) as usize],
+            0xA000..=0xBFFF => {
+                if self.ram_enabled && !self.ram_bank.is_empty() {
+                    let offset = (index - 0xA000) as usize % self.ram_size.min(RAM_BANK_SIZE);
+                    self.ram_bank[self.ram_bank_index()][offset]
+                } else {
+                    0xFF
+                }
+            },
+            _ => unreachable!("{}", index),
+        }
+    }
+
+    fn load_rom(&mut self, bytes: Vec<u8>) -> crate::error::Result<()> {
+        super::check_rom_size(&bytes, MAX_ROM_SIZE)?;
+
+        self.multicart = Self::detect_multicart(&bytes);
+        self.ram_size = super::ram_size(&bytes);
+
+        self.rom_bank = super::split_rom_banks(&bytes);
+
+        let ram_banks = self.ram_size.div_ceil(RAM_BANK_SIZE);
+        self.ram_bank = vec![vec![0; RAM_BANK_SIZE]; ram_banks];
+
+        Ok(())
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        super::flatten_ram(&self.ram_bank, self.ram_size)
     }
 
-    fn load_rom(&mut self, bytes: Vec<u8>) {
-        todo!()
+    fn load_save_ram(&mut self, bytes: &[u8]) {
+        super::fill_ram(&mut self.ram_bank, bytes);
     }
-    
+
+}
+
+impl crate::state::SaveState for Mbc1 {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        super::save_ram_banks(&self.ram_bank, writer);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_index);
+        writer.write_u8(self.secondary_index);
+        writer.write_bool(self.mode == BankingMode::Advanced);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        super::load_ram_banks(&mut self.ram_bank, reader)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_index = reader.read_u8()? & 0b0001_1111;
+        self.secondary_index = reader.read_u8()? & 0b0000_0011;
+        self.mode = if reader.read_bool()? { BankingMode::Advanced } else { BankingMode::Simple };
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::banked_rom;
+
+    fn mbc1(rom: Vec<u8>) -> Mbc1 {
+        let mut mbc = Mbc1::default();
+        mbc.load_rom(rom).unwrap();
+        mbc
+    }
+
+    #[test]
+    fn rom_banks_switch_with_both_registers() {
+        let mut mbc = mbc1(banked_rom(128, 0));
+        assert_eq!(mbc.read(0x4000), 1);
+
+        mbc.write(0x2000, 0x05);
+        assert_eq!(mbc.read(0x4000), 5);
+        // Bank 0 can't be picked for 0x4000-0x7FFF, it turns into bank 1
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 1);
+
+        // The secondary register is bits 5-6 of the bank
+        mbc.write(0x4000, 0x02);
+        mbc.write(0x2000, 0x05);
+        assert_eq!(mbc.read(0x4000), 0x45);
+        // Only the 5 bits of the ROM register are checked for zero
+        mbc.write(0x2000, 0x20);
+        assert_eq!(mbc.read(0x4000), 0x41);
+
+        // 0x0000-0x3FFF only follows the secondary register in advanced mode
+        assert_eq!(mbc.read(0x0000), 0);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x40);
+    }
+
+    #[test]
+    fn rom_bank_wraps_around_small_roms() {
+        let mut mbc = mbc1(banked_rom(8, 0));
+        mbc.write(0x2000, 0x0B);
+        assert_eq!(mbc.read(0x4000), 3);
+    }
+
+    #[test]
+    fn ram_needs_enabling_and_banks_in_advanced_mode() {
+        let mut mbc = mbc1(banked_rom(4, 0x03));
+
+        assert!(!mbc.write(0xA000, 0x12));
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        mbc.write(0x0000, 0x0A);
+        assert!(mbc.write(0xA000, 0x12));
+        assert_eq!(mbc.read(0xA000), 0x12);
+
+        // Simple mode keeps bank 0 whatever the secondary register holds
+        mbc.write(0x4000, 0x01);
+        assert_eq!(mbc.read(0xA000), 0x12);
+
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.read(0xA000), 0x00);
+        assert!(mbc.write(0xA000, 0x34));
+
+        mbc.write(0x6000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0x12);
+        assert_eq!(mbc.save_ram()[0], 0x12);
+        assert_eq!(mbc.save_ram()[RAM_BANK_SIZE], 0x34);
+
+        // Anything but 0x0A in the low nibble disables it again
+        mbc.write(0x0000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn multicart_shifts_the_secondary_register_down() {
+        let mut rom = banked_rom(64, 0);
+        for game in 0..4 {
+            let start = game * 0x10 * ROM_BANK_SIZE;
+            rom[start + LOGO_START..start + LOGO_END].fill(0xCE);
+        }
+        let mut mbc = mbc1(rom);
+
+        mbc.write(0x4000, 0x01);
+        mbc.write(0x2000, 0x12);
+        // Only the low 4 bits of the ROM register are wired up
+        assert_eq!(mbc.read(0x4000), 0x12);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x10);
+    }
+
 }
@@ -1,26 +1,155 @@
+use super::MemController;
+
+/// Largest ROM the controller can address
+const MAX_ROM_SIZE: usize = 0x4_0000;
+
+/// MBC2 has 512 half bytes of RAM built into the controller itself
+const RAM_SIZE: usize = 0x200;
+/// Address bit that picks between the RAM enable and ROM bank registers
+const REGISTER_SELECT_BIT: u16 = 0b0000_0001_0000_0000;
 
 pub struct Mbc2 {
-    
+    rom_bank: Vec<Box<[u8]>>,
+    /// Only the lower nibble of each byte is stored
+    ram: [u8; RAM_SIZE],
+    ram_enabled: bool,
+    /// 4 bit ROM bank register
+    rom_index: u8,
 }
 
 impl Default for Mbc2 {
     fn default() -> Self {
-       Self {} 
+        Self {
+            rom_bank: Vec::new(),
+            ram: [0; RAM_SIZE],
+            ram_enabled: false,
+            rom_index: 1,
+        }
     }
 }
 
-impl super::MemController for Mbc2 {
+impl MemController for Mbc2 {
 
     fn read(&self, index: u16) -> u8 {
-        todo!()
+        match index {
+            0x0000..=0x3FFF => self.rom_bank[0][index as usize],
+            0x4000..=0x7FFF => {
+                let bank = self.rom_index as usize % self.rom_bank.len();
+                self.rom_bank[bank][(index - 0x4000) as usize]
+            },
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    // The upper nibble is not connected and the RAM echoes every 512 bytes
+                    self.ram[(index as usize - 0xA000) % RAM_SIZE] | 0xF0
+                } else {
+                    0xFF
+                }
+            },
+            _ => unreachable!("{}", index),
+        }
+    }
+
+    fn write(&mut self, index: u16, val: u8) -> bool {
+        match index {
+            0x0000..=0x3FFF => {
+                if index & REGISTER_SELECT_BIT == 0 {
+                    self.ram_enabled = (val & 0x0F) == 0x0A;
+                } else {
+                    self.rom_index = match val & 0x0F {
+                        0 => 1,
+                        x => x,
+                    };
+                }
+            },
+            0x4000..=0x7FFF => (),
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    self.ram[(index as usize - 0xA000) % RAM_SIZE] = val & 0x0F;
+                    return true;
+                }
+            },
+            _ => unreachable!("Area in memory should not try to be accessed by this funtion")
+        }
+
+        false
     }
 
-    fn write(&mut self, index: u16, val: u8) {
-        todo!()
+    fn load_rom(&mut self, bytes: Vec<u8>) -> crate::error::Result<()> {
+        super::check_rom_size(&bytes, MAX_ROM_SIZE)?;
+
+        self.rom_bank = super::split_rom_banks(&bytes);
+
+        Ok(())
     }
 
-    fn load_rom(&mut self, bytes: Vec<u8>) {
-        todo!()
+    fn save_ram(&self) -> Vec<u8> {
+        self.ram.to_vec()
     }
-    
+
+    fn load_save_ram(&mut self, bytes: &[u8]) {
+        for (cell, byte) in self.ram.iter_mut().zip(bytes) {
+            *cell = byte & 0x0F;
+        }
+    }
+
+}
+
+impl crate::state::SaveState for Mbc2 {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_index);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        reader.read_bytes(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_index = reader.read_u8()? & 0x0F;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::banked_rom;
+
+    fn mbc2() -> Mbc2 {
+        let mut mbc = Mbc2::default();
+        mbc.load_rom(banked_rom(16, 0)).unwrap();
+        mbc
+    }
+
+    #[test]
+    fn address_bit_8_picks_the_register() {
+        let mut mbc = mbc2();
+
+        mbc.write(0x2100, 0x07);
+        assert_eq!(mbc.read(0x4000), 7);
+        // With bit 8 clear the write goes to RAM enable instead
+        mbc.write(0x2000, 0x03);
+        assert_eq!(mbc.read(0x4000), 7);
+
+        mbc.write(0x0100, 0x00);
+        assert_eq!(mbc.read(0x4000), 1);
+        mbc.write(0x3FFF, 0x1F);
+        assert_eq!(mbc.read(0x4000), 15);
+    }
+
+    #[test]
+    fn ram_is_half_bytes_that_echo() {
+        let mut mbc = mbc2();
+        assert!(!mbc.write(0xA000, 0x05));
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        mbc.write(0x0000, 0x0A);
+        assert!(mbc.write(0xA001, 0xAB));
+        assert_eq!(mbc.read(0xA001), 0xFB);
+        assert_eq!(mbc.read(0xA201), 0xFB);
+        assert_eq!(mbc.read(0xBE01), 0xFB);
+        assert_eq!(mbc.save_ram()[1], 0x0B);
+    }
+
 }
@@ -0,0 +1,763 @@
+use super::{ MemController, RtcClock, RAM_BANK_SIZE };
+
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// Largest ROM the controller can address
+const MAX_ROM_SIZE: usize = 0x20_0000;
+/// Cycles the CPU runs in one second, used when the clock follows emulated time
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+const RTC_SECONDS: u8 = 0x08;
+const RTC_MINUTES: u8 = 0x09;
+const RTC_HOURS: u8 = 0x0A;
+const RTC_DAY_LOW: u8 = 0x0B;
+const RTC_DAY_HIGH: u8 = 0x0C;
+
+/// Size of the RTC block appended to .sav files, registers and latched registers as 32 bit
+/// values followed by a 64 bit unix timestamp
+const RTC_FOOTER_SIZE: usize = 48;
+/// Older emulators write the timestamp as 32 bits
+const RTC_FOOTER_SIZE_SHORT: usize = 44;
+
+const DAY_HIGH_BIT: u8 = 0b0000_0001;
+const HALT_BIT: u8 = 0b0100_0000;
+const CARRY_BIT: u8 = 0b1000_0000;
+
+/// Which register is mapped into 0xA000-0xBFFF
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mapped {
+    Ram(usize),
+    Rtc(u8),
+    None,
+}
+
+/// The real time clock that lives on MBC3 cartridges
+#[derive(Clone)]
+struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    /// 9 bit day counter
+    days: u16,
+    halt: bool,
+    /// Set when the day counter overflows, only cleared by the game
+    carry: bool,
+    /// Copy of the registers visible to the game, updated on latch
+    latched: [u8; 5],
+
+    clock: RtcClock,
+    /// Cycles that have passed since the last full second
+    cycles: u32,
+    /// Host time the registers were last brought up to date
+    last_sync: SystemTime,
+}
+
+impl Rtc {
+
+    fn new(clock: RtcClock) -> Self {
+        Self {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            clock,
+            cycles: 0,
+            last_sync: SystemTime::now(),
+        }
+    }
+
+    fn tick(&mut self, ticks: u8) {
+        if self.clock != RtcClock::Cycles || self.halt {
+            return;
+        }
+
+        self.cycles += ticks as u32;
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    /// Catches the registers up with the host clock when running on wall time
+    fn sync(&mut self) {
+        if self.clock != RtcClock::WallTime {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.last_sync).map(|d| d.as_secs()).unwrap_or(0);
+
+        if !self.halt {
+            self.advance(elapsed);
+        }
+
+        // Keep the leftover fraction of a second for the next sync
+        self.last_sync = if self.halt {
+            now
+        } else {
+            self.last_sync + Duration::from_secs(elapsed)
+        };
+    }
+
+    /// Moves the clock forward by `seconds` at once, so catching up on a save that sat for
+    /// months costs the same as a single second
+    fn advance(&mut self, seconds: u64) {
+        let minutes = count_up(&mut self.seconds, seconds, 60, 64);
+        let hours = count_up(&mut self.minutes, minutes, 60, 64);
+        let days = count_up(&mut self.hours, hours, 24, 32) + self.days as u64;
+
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+
+    fn latch(&mut self) {
+        self.sync();
+        self.latched = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.read_register(RTC_DAY_LOW),
+            self.read_register(RTC_DAY_HIGH),
+        ];
+    }
+
+    fn read_register(&self, register: u8) -> u8 {
+        match register {
+            RTC_SECONDS => self.seconds,
+            RTC_MINUTES => self.minutes,
+            RTC_HOURS => self.hours,
+            RTC_DAY_LOW => (self.days & 0xFF) as u8,
+            RTC_DAY_HIGH => {
+                let mut val = ((self.days >> 8) as u8) & DAY_HIGH_BIT;
+                if self.halt {
+                    val |= HALT_BIT;
+                }
+                if self.carry {
+                    val |= CARRY_BIT;
+                }
+                val
+            },
+            _ => unreachable!("Not an RTC register: {:x}", register),
+        }
+    }
+
+    fn read_latched(&self, register: u8) -> u8 {
+        self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    fn write_register(&mut self, register: u8, val: u8) {
+        self.sync();
+
+        match register {
+            RTC_SECONDS => {
+                self.seconds = val & 0b0011_1111;
+                self.cycles = 0;
+            },
+            RTC_MINUTES => self.minutes = val & 0b0011_1111,
+            RTC_HOURS => self.hours = val & 0b0001_1111,
+            RTC_DAY_LOW => self.days = (self.days & 0x100) | val as u16,
+            RTC_DAY_HIGH => {
+                self.days = (self.days & 0xFF) | (((val & DAY_HIGH_BIT) as u16) << 8);
+                self.halt = val & HALT_BIT == HALT_BIT;
+                self.carry = val & CARRY_BIT == CARRY_BIT;
+            },
+            _ => unreachable!("Not an RTC register: {:x}", register),
+        }
+    }
+
+    /// Serializes the clock in the footer layout shared by most emulators
+    fn footer(&mut self) -> Vec<u8> {
+        self.sync();
+
+        let registers = [RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY_LOW, RTC_DAY_HIGH]
+            .map(|register| self.read_register(register));
+        let synced = match self.clock {
+            RtcClock::WallTime => self.last_sync,
+            RtcClock::Cycles => SystemTime::now(),
+        };
+        let timestamp = synced
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut bytes = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for val in registers.iter().chain(self.latched.iter()) {
+            bytes.extend_from_slice(&(*val as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes
+    }
+
+    /// Restores the clock from a save file footer, catching up on the time the game was closed
+    /// if the clock follows the host
+    fn load_footer(&mut self, bytes: &[u8]) {
+        let value = |i: usize| bytes[i * 4];
+
+        self.seconds = value(0) & 0b0011_1111;
+        self.minutes = value(1) & 0b0011_1111;
+        self.hours = value(2) & 0b0001_1111;
+        self.days = value(3) as u16 | (((value(4) & DAY_HIGH_BIT) as u16) << 8);
+        self.halt = value(4) & HALT_BIT == HALT_BIT;
+        self.carry = value(4) & CARRY_BIT == CARRY_BIT;
+        for (i, latched) in self.latched.iter_mut().enumerate() {
+            *latched = value(5 + i);
+        }
+        self.cycles = 0;
+
+        let timestamp = if bytes.len() >= RTC_FOOTER_SIZE {
+            u64::from_le_bytes(bytes[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as u64
+        };
+        self.last_sync = UNIX_EPOCH + Duration::from_secs(timestamp);
+        self.sync();
+    }
+
+    fn set_clock(&mut self, clock: RtcClock) {
+        self.sync();
+        self.clock = clock;
+        self.last_sync = SystemTime::now();
+    }
+
+}
+
+/// Adds `amount` to a register that carries into the next one at `limit`, returning how many
+/// times it carried. Out of range values a game wrote count up to the register's bit width
+/// `width` and wrap to 0 without carrying
+fn count_up(register: &mut u8, amount: u64, limit: u64, width: u64) -> u64 {
+    let mut amount = amount;
+    let mut value = *register as u64;
+    if value >= limit {
+        if amount < width - value {
+            *register = (value + amount) as u8;
+            return 0;
+        }
+        amount -= width - value;
+        value = 0;
+    }
+
+    let total = value + amount;
+    *register = (total % limit) as u8;
+    total / limit
+}
+
+pub struct Mbc3 {
+    rom_bank: Vec<Box<[u8]>>,
+    ram_bank: Vec<Vec<u8>>,
+    ram_size: usize,
+    /// Enables both the RAM and the RTC registers
+    ram_enabled: bool,
+    /// 7 bit ROM bank register
+    rom_index: u8,
+    mapped: Mapped,
+    /// Only the timer cartridge types have a clock
+    rtc: Option<Rtc>,
+    /// Last value written to the latch register, latching happens on a 0 -> 1 write
+    latch_reg: u8,
+}
+
+impl Mbc3 {
+
+    pub fn new(has_timer: bool, clock: RtcClock) -> Self {
+        Self {
+            rom_bank: Vec::new(),
+            ram_bank: Vec::new(),
+            ram_size: 0,
+            ram_enabled: false,
+            rom_index: 1,
+            mapped: Mapped::Ram(0),
+            rtc: if has_timer { Some(Rtc::new(clock)) } else { None },
+            latch_reg: 0xFF,
+        }
+    }
+
+}
+
+impl MemController for Mbc3 {
+
+    fn read(&self, index: u16) -> u8 {
+        match index {
+            0x0000..=0x3FFF => self.rom_bank[0][index as usize],
+            0x4000..=0x7FFF => {
+                let bank = self.rom_index as usize % self.rom_bank.len();
+                self.rom_bank[bank][(index - 0x4000) as usize]
+            },
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+
+                match (self.mapped, &self.rtc) {
+                    (Mapped::Ram(bank), _) if bank < self.ram_bank.len() => {
+                        let offset = (index - 0xA000) as usize % self.ram_size.min(RAM_BANK_SIZE);
+                        self.ram_bank[bank][offset]
+                    },
+                    (Mapped::Rtc(register), Some(rtc)) => rtc.read_latched(register),
+                    _ => 0xFF,
+                }
+            },
+            _ => unreachable!("{}", index),
+        }
+    }
+
+    fn write(&mut self, index: u16, val: u8) -> bool {
+        match index {
+            0x0000..=0x1FFF => self.ram_enabled = (val & 0x0F) == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_index = match val & 0b0111_1111 {
+                    0 => 1,
+                    x => x,
+                };
+            },
+            0x4000..=0x5FFF => {
+                self.mapped = match val {
+                    0x00..=0x03 => Mapped::Ram(val as usize),
+                    RTC_SECONDS..=RTC_DAY_HIGH => Mapped::Rtc(val),
+                    _ => Mapped::None,
+                };
+            },
+            0x6000..=0x7FFF => {
+                let latched = self.latch_reg == 0 && val == 1;
+                self.latch_reg = val;
+
+                // The latched registers are part of the clock saved in the .sav footer
+                if let (true, Some(rtc)) = (latched, &mut self.rtc) {
+                    rtc.latch();
+                    return true;
+                }
+            },
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return false;
+                }
+
+                // Writing the clock registers, halting it included, changes the saved clock
+                match (self.mapped, &mut self.rtc) {
+                    (Mapped::Ram(bank), _) if bank < self.ram_bank.len() => {
+                        let offset = (index - 0xA000) as usize % self.ram_size.min(RAM_BANK_SIZE);
+                        self.ram_bank[bank][offset] = val;
+                        return true;
+                    },
+                    (Mapped::Rtc(register), Some(rtc)) => {
+                        rtc.write_register(register, val);
+                        return true;
+                    },
+                    _ => (),
+                }
+            },
+            _ => unreachable!("Area in memory should not try to be accessed by this funtion")
+        }
+
+        false
+    }
+
+    fn load_rom(&mut self, bytes: Vec<u8>) -> crate::error::Result<()> {
+        super::check_rom_size(&bytes, MAX_ROM_SIZE)?;
+
+        self.ram_size = super::ram_size(&bytes);
+        self.rom_bank = super::split_rom_banks(&bytes);
+
+        let ram_banks = self.ram_size.div_ceil(RAM_BANK_SIZE);
+        self.ram_bank = vec![vec![0; RAM_BANK_SIZE]; ram_banks];
+
+        Ok(())
+    }
+
+    fn tick(&mut self, ticks: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(ticks);
+        }
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        let mut bytes = super::flatten_ram(&self.ram_bank, self.ram_size);
+
+        if let Some(rtc) = &self.rtc {
+            // Bring the copy up to date with the host clock without touching the live clock
+            let mut rtc = rtc.clone();
+            bytes.extend(rtc.footer());
+        }
+
+        bytes
+    }
+
+    fn load_save_ram(&mut self, bytes: &[u8]) {
+        let ram = bytes.len().min(self.ram_size);
+        super::fill_ram(&mut self.ram_bank, &bytes[..ram]);
+
+        if let Some(rtc) = &mut self.rtc {
+            let footer = &bytes[ram..];
+            if footer.len() == RTC_FOOTER_SIZE || footer.len() == RTC_FOOTER_SIZE_SHORT {
+                rtc.load_footer(footer);
+            }
+        }
+    }
+
+    fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_clock(clock);
+        }
+    }
+
+}
+
+impl crate::state::SaveState for Mbc3 {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        super::save_ram_banks(&self.ram_bank, writer);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_index);
+        writer.write_u8(match self.mapped {
+            Mapped::Ram(bank) => bank as u8,
+            Mapped::Rtc(register) => register,
+            Mapped::None => 0xFF,
+        });
+        writer.write_u8(self.latch_reg);
+
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        super::load_ram_banks(&mut self.ram_bank, reader)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_index = reader.read_u8()? & 0b0111_1111;
+        self.mapped = match reader.read_u8()? {
+            x @ 0x00..=0x03 => Mapped::Ram(x as usize),
+            x @ RTC_SECONDS..=RTC_DAY_HIGH => Mapped::Rtc(x),
+            _ => Mapped::None,
+        };
+        self.latch_reg = reader.read_u8()?;
+
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+
+}
+
+impl crate::state::SaveState for Rtc {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u16(self.days);
+        writer.write_bool(self.halt);
+        writer.write_bool(self.carry);
+        writer.write_bytes(&self.latched);
+        writer.write_u32(self.cycles);
+
+        // Only a clock that follows the host needs to know when it was saved, so the same
+        // emulated moment always saves the same bytes
+        writer.write_bool(self.clock == RtcClock::WallTime);
+        if self.clock == RtcClock::WallTime {
+            let since_epoch = self.last_sync.duration_since(UNIX_EPOCH).unwrap_or_default();
+            writer.write_u64(since_epoch.as_secs());
+            writer.write_u32(since_epoch.subsec_nanos());
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        self.seconds = reader.read_u8()? & 0b0011_1111;
+        self.minutes = reader.read_u8()? & 0b0011_1111;
+        self.hours = reader.read_u8()? & 0b0001_1111;
+        self.days = reader.read_u16()? & 0x1FF;
+        self.halt = reader.read_bool()?;
+        self.carry = reader.read_bool()?;
+        reader.read_bytes(&mut self.latched)?;
+        self.cycles = reader.read_u32()?;
+        if self.cycles >= CYCLES_PER_SECOND {
+            return Err(crate::state::StateError::Invalid("RTC cycle count"));
+        }
+
+        self.last_sync = if reader.read_bool()? {
+            let seconds = reader.read_u64()?;
+            let nanos = reader.read_u32()?;
+            UNIX_EPOCH + Duration::new(seconds, nanos)
+        } else {
+            SystemTime::now()
+        };
+        // Catch up on the time since the state was made
+        self.sync();
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::banked_rom;
+
+    fn mbc3(has_timer: bool) -> Mbc3 {
+        let mut mbc = Mbc3::new(has_timer, RtcClock::Cycles);
+        mbc.load_rom(banked_rom(128, 0x03)).unwrap();
+        mbc
+    }
+
+    fn run_seconds(mbc: &mut Mbc3, seconds: u32) {
+        for _ in 0..seconds * (CYCLES_PER_SECOND / 128) {
+            mbc.tick(128);
+        }
+    }
+
+    fn latch(mbc: &mut Mbc3) -> bool {
+        mbc.write(0x6000, 0x00);
+        mbc.write(0x6000, 0x01)
+    }
+
+    fn read_rtc(mbc: &mut Mbc3, register: u8) -> u8 {
+        mbc.write(0x4000, register);
+        mbc.read(0xA000)
+    }
+
+    #[test]
+    fn rom_bank_register_is_7_bits() {
+        let mut mbc = mbc3(false);
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 1);
+        mbc.write(0x2000, 0x45);
+        assert_eq!(mbc.read(0x4000), 0x45);
+        mbc.write(0x2000, 0xFF);
+        assert_eq!(mbc.read(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn ram_banks_and_clock_share_the_window() {
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+
+        mbc.write(0x4000, 0x02);
+        assert!(mbc.write(0xA000, 0x22));
+        mbc.write(0x4000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0x00);
+        mbc.write(0x4000, 0x02);
+        assert_eq!(mbc.read(0xA000), 0x22);
+
+        mbc.write(0x4000, RTC_SECONDS);
+        assert_eq!(mbc.read(0xA000), 0);
+        mbc.write(0x4000, 0x07);
+        assert!(!mbc.write(0xA000, 0x11));
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn clock_is_only_seen_after_a_latch() {
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+
+        run_seconds(&mut mbc, 3);
+        assert_eq!(read_rtc(&mut mbc, RTC_SECONDS), 0);
+
+        assert!(latch(&mut mbc));
+        assert_eq!(read_rtc(&mut mbc, RTC_SECONDS), 3);
+
+        // Writing 1 again without a 0 in between doesn't latch
+        run_seconds(&mut mbc, 2);
+        assert!(!mbc.write(0x6000, 0x01));
+        assert_eq!(read_rtc(&mut mbc, RTC_SECONDS), 3);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, RTC_SECONDS), 5);
+    }
+
+    #[test]
+    fn clock_rolls_over_into_days_and_sets_the_carry() {
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+
+        mbc.write(0x4000, RTC_SECONDS);
+        mbc.write(0xA000, 59);
+        mbc.write(0x4000, RTC_MINUTES);
+        mbc.write(0xA000, 59);
+        mbc.write(0x4000, RTC_HOURS);
+        mbc.write(0xA000, 23);
+        mbc.write(0x4000, RTC_DAY_LOW);
+        mbc.write(0xA000, 0xFF);
+        mbc.write(0x4000, RTC_DAY_HIGH);
+        mbc.write(0xA000, DAY_HIGH_BIT);
+
+        run_seconds(&mut mbc, 1);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, RTC_SECONDS), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_MINUTES), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_HOURS), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_DAY_LOW), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_DAY_HIGH), CARRY_BIT);
+    }
+
+    /// Moves the clock on by one second a register at a time, the way the hardware counts
+    fn step_second(rtc: &mut Rtc) {
+        // Registers wrap at their bit width if a game wrote an out of range value
+        rtc.seconds = (rtc.seconds + 1) & 0b0011_1111;
+        if rtc.seconds != 60 {
+            return;
+        }
+        rtc.seconds = 0;
+
+        rtc.minutes = (rtc.minutes + 1) & 0b0011_1111;
+        if rtc.minutes != 60 {
+            return;
+        }
+        rtc.minutes = 0;
+
+        rtc.hours = (rtc.hours + 1) & 0b0001_1111;
+        if rtc.hours != 24 {
+            return;
+        }
+        rtc.hours = 0;
+
+        rtc.days += 1;
+        if rtc.days > 0x1FF {
+            rtc.days = 0;
+            rtc.carry = true;
+        }
+    }
+
+    #[test]
+    fn catching_up_matches_counting_every_second() {
+        // In range values, then ones a game wrote that are past where the registers carry
+        let starts = [
+            (0, 0, 0, 0), (59, 59, 23, 0x1FF), (12, 34, 5, 0x123),
+            (62, 59, 23, 0x1FF), (59, 61, 23, 0), (30, 59, 30, 0x1FE),
+        ];
+        for (seconds, minutes, hours, days) in starts {
+            for elapsed in [1, 2, 59, 61, 3599, 3601, 86_399, 86_401, 200_000] {
+                let mut bulk = Rtc::new(RtcClock::Cycles);
+                (bulk.seconds, bulk.minutes, bulk.hours, bulk.days) = (seconds, minutes, hours, days);
+                let mut stepped = bulk.clone();
+
+                bulk.advance(elapsed);
+                for _ in 0..elapsed {
+                    step_second(&mut stepped);
+                }
+                assert_eq!(
+                    (bulk.seconds, bulk.minutes, bulk.hours, bulk.days, bulk.carry),
+                    (stepped.seconds, stepped.minutes, stepped.hours, stepped.days, stepped.carry),
+                    "{:?} + {}", (seconds, minutes, hours, days), elapsed,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn years_away_only_set_the_day_carry() {
+        let mut rtc = Rtc::new(RtcClock::Cycles);
+        // Two years, two days, three hours, four minutes and five seconds
+        rtc.advance((2 * 365 + 2) * 86_400 + 3 * 3600 + 4 * 60 + 5);
+        assert_eq!((rtc.seconds, rtc.minutes, rtc.hours, rtc.days), (5, 4, 3, (2 * 365 + 2) % 512));
+        assert!(rtc.carry);
+    }
+
+    #[test]
+    fn halted_clock_stands_still() {
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, RTC_DAY_HIGH);
+        assert!(mbc.write(0xA000, HALT_BIT));
+
+        run_seconds(&mut mbc, 2);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, RTC_SECONDS), 0);
+        assert_eq!(read_rtc(&mut mbc, RTC_DAY_HIGH), HALT_BIT);
+    }
+
+    #[test]
+    fn save_file_keeps_the_ram_and_clock() {
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, 0x01);
+        mbc.write(0xA123, 0x77);
+        mbc.write(0x4000, RTC_MINUTES);
+        mbc.write(0xA000, 42);
+        // Halted so no time passes between saving and loading
+        mbc.write(0x4000, RTC_DAY_HIGH);
+        mbc.write(0xA000, HALT_BIT | DAY_HIGH_BIT);
+        latch(&mut mbc);
+
+        let save = mbc.save_ram();
+        assert_eq!(save.len(), 0x8000 + RTC_FOOTER_SIZE);
+        // The registers and then the latched registers, each as a little endian u32
+        assert_eq!(save[0x8004..0x8008], [42, 0, 0, 0]);
+        assert_eq!(save[0x8018..0x801C], [42, 0, 0, 0]);
+
+        let mut loaded = mbc3(true);
+        loaded.load_save_ram(&save);
+        loaded.write(0x0000, 0x0A);
+        loaded.write(0x4000, 0x01);
+        assert_eq!(loaded.read(0xA123), 0x77);
+        assert_eq!(read_rtc(&mut loaded, RTC_MINUTES), 42);
+        latch(&mut loaded);
+        assert_eq!(read_rtc(&mut loaded, RTC_MINUTES), 42);
+        assert_eq!(read_rtc(&mut loaded, RTC_DAY_HIGH), HALT_BIT | DAY_HIGH_BIT);
+    }
+
+    #[test]
+    fn short_footer_from_older_emulators_loads() {
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, RTC_HOURS);
+        mbc.write(0xA000, 13);
+        mbc.write(0x4000, RTC_DAY_HIGH);
+        mbc.write(0xA000, HALT_BIT);
+
+        let save = mbc.save_ram();
+        let mut loaded = mbc3(true);
+        loaded.load_save_ram(&save[..0x8000 + RTC_FOOTER_SIZE_SHORT]);
+        loaded.write(0x0000, 0x0A);
+        latch(&mut loaded);
+        assert_eq!(read_rtc(&mut loaded, RTC_HOURS), 13);
+    }
+
+    #[test]
+    fn only_accepted_writes_change_the_save() {
+        let mut mbc = mbc3(true);
+        // Bank switches and writes while RAM is disabled don't touch anything saved
+        assert!(!mbc.write(0x2000, 0x05));
+        assert!(!mbc.write(0x4000, 0x01));
+        assert!(!mbc.write(0xA000, 0x12));
+
+        mbc.write(0x0000, 0x0A);
+        assert!(mbc.write(0xA000, 0x12));
+        mbc.write(0x4000, RTC_SECONDS);
+        assert!(mbc.write(0xA000, 30));
+        assert!(latch(&mut mbc));
+    }
+
+    #[test]
+    fn save_state_only_depends_on_emulated_time() {
+        use crate::state::{ SaveState, StateReader, StateWriter };
+
+        let save = |mbc: &Mbc3| {
+            let mut writer = StateWriter::new(0, 0);
+            mbc.save_state(&mut writer);
+            writer.into_bytes()
+        };
+
+        let mut mbc = mbc3(true);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, RTC_HOURS);
+        mbc.write(0xA000, 5);
+        run_seconds(&mut mbc, 3);
+        mbc.tick(200);
+
+        let state = save(&mbc);
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(save(&mbc) == state);
+
+        let mut loaded = mbc3(true);
+        loaded.load_state(&mut StateReader::new(&state, 0, 0).unwrap()).unwrap();
+        assert!(save(&loaded) == state);
+        latch(&mut loaded);
+        assert_eq!(read_rtc(&mut loaded, RTC_SECONDS), 3);
+        assert_eq!(read_rtc(&mut loaded, RTC_HOURS), 5);
+    }
+
+}
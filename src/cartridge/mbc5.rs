@@ -0,0 +1,264 @@
+use super::{ MemController, RAM_BANK_SIZE };
+
+/// Largest ROM the controller can address
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
+/// On rumble cartridges this bit of the RAM bank register drives the motor
+const RUMBLE_BIT: u8 = 0b0000_1000;
+
+pub struct Mbc5 {
+    rom_bank: Vec<Box<[u8]>>,
+    ram_bank: Vec<Vec<u8>>,
+    ram_size: usize,
+    ram_enabled: bool,
+    /// 9 bit ROM bank register, bank 0 can be mapped to 0x4000-0x7FFF
+    rom_index: u16,
+    /// 4 bit RAM bank register, only 3 bits on rumble cartridges
+    ram_index: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl Mbc5 {
+
+    pub fn new(has_rumble: bool) -> Self {
+        Self {
+            rom_bank: Vec::new(),
+            ram_bank: Vec::new(),
+            ram_size: 0,
+            ram_enabled: false,
+            rom_index: 1,
+            ram_index: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+
+    /// Bits of the RAM bank register that select a bank, rumble cartridges give the top one to
+    /// the motor
+    fn ram_index_mask(&self) -> u8 {
+        if self.has_rumble {
+            0b0000_0111
+        } else {
+            0b0000_1111
+        }
+    }
+
+    fn ram_offset(&self, index: u16) -> Option<(usize, usize)> {
+        if !self.ram_enabled || self.ram_bank.is_empty() {
+            return None;
+        }
+
+        let bank = self.ram_index as usize % self.ram_bank.len();
+        let offset = (index - 0xA000) as usize % self.ram_size.min(RAM_BANK_SIZE);
+        Some((bank, offset))
+    }
+
+}
+
+impl MemController for Mbc5 {
+
+    fn read(&self, index: u16) -> u8 {
+        match index {
+            0x0000..=0x3FFF => self.rom_bank[0][index as usize],
+            0x4000..=0x7FFF => {
+                let bank = self.rom_index as usize % self.rom_bank.len();
+                self.rom_bank[bank][(index - 0x4000) as usize]
+            },
+            0xA000..=0xBFFF => match self.ram_offset(index) {
+                Some((bank, offset)) => self.ram_bank[bank][offset],
+                None => 0xFF,
+            },
+            _ => unreachable!("{}", index),
+        }
+    }
+
+    fn write(&mut self, index: u16, val: u8) -> bool {
+        match index {
+            // Unlike MBC1 the upper nibble has to be 0 too
+            0x0000..=0x1FFF => self.ram_enabled = val == 0x0A,
+            0x2000..=0x2FFF => self.rom_index = (self.rom_index & 0x100) | val as u16,
+            0x3000..=0x3FFF => self.rom_index = (self.rom_index & 0xFF) | (((val & 1) as u16) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = val & RUMBLE_BIT == RUMBLE_BIT;
+                }
+                self.ram_index = val & self.ram_index_mask();
+            },
+            0x6000..=0x7FFF => (),
+            0xA000..=0xBFFF => {
+                if let Some((bank, offset)) = self.ram_offset(index) {
+                    self.ram_bank[bank][offset] = val;
+                    return true;
+                }
+            },
+            _ => unreachable!("Area in memory should not try to be accessed by this funtion")
+        }
+
+        false
+    }
+
+    fn load_rom(&mut self, bytes: Vec<u8>) -> crate::error::Result<()> {
+        super::check_rom_size(&bytes, MAX_ROM_SIZE)?;
+
+        self.ram_size = super::ram_size(&bytes);
+        self.rom_bank = super::split_rom_banks(&bytes);
+
+        let ram_banks = self.ram_size.div_ceil(RAM_BANK_SIZE);
+        self.ram_bank = vec![vec![0; RAM_BANK_SIZE]; ram_banks];
+
+        Ok(())
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        super::flatten_ram(&self.ram_bank, self.ram_size)
+    }
+
+    fn load_save_ram(&mut self, bytes: &[u8]) {
+        super::fill_ram(&mut self.ram_bank, bytes);
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+}
+
+impl crate::state::SaveState for Mbc5 {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        super::save_ram_banks(&self.ram_bank, writer);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_index);
+        writer.write_u8(self.ram_index);
+        writer.write_bool(self.rumble);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        super::load_ram_banks(&mut self.ram_bank, reader)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_index = reader.read_u16()? & 0x1FF;
+        self.ram_index = reader.read_u8()? & self.ram_index_mask();
+        self.rumble = reader.read_bool()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::banked_rom;
+
+    fn mbc5(banks: usize, ram_code: u8, has_rumble: bool) -> Mbc5 {
+        let mut rom = banked_rom(banks, ram_code);
+        // Banks past 255 need the high bit of their number too
+        for (number, bank) in rom.chunks_mut(0x4000).enumerate() {
+            bank[1] = (number >> 8) as u8;
+        }
+
+        let mut mbc = Mbc5::new(has_rumble);
+        mbc.load_rom(rom).unwrap();
+        mbc
+    }
+
+    fn switchable_bank(mbc: &Mbc5) -> u16 {
+        mbc.read(0x4000) as u16 | (mbc.read(0x4001) as u16) << 8
+    }
+
+    #[test]
+    fn rom_bank_register_is_9_bits_and_can_map_bank_0() {
+        let mut mbc = mbc5(512, 0x00, false);
+        assert_eq!(switchable_bank(&mbc), 1);
+
+        // Unlike MBC1 bank 0 is not turned into bank 1
+        mbc.write(0x2000, 0x00);
+        assert_eq!(switchable_bank(&mbc), 0);
+
+        mbc.write(0x2000, 0x23);
+        mbc.write(0x3000, 0xFF);
+        assert_eq!(switchable_bank(&mbc), 0x123);
+        mbc.write(0x2000, 0xFF);
+        assert_eq!(switchable_bank(&mbc), 0x1FF);
+        mbc.write(0x3000, 0x00);
+        assert_eq!(switchable_bank(&mbc), 0xFF);
+        assert_eq!(mbc.read(0x0000), 0);
+    }
+
+    #[test]
+    fn ram_has_16_banks() {
+        let mut mbc = mbc5(4, 0x04, false);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+        assert!(!mbc.write(0xA000, 0x12));
+
+        mbc.write(0x0000, 0x0A);
+        for bank in 0..16 {
+            mbc.write(0x4000, bank);
+            assert!(mbc.write(0xB000, 0x80 | bank));
+        }
+        for bank in 0..16 {
+            mbc.write(0x4000, bank);
+            assert_eq!(mbc.read(0xB000), 0x80 | bank);
+        }
+
+        let save = mbc.save_ram();
+        assert_eq!(save.len(), 16 * RAM_BANK_SIZE);
+        assert_eq!(save[15 * RAM_BANK_SIZE + 0x1000], 0x8F);
+    }
+
+    #[test]
+    fn rumble_takes_the_top_bit_of_the_ram_bank() {
+        let mut mbc = mbc5(4, 0x03, true);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, 0x01);
+        mbc.write(0xA000, 0x11);
+        assert!(!mbc.rumble());
+
+        // The motor turns on and bank 1 stays mapped
+        mbc.write(0x4000, RUMBLE_BIT | 0x01);
+        assert!(mbc.rumble());
+        assert_eq!(mbc.read(0xA000), 0x11);
+
+        mbc.write(0x4000, 0x02);
+        assert!(!mbc.rumble());
+        assert_eq!(mbc.read(0xA000), 0x00);
+
+        // Without rumble the same bit selects a bank
+        let mut mbc = mbc5(4, 0x04, false);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, RUMBLE_BIT | 0x01);
+        mbc.write(0xA000, 0x99);
+        mbc.write(0x4000, 0x01);
+        assert_eq!(mbc.read(0xA000), 0x00);
+        assert!(!mbc.rumble());
+    }
+
+    #[test]
+    fn ram_only_enables_on_exactly_0x0a() {
+        let mut mbc = mbc5(4, 0x03, false);
+        mbc.write(0x0000, 0x1A);
+        assert!(!mbc.write(0xA000, 0x12));
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        mbc.write(0x0000, 0x0A);
+        assert!(mbc.write(0xA000, 0x12));
+        assert_eq!(mbc.read(0xA000), 0x12);
+    }
+
+    #[test]
+    fn loading_a_state_masks_the_ram_bank_like_a_write() {
+        use crate::state::{ SaveState, StateReader, StateWriter };
+
+        let mut mbc = mbc5(4, 0x03, true);
+        let mut writer = StateWriter::new(0, 0);
+        mbc.save_state(&mut writer);
+        let mut state = writer.into_bytes();
+        // ram_index sits just before the rumble flag at the end
+        let at = state.len() - 2;
+        state[at] = RUMBLE_BIT | 0x02;
+
+        mbc.load_state(&mut StateReader::new(&state, 0, 0).unwrap()).unwrap();
+        assert_eq!(mbc.ram_index, 0x02);
+    }
+
+}
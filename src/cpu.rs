@@ -1,39 +1,46 @@
 use crate::dissasembler::{
-    Condition, Dissasembler, Flags, Instruction, Register, RegisterData, Take,
+    Condition, Flags, Instruction, OpCode, Register, RegisterData, Take, PREFIXED, UNPREFIXED,
 };
+use crate::cartridge::RtcClock;
+use crate::error::Result;
 use crate::interupts::Interupt;
 use crate::mmu::MMU;
+use crate::profile::{ self, Subsystem };
 use crate::register::Registers;
+use crate::state::{ SaveState, StateError, StateReader, StateWriter };
 use crate::util::{le_combine, BitOperations};
 
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::LineWriter;
-use std::io::Write;
+use std::path::Path;
 
 pub struct Cpu {
     pub registers: Registers,
     pub mmu: MMU,
     pub halted: bool,
-    pub debug_file: LineWriter<File>,
+    /// Set by STOP, the CPU and LCD sit still until a button is pressed
+    pub stopped: bool,
+    /// Set by an opcode the CPU doesn't have, which hangs it for good while the rest of the
+    /// machine carries on
+    pub locked: bool,
+    /// Instructions left to run before an EI takes effect, IME is set once this counts down to 0
+    ei_delay: u8,
+    /// Set by a HALT that didn't halt because of a pending interupt, the next opcode is read
+    /// without moving PC past it
+    halt_bug: bool,
+    /// Clock cycles the rest of the machine has been run for during the current instruction
+    cycles: u32,
 }
 
 impl Cpu {
-    pub fn from_rom(rom: Vec<u8>) -> Self {
-        File::create("log.txt").unwrap();
-        let file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open("log.txt")
-            .unwrap();
-
-        let debug_file = LineWriter::new(file);
-
+    pub fn from_rom(rom: Vec<u8>, rom_path: Option<&Path>, rtc_clock: RtcClock) -> Result<Self> {
         let mut cpu = Self {
             registers: Registers::default(),
-            mmu: MMU::new(rom),
+            mmu: MMU::new(rom, rom_path, rtc_clock)?,
             halted: false,
-            debug_file,
+            stopped: false,
+            locked: false,
+            ei_delay: 0,
+            halt_bug: false,
+            cycles: 0,
         };
 
         // Cpu defaults
@@ -44,16 +51,52 @@ impl Cpu {
         cpu.registers.set_hl(0x014D);
         cpu.registers.sp = 0xFFFE;
 
-        cpu
+        // Without a CGB boot ROM to run, start the cartridge with the registers it leaves behind.
+        // A being 0x11 is how games tell they are on a CGB
+        if cpu.mmu.cgb {
+            cpu.registers.pc = 0x100;
+            cpu.registers.set_af(0x1180);
+            cpu.registers.set_bc(0x0000);
+            cpu.registers.set_de(0xFF56);
+            cpu.registers.set_hl(0x000D);
+        }
+
+        Ok(cpu)
     }
 
-    pub fn tick(&mut self, d: &Dissasembler) -> (u8, bool) {
+    /// Checksums of the loaded ROM that tie a save state to it
+    pub fn rom_checksums(&self) -> (u16, u8) {
+        self.mmu.cartridge.header()
+            .map(|header| (header.global_checksum, header.header_checksum))
+            .unwrap_or((0, 0))
+    }
+
+    /// Runs one instruction, or one M-cycle of being halted or locked up, and returns how many clock cycles
+    /// it took. The rest of the machine is run along with every memory access
+    pub fn tick(&mut self) -> u32 {
+        self.cycles = 0;
+
+        // Nothing but the joypad runs while stopped
+        if self.stopped {
+            if self.mmu.joypad.lines_low() {
+                self.stopped = false;
+            }
+            return 4;
+        }
+
+        if self.locked {
+            self.internal_cycle();
+            return self.cycles;
+        }
+
         if self.halted {
+            self.internal_cycle();
+            // Any requested and enabled interupt wakes the CPU, whether or not IME lets it be
+            // serviced
             if self.mmu.interupt.has_interupts() {
                 self.halted = false;
-                return (4, true);
             }
-            return (4, false);
+            return self.cycles;
         }
         
         let pc = self.registers.pc;
@@ -77,42 +120,14 @@ impl Cpu {
                           self.mmu.read_8(pc.wrapping_add(3))
                           ).to_uppercase();
 
-        self.debug_file.write_all(str.as_bytes()).unwrap();
+        log::trace!("{}", str.trim_end());
         */
         //println!("{}", self.registers.pc);
-        let mut code = &d.unprefixed[&self.mmu.read_8(self.registers.pc)];
-        self.increment(RegisterData::from_reg(Register::PC));
-
-        if matches!(code.instruction, Instruction::PREFIX) {
-            code = &d.prefixed[&self.mmu.read_8(self.registers.pc)];
-            self.increment(RegisterData::from_reg(Register::PC));
-        }
-
-        let mut instruction = code.instruction;
-
-        //println!("Running: {}", instruction);
-        
-        match code.extra_data {
-            Take::None => (),
-            Take::Eight => {
-                if code.code == 0xE0 || code.code == 0xF8 {
-                    instruction = instruction.insert_r1(self.get_8());
-                } else {
-                    instruction = instruction.insert_r2(self.get_8());
-                }
-            }
-            Take::Sixteen => {
-                if code.code == 0x08 || code.code == 0xEA {
-                    instruction = instruction.insert_r1(self.get_16())
-                } else {
-                    instruction = instruction.insert_r2(self.get_16())
-                }
-            }
-        }
+        let (code, instruction, cycles) = profile::time(Subsystem::Decode, || self.decode());
 
         match instruction {
             Instruction::NOP => (),
-            Instruction::STOP => std::process::exit(0),
+            Instruction::STOP => self.stop(),
             Instruction::HALT => self.halt_cpu(),
             Instruction::EI => self.enable_interupts(),
             Instruction::DI => self.disable_interupts(),
@@ -159,19 +174,128 @@ impl Cpu {
             Instruction::RES(b, r) => self.reset_bit(b, r),
             Instruction::SET(b, r) => self.set_bit(b, r),
             Instruction::PREFIX => (),
+            Instruction::ILLEGAL => {
+                log::error!("Illegal opcode {:#04x} at {:#06x}, the CPU has locked up", code.code, pc);
+                self.locked = true;
+            },
         };
 
-        (code.cycles.into(), false)
+        // Whatever the instruction spent not accessing memory
+        if cycles as u32 > self.cycles {
+            let ticks = cycles - self.cycles as u8;
+            profile::time(Subsystem::Hardware, || self.mmu.tick(ticks));
+            self.cycles = cycles as u32;
+        }
+
+        // HDMA pauses the CPU while it copies, the rest of the machine keeps running
+        loop {
+            let stall = self.mmu.take_dma_stall();
+            if stall == 0 {
+                break;
+            }
+            for _ in 0..stall / 4 {
+                self.internal_cycle();
+            }
+        }
+
+        // EI only lets interupts through once the instruction after it has run
+        if self.ei_delay > 0 {
+            self.ei_delay -= 1;
+            if self.ei_delay == 0 {
+                self.mmu.enable_interupts();
+            }
+        }
+
+        self.cycles
+    }
+
+    /// Reads the next instruction and whatever operand follows it. Returns the instruction along
+    /// with the clock cycles it will take
+    fn decode(&mut self) -> (&'static OpCode, Instruction, u8) {
+        let mut code = &UNPREFIXED[self.read_8(self.registers.pc) as usize];
+        if !std::mem::take(&mut self.halt_bug) {
+            self.increment(RegisterData::from_reg(Register::PC));
+        }
+
+        if matches!(code.instruction, Instruction::PREFIX) {
+            code = &PREFIXED[self.read_8(self.registers.pc) as usize];
+            self.increment(RegisterData::from_reg(Register::PC));
+        }
+
+        let mut instruction = code.instruction;
+        let cycles = self.instruction_cycles(&instruction, code.cycles);
+
+        //println!("Running: {}", instruction);
+        
+        match code.extra_data {
+            Take::None => (),
+            Take::Eight(operand) => instruction = instruction.insert(operand, self.get_8()),
+            Take::Sixteen(operand) => instruction = instruction.insert(operand, self.get_16()),
+        }
+
+        (code, instruction, cycles)
+    }
+
+    /// Clock cycles an instruction takes. The opcode table has the cycles of conditional
+    /// branches that are taken, the ones that aren't are quicker
+    fn instruction_cycles(&mut self, instruction: &Instruction, cycles: u8) -> u8 {
+        match instruction {
+            Instruction::JR(c, _) | Instruction::JP(c, _) if !self.condition_met(*c) => cycles - 4,
+            Instruction::CALL(c, _) | Instruction::RET(c) if !self.condition_met(*c) => cycles - 12,
+            _ => cycles,
+        }
+    }
+
+    fn condition_met(&mut self, c: Condition) -> bool {
+        match c {
+            Condition::Always => true,
+            Condition::Z => self.registers.get_z(),
+            Condition::NZ => !self.registers.get_z(),
+            Condition::C => self.registers.get_c(),
+            Condition::NC => !self.registers.get_c(),
+        }
+    }
+
+    /// An M-cycle that doesn't touch memory
+    fn internal_cycle(&mut self) {
+        profile::time(Subsystem::Hardware, || self.mmu.tick(4));
+        self.cycles += 4;
+    }
+
+    /// Reads memory during the next M-cycle, running the rest of the machine up to it first
+    pub fn read_8(&mut self, index: u16) -> u8 {
+        self.internal_cycle();
+        profile::time(Subsystem::MemoryRead, || self.mmu.read_8(index))
+    }
+
+    /// Writes memory during the next M-cycle, running the rest of the machine up to it first
+    pub fn write_8(&mut self, index: u16, val: u8) {
+        self.internal_cycle();
+        profile::time(Subsystem::MemoryWrite, || self.mmu.write_8(index, val));
+    }
+
+    /// Reads two bytes over two M-cycles, low byte first
+    pub fn read_16(&mut self, index: u16) -> u16 {
+        let ls = self.read_8(index);
+        let ms = self.read_8(index.wrapping_add(1));
+        le_combine(ls, ms)
+    }
+
+    /// Writes two bytes over two M-cycles, low byte first
+    pub fn write_16(&mut self, index: u16, val: u16) {
+        let (ms, ls) = val.split();
+        self.write_8(index, ls);
+        self.write_8(index.wrapping_add(1), ms);
     }
 
     pub fn get_8(&mut self) -> RegisterData {
-        let data = self.mmu.read_8(self.registers.pc);
+        let data = self.read_8(self.registers.pc);
         self.increment(RegisterData::from_reg(Register::PC));
         RegisterData::from_reg(Register::Const8(data))
     }
 
     pub fn get_16(&mut self) -> RegisterData {
-        let data = self.mmu.read_16(self.registers.pc);
+        let data = self.read_16(self.registers.pc);
         self.increment(RegisterData::from_reg(Register::PC));
         self.increment(RegisterData::from_reg(Register::PC));
         RegisterData::from_reg(Register::Const16(data))
@@ -179,7 +303,7 @@ impl Cpu {
 
     pub fn load_incrememnt(&mut self, _r1: RegisterData, r2: RegisterData) {
         if r2.register.is_16() {
-            let val = self.mmu.read_8(self.registers.get_hl());
+            let val = self.read_8(self.registers.get_hl());
             self.registers
                 .set_hl(self.registers.get_hl().wrapping_add(1));
 
@@ -187,7 +311,7 @@ impl Cpu {
         } else {
             let val = self.registers.a;
 
-            self.mmu.write_8(self.registers.get_hl(), val);
+            self.write_8(self.registers.get_hl(), val);
             self.registers
                 .set_hl(self.registers.get_hl().wrapping_add(1));
         }
@@ -195,7 +319,7 @@ impl Cpu {
 
     pub fn load_decrement(&mut self, _r1: RegisterData, r2: RegisterData) {
         if r2.register.is_16() {
-            let val = self.mmu.read_8(self.registers.get_hl());
+            let val = self.read_8(self.registers.get_hl());
             self.registers
                 .set_hl(self.registers.get_hl().wrapping_sub(1));
 
@@ -203,7 +327,7 @@ impl Cpu {
         } else {
             let val = self.registers.a;
 
-            self.mmu.write_8(self.registers.get_hl(), val);
+            self.write_8(self.registers.get_hl(), val);
             self.registers
                 .set_hl(self.registers.get_hl().wrapping_sub(1));
         }
@@ -214,14 +338,14 @@ impl Cpu {
             let val = self.registers.a;
 
             match r1.register {
-                Register::C => self.mmu.write_8(self.registers.c as u16 + 0xFF00, val),
-                Register::Const8(i) => self.mmu.write_8(i as u16 + 0xFF00, val),
+                Register::C => self.write_8(self.registers.c as u16 + 0xFF00, val),
+                Register::Const8(i) => self.write_8(i as u16 + 0xFF00, val),
                 _ => unreachable!(),
             }
         } else {
             let val = match r2.register {
-                Register::C => self.mmu.read_8((self.registers.c as u16) + 0xFF00),
-                Register::Const8(i) => self.mmu.read_8(0xFF00 + (i as u16)),
+                Register::C => self.read_8((self.registers.c as u16) + 0xFF00),
+                Register::Const8(i) => self.read_8(0xFF00 + (i as u16)),
                 _ => unreachable!(),
             };
 
@@ -235,14 +359,26 @@ impl Cpu {
             _ => unreachable!(),
         };
 
-        self.registers
-            .set_hl(self.mmu.read_16(self.registers.sp.wrapping_add_signed(val)));
+        let sp = self.registers.sp;
+        let offset = val as u16;
+        self.registers.set_hl(sp.wrapping_add_signed(val));
 
+        // The flags come from adding the offset to the low byte of SP, as if it were unsigned
         let reg = &mut self.registers;
         reg.unset_z();
         reg.unset_n();
-        reg.set_h();
-        reg.set_c();
+
+        if (sp & 0xF) + (offset & 0xF) > 0xF {
+            reg.set_h();
+        } else {
+            reg.unset_h();
+        }
+
+        if (sp & 0xFF) + (offset & 0xFF) > 0xFF {
+            reg.set_c();
+        } else {
+            reg.unset_c();
+        }
     }
 
     pub fn load(&mut self, r1: RegisterData, r2: RegisterData) {
@@ -259,7 +395,7 @@ impl Cpu {
             };
 
             if r2.pointer {
-                val = self.mmu.read_16(val);
+                val = self.read_16(val);
             }
 
             if r1.pointer {
@@ -274,10 +410,10 @@ impl Cpu {
                     _ => unreachable!("All u16 values are handled here not a {}", r1.register),
                 };
 
-                self.mmu.write_16(index, val);
+                self.write_16(index, val);
             } else {
                 if matches!(r1.register, Register::A) {
-                    self.registers.a = self.mmu.read_8(val);
+                    self.registers.a = self.read_8(val);
                     return;
                 }
 
@@ -288,7 +424,7 @@ impl Cpu {
                     Register::HL => self.registers.set_hl(val),
                     Register::SP => self.registers.sp = val,
                     Register::PC => self.registers.pc = val,
-                    Register::Const16(x) => self.mmu.write_16(x, val),
+                    Register::Const16(x) => self.write_16(x, val),
                     _ => unreachable!("All u16 values are handled here not a {}", r1.register),
                 };
             }
@@ -315,7 +451,7 @@ impl Cpu {
                         _ => unreachable!("Handled in outer match: {}", r2.register),
                     };
 
-                    self.mmu.read_8(index)
+                    self.read_8(index)
                 }
             };
 
@@ -340,7 +476,7 @@ impl Cpu {
                         _ => unreachable!("Handled in outer match: {}", r1.register),
                     };
 
-                    self.mmu.write_8(index, val);
+                    self.write_8(index, val);
                 }
             }
         }
@@ -356,21 +492,21 @@ impl Cpu {
             _ => unreachable!("No other registers pushed to stack"),
         };
 
+        // SP is decremented in an internal M-cycle before anything is written
+        self.internal_cycle();
+
         let (ms, ls) = val.split();
-        let sp = &mut self.registers.sp;
-        *sp -= 1;
-        self.mmu.write_8(*sp, ms);
-        *sp -= 1;
-        self.mmu.write_8(*sp, ls);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_8(self.registers.sp, ms);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_8(self.registers.sp, ls);
     }
 
     fn pop(&mut self, r1: RegisterData) {
-        let sp = &mut self.registers.sp;
-
-        let ls = self.mmu.read_8(*sp);
-        *sp += 1;
-        let ms = self.mmu.read_8(*sp);
-        *sp += 1;
+        let ls = self.read_8(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        let ms = self.read_8(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(1);
 
         let val = le_combine(ls, ms);
         match r1.register {
@@ -468,7 +604,7 @@ impl Cpu {
                 Register::E => self.registers.e,
                 Register::H => self.registers.h,
                 Register::L => self.registers.l,
-                Register::HL => self.mmu.read_8(self.registers.get_hl()),
+                Register::HL => self.read_8(self.registers.get_hl()),
                 Register::Const8(x) => x,
                 _ => unreachable!(),
             };
@@ -509,7 +645,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -552,7 +688,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -594,7 +730,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -637,7 +773,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -688,7 +824,7 @@ impl Cpu {
                 Register::E => self.registers.e,
                 Register::H => self.registers.h,
                 Register::L => self.registers.l,
-                Register::HL => self.mmu.read_8(self.registers.get_hl()),
+                Register::HL => self.read_8(self.registers.get_hl()),
                 _ => unreachable!(),
             };
 
@@ -718,7 +854,7 @@ impl Cpu {
                 Register::E => self.registers.e = result,
                 Register::H => self.registers.h = result,
                 Register::L => self.registers.l = result,
-                Register::HL => self.mmu.write_8(self.registers.get_hl(), result),
+                Register::HL => self.write_8(self.registers.get_hl(), result),
                 _ => unreachable!(),
             };
         }
@@ -743,7 +879,7 @@ impl Cpu {
                 Register::E => self.registers.e,
                 Register::H => self.registers.h,
                 Register::L => self.registers.l,
-                Register::HL => self.mmu.read_8(self.registers.get_hl()),
+                Register::HL => self.read_8(self.registers.get_hl()),
                 _ => unreachable!(),
             };
 
@@ -773,7 +909,7 @@ impl Cpu {
                 Register::E => self.registers.e = result,
                 Register::H => self.registers.h = result,
                 Register::L => self.registers.l = result,
-                Register::HL => self.mmu.write_8(self.registers.get_hl(), result),
+                Register::HL => self.write_8(self.registers.get_hl(), result),
                 _ => unreachable!(),
             };
         }
@@ -788,7 +924,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -818,7 +954,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -848,7 +984,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             Register::Const8(x) => x,
             _ => unreachable!(),
         };
@@ -946,7 +1082,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -979,7 +1115,7 @@ impl Cpu {
             Register::E => self.registers.e = new,
             Register::H => self.registers.h = new,
             Register::L => self.registers.l = new,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), new),
+            Register::HL => self.write_8(self.registers.get_hl(), new),
             _ => unreachable!(),
         }
     }
@@ -993,7 +1129,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1024,7 +1160,7 @@ impl Cpu {
             Register::E => self.registers.e = new,
             Register::H => self.registers.h = new,
             Register::L => self.registers.l = new,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), new),
+            Register::HL => self.write_8(self.registers.get_hl(), new),
             _ => unreachable!(),
         }
     }
@@ -1038,7 +1174,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1071,7 +1207,7 @@ impl Cpu {
             Register::E => self.registers.e = new,
             Register::H => self.registers.h = new,
             Register::L => self.registers.l = new,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), new),
+            Register::HL => self.write_8(self.registers.get_hl(), new),
             _ => unreachable!(),
         }
     }
@@ -1085,7 +1221,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1116,7 +1252,7 @@ impl Cpu {
             Register::E => self.registers.e = new,
             Register::H => self.registers.h = new,
             Register::L => self.registers.l = new,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), new),
+            Register::HL => self.write_8(self.registers.get_hl(), new),
             _ => unreachable!(),
         }
     }
@@ -1185,7 +1321,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1216,7 +1352,7 @@ impl Cpu {
             Register::E => self.registers.e = result,
             Register::H => self.registers.h = result,
             Register::L => self.registers.l = result,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), result),
+            Register::HL => self.write_8(self.registers.get_hl(), result),
             _ => unreachable!(),
         }
     }
@@ -1230,7 +1366,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1261,7 +1397,7 @@ impl Cpu {
             Register::E => self.registers.e = result,
             Register::H => self.registers.h = result,
             Register::L => self.registers.l = result,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), result),
+            Register::HL => self.write_8(self.registers.get_hl(), result),
             _ => unreachable!(),
         }
     }
@@ -1275,7 +1411,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1306,7 +1442,7 @@ impl Cpu {
             Register::E => self.registers.e = result,
             Register::H => self.registers.h = result,
             Register::L => self.registers.l = result,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), result),
+            Register::HL => self.write_8(self.registers.get_hl(), result),
             _ => unreachable!(),
         }
     }
@@ -1320,7 +1456,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1346,7 +1482,7 @@ impl Cpu {
             Register::E => self.registers.e = result,
             Register::H => self.registers.h = result,
             Register::L => self.registers.l = result,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), result),
+            Register::HL => self.write_8(self.registers.get_hl(), result),
             _ => unreachable!(),
         }
     }
@@ -1360,7 +1496,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1388,7 +1524,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1402,7 +1538,7 @@ impl Cpu {
             Register::E => self.registers.e = val,
             Register::H => self.registers.h = val,
             Register::L => self.registers.l = val,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), val),
+            Register::HL => self.write_8(self.registers.get_hl(), val),
             _ => unreachable!(),
         }
     }
@@ -1416,7 +1552,7 @@ impl Cpu {
             Register::E => self.registers.e,
             Register::H => self.registers.h,
             Register::L => self.registers.l,
-            Register::HL => self.mmu.read_8(self.registers.get_hl()),
+            Register::HL => self.read_8(self.registers.get_hl()),
             _ => unreachable!(),
         };
 
@@ -1430,41 +1566,82 @@ impl Cpu {
             Register::E => self.registers.e = val,
             Register::H => self.registers.h = val,
             Register::L => self.registers.l = val,
-            Register::HL => self.mmu.write_8(self.registers.get_hl(), val),
+            Register::HL => self.write_8(self.registers.get_hl(), val),
             _ => unreachable!(),
         }
     }
 
+    /// EI, counts this instruction and the one after it before IME is set
     pub fn enable_interupts(&mut self) {
-        self.mmu.enable_interupts();
+        self.ei_delay = 2;
     }
 
     pub fn disable_interupts(&mut self) {
+        self.ei_delay = 0;
         self.mmu.disble_interupts();
     }
 
-    pub fn service_interupts(&mut self, interupt: Interupt) -> u8 {
-        if interupt == Interupt::None || interupt == Interupt::Serial {
+    /// Jumps to the handler of the highest priority interupt if IME allows it, returning the
+    /// clock cycles that took. Nothing is dispatched while stopped, a button press has to wake
+    /// the CPU first, and nothing ever is once it has locked up
+    pub fn service_interupts(&mut self) -> u32 {
+        if self.stopped || self.locked || !self.mmu.interupt.master || !self.mmu.interupt.has_interupts() {
             return 0;
         }
+        self.mmu.disble_interupts();
+
+        // An interupt during the HALT bug returns to the HALT instead of the byte after it
+        let mut pc = self.registers.pc;
+        if std::mem::take(&mut self.halt_bug) {
+            pc = pc.wrapping_sub(1);
+        }
 
-        let goto = match interupt {
+        /*
+         *  Dispatch takes 5 M-cycles: 2 waiting, 2 pushing PC and 1 jumping. Which interupt is
+         *  serviced is only decided after the high byte of PC is pushed, so if that push lands
+         *  on IE and disables the interupt, nothing is acknowledged and PC ends up at 0x0000
+         */
+        self.cycles = 0;
+        self.internal_cycle();
+        self.internal_cycle();
+
+        let (ms, ls) = pc.split();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_8(self.registers.sp, ms);
+
+        let goto = match self.mmu.interupt.take_interupt() {
             Interupt::VBlank => 0x40,
             Interupt::LCD => 0x48,
             Interupt::Timer => 0x50,
+            Interupt::Serial => 0x58,
             Interupt::Joypad => 0x60,
-            _ => unreachable!(),
+            Interupt::None => 0x00,
         };
 
-        self.push(RegisterData::from_reg(Register::PC));
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write_8(self.registers.sp, ls);
         self.registers.pc = goto;
-        
-        self.disable_interupts();
-        20
+        self.internal_cycle();
+
+        self.cycles
     }
 
+    /// STOP, which resets DIV and either switches speed on a CGB that asked for it through KEY1
+    /// or stops the CPU and LCD until a button is pressed
+    pub fn stop(&mut self) {
+        if !self.mmu.stop() {
+            self.stopped = true;
+        }
+    }
+
+    /// HALT, which doesn't halt at all when IME is off and an interupt is already waiting.
+    /// Instead the CPU carries on and fails to move PC past the next opcode
     pub fn halt_cpu(&mut self) {
-        self.halted = true;
+        if !self.mmu.interupt.master && self.mmu.interupt.has_interupts() {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
     }
 
     pub fn jump(&mut self, c: Condition, r: RegisterData) {
@@ -1553,6 +1730,11 @@ impl Cpu {
     }
 
     pub fn ret(&mut self, c: Condition) {
+        // A conditional RET spends an M-cycle checking the flags before it pops anything
+        if !matches!(c, Condition::Always) {
+            self.internal_cycle();
+        }
+
         match c {
             Condition::Always => {
                 self.pop(RegisterData::from_reg(Register::PC));
@@ -1573,8 +1755,9 @@ impl Cpu {
         }
     }
 
+    /// RETI, which unlike EI sets IME straight away
     pub fn return_interrupt(&mut self) {
-        self.enable_interupts();
+        self.mmu.enable_interupts();
         self.pop(RegisterData::from_reg(Register::PC));
     }
 
@@ -1583,3 +1766,66 @@ impl Cpu {
         self.registers.pc = to;
     }
 }
+
+impl SaveState for Cpu {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.locked);
+        writer.write_u8(self.ei_delay);
+        writer.write_bool(self.halt_bug);
+        self.mmu.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::result::Result<(), StateError> {
+        self.registers.load_state(reader)?;
+        self.halted = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.locked = reader.read_bool()?;
+        self.ei_delay = reader.read_u8()?;
+        self.halt_bug = reader.read_bool()?;
+        self.mmu.load_state(reader)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::write_header_checksum;
+
+    /// Runs LD HL,SP+e8 with SP at `sp`, returning HL and whether H and C came out set
+    fn load_hl_sp(sp: u16, offset: u8) -> (u16, bool, bool) {
+        let mut rom = vec![0; 0x8000];
+        write_header_checksum(&mut rom);
+        let mut cpu = Cpu::from_rom(rom, None, RtcClock::default()).unwrap();
+        cpu.registers.sp = sp;
+        cpu.registers.set_z();
+        cpu.registers.set_n();
+        cpu.load_hl_sp(RegisterData::from_reg(Register::Const8(offset)));
+
+        assert!(!cpu.registers.get_z() && !cpu.registers.get_n());
+        (cpu.registers.get_hl(), cpu.registers.get_h(), cpu.registers.get_c())
+    }
+
+    #[test]
+    fn load_hl_sp_adds_the_offset_without_reading_memory() {
+        assert_eq!(load_hl_sp(0xC000, 0x12), (0xC012, false, false));
+        assert_eq!(load_hl_sp(0xC000, 0xFE), (0xBFFE, false, false));
+    }
+
+    #[test]
+    fn load_hl_sp_takes_flags_from_the_low_byte() {
+        // Positive offsets carry out of bit 3 and bit 7
+        assert_eq!(load_hl_sp(0xC00F, 0x01), (0xC010, true, false));
+        assert_eq!(load_hl_sp(0xC0F0, 0x10), (0xC100, false, true));
+        assert_eq!(load_hl_sp(0xC0FF, 0x01), (0xC100, true, true));
+
+        // Negative offsets add as the unsigned byte, so -1 carries from anything but 0
+        assert_eq!(load_hl_sp(0xC001, 0xFF), (0xC000, true, true));
+        assert_eq!(load_hl_sp(0xC000, 0xFF), (0xBFFF, false, false));
+        assert_eq!(load_hl_sp(0xC010, 0xF0), (0xC000, false, true));
+    }
+}
@@ -1,9 +1,4 @@
-use std::collections::hash_map::HashMap;
-use serde_json::{ Result, Value };
-use crate::cpu::Cpu;
-
-
-#[derive(Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 /// Represents a register and whether or not it is a pointer to data instead of an actual register.
 /// Includes an optional operation to be done to the register value before its use
 pub struct RegisterData {
@@ -11,7 +6,7 @@ pub struct RegisterData {
     pub pointer: bool,
 }
 
-#[derive(enum_display::EnumDisplay, PartialEq, Eq, Clone, Copy)]
+#[derive(enum_display::EnumDisplay, Debug, PartialEq, Eq, Clone, Copy)]
 /// Each register that might be used for an instruction and constant values should they be used
 /// instead
 pub enum Register {
@@ -62,48 +57,7 @@ impl RegisterData {
     }
 }
 
-impl std::convert::From<&str> for RegisterData {
-    fn from(value: &str) -> Self {
-
-        let mut pointer = false;
-        if value.contains("(") {
-            pointer = true;
-        }
-
-        let register = match value
-            .replace("(", "")
-            .replace(")", "")
-            .replace("-", "")
-            .replace("+", "") 
-            .as_str()
-        {
-            "A" => Register::A,
-            "F" => Register::F,
-            "B" => Register::B,
-            "C" => Register::C,
-            "D" => Register::D,
-            "E" => Register::E,
-            "H" => Register::H,
-            "L" => Register::L,
-            "AF" => Register::AF,
-            "BC" => Register::BC,
-            "DE" => Register::DE,
-            "HL" => Register::HL,
-            "SP" => Register::SP,
-            "PC" => Register::PC,
-            "d16" | "a16"=> Register::None,
-            "d8" | "r8" | "a8" => Register::None,
-            _ => panic!("Register couldn't be deciphered: {}", value),
-        };
-
-        Self {
-            register,
-            pointer,
-        }
-    }
-}
-
-#[derive(Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 /// Conditions that must be checked for certain instructions
 pub enum Condition {
     NZ,
@@ -113,19 +67,7 @@ pub enum Condition {
     Always,
 }
 
-impl std::convert::From<&str> for Condition {
-    fn from(value: &str) -> Self {
-        match value {
-            "NC" => Self::NC,
-            "C" => Self::C,
-            "NZ" => Self::NZ,
-            "Z" => Self::Z,
-            _ => panic!("Condition couldn't be deciphered: {}", value)
-        }
-    }
-}
-
-#[derive(Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 /// # Flags stores each flag as an Option<(bool, bool)>
 /// If the flag contains a none then it is not affected by a subsuquent call to an instruction
 ///
@@ -153,7 +95,7 @@ impl Flags {
 
 }
 
-#[derive(enum_display::EnumDisplay, Clone, Copy)]
+#[derive(enum_display::EnumDisplay, Debug, PartialEq, Clone, Copy)]
 /// Represents all possible instructions the gameboy can do and the data required for them to run
 pub enum Instruction {
     NOP, // Done
@@ -205,10 +147,20 @@ pub enum Instruction {
     RES(u8, RegisterData), // Done
     SET(u8, RegisterData), // Done
     PREFIX,
+    /// One of the opcodes the Game Boy doesn't have
+    ILLEGAL,
 }
 
 impl Instruction {
 
+    /// Puts the bytes read after the opcode into the operand they stand in for
+    pub fn insert(&mut self, operand: Operand, new: RegisterData) -> Instruction {
+        match operand {
+            Operand::First => self.insert_r1(new),
+            Operand::Second => self.insert_r2(new),
+        }
+    }
+
     pub fn insert_r2(&mut self, new: RegisterData) -> Instruction {
         match self {
             Self::LD(r1, _r2) => Self::LD(*r1, new),
@@ -224,6 +176,8 @@ impl Instruction {
             Self::JR(c, _r2) => Self::JR(*c, new),
             Self::JP(c, _r2) => Self::JP(*c, new),
             Self::CALL(c, _r2) => Self::CALL(*c, new),
+            // STOP is followed by a byte that is skipped over and never used
+            Self::STOP => Self::STOP,
             _ => unreachable!("{}", self)
         }
     }
@@ -239,13 +193,21 @@ impl Instruction {
     
 }
 
-#[derive(enum_display::EnumDisplay)]
+#[derive(enum_display::EnumDisplay, Debug, PartialEq, Clone, Copy)]
+/// The bytes that follow an opcode, and which operand they are read into
 pub enum Take {
     None,
-    Eight,
-    Sixteen,
+    Eight(Operand),
+    Sixteen(Operand),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+    First,
+    Second,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
 /// Represents and opcode and what must be done for it to run correctly and in time
 pub struct OpCode {
     pub code: u8,
@@ -255,208 +217,206 @@ pub struct OpCode {
     pub extra_data: Take,
 }
 
-/// Holds two HashMaps that take in opcode numeric values and return the OpCode
-pub struct Dissasembler {
-    pub unprefixed: HashMap<u8, OpCode>,
-    pub prefixed: HashMap<u8, OpCode>,
-}
+// The UNPREFIXED and PREFIXED decode tables, generated from opcodes.json by build.rs
+include!(concat!(env!("OUT_DIR"), "/opcodes.rs"));
 
-impl Dissasembler {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
 
-    /// Creates a new Dissasembler object that contains HashMaps that take an opcode value and
-    /// retrn its associated OpCode
-    pub fn new() -> Result<Self> {
-        let v: Value = serde_json::from_str(include_str!("opcodes.json"))?;
+    /// Opcodes the Game Boy doesn't have
+    const ILLEGAL: [u8; 11] = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
 
-        let mut unprefixed = HashMap::new();
-        let mut prefixed = HashMap::new();
-        
-        let mut i = 0;
-        loop {
-            let object = v.get(i);
+    fn register(value: &str) -> RegisterData {
+        let register = match value.replace(['(', ')', '-', '+'], "").as_str() {
+            "A" => Register::A,
+            "F" => Register::F,
+            "B" => Register::B,
+            "C" => Register::C,
+            "D" => Register::D,
+            "E" => Register::E,
+            "H" => Register::H,
+            "L" => Register::L,
+            "AF" => Register::AF,
+            "BC" => Register::BC,
+            "DE" => Register::DE,
+            "HL" => Register::HL,
+            "SP" => Register::SP,
+            "PC" => Register::PC,
+            "d16" | "a16" | "d8" | "r8" | "a8" => Register::None,
+            _ => panic!("Register couldn't be deciphered: {}", value),
+        };
 
-            if object.is_none() {
-                break;
-            }
+        RegisterData { register, pointer: value.contains('(') }
+    }
 
-            let object = object.unwrap();
+    fn condition(value: &str) -> Condition {
+        match value {
+            "NC" => Condition::NC,
+            "C" => Condition::C,
+            "NZ" => Condition::NZ,
+            "Z" => Condition::Z,
+            "d16" | "a16" | "r8" => Condition::Always,
+            _ => panic!("Condition couldn't be deciphered: {}", value),
+        }
+    }
 
-            // Creation of all flags for opcode
-            let flags_array = object["flagsZNHC"].as_array().unwrap();
-            let mut flags = Flags::all_none();
+    fn flag(value: &str) -> Option<(bool, bool)> {
+        match value {
+            "0" => Some((false, false)),
+            "1" => Some((false, true)),
+            _ => Some((true, false)),
+        }
+    }
 
-            for (i, flag) in flags_array.iter().enumerate() {
-                match i {
-                    0 => flags.z = Self::create_flag(flag.as_str().unwrap()),
-                    1 => flags.n = Self::create_flag(flag.as_str().unwrap()),
-                    2 => flags.hc = Self::create_flag(flag.as_str().unwrap()),
-                    3 => flags.c = Self::create_flag(flag.as_str().unwrap()),
-                    _ => panic!("Flag Creation Panic")
-                }
-            }
+    /// Decodes a json object the way `Dissasembler::new` did before the tables were generated,
+    /// with the operand the bytes after it go into picked the way `Cpu::tick` used to
+    fn decode(object: &Value) -> OpCode {
+        let code = u8::from_str_radix(&object["opcode"].as_str().unwrap().replace("0x", ""), 16).unwrap();
+        let operand = |i: usize| object["operands"][i].as_str().unwrap();
+        let flags: Vec<&str> = object["flagsZNHC"].as_array().unwrap().iter().map(|f| f.as_str().unwrap()).collect();
+
+        let instruction = match object["mnemonic"].as_str().unwrap() {
+            "NOP" => Instruction::NOP,
+            "STOP" => Instruction::STOP,
+            "HALT" => Instruction::HALT,
+            "EI" => Instruction::EI,
+            "DI" => Instruction::DI,
+            "RLCA" => Instruction::RLCA,
+            "RLA" => Instruction::RLA,
+            "RRCA" => Instruction::RRCA,
+            "RRA" => Instruction::RRA,
+            "DAA" => Instruction::DAA,
+            "SCF" => Instruction::SCF,
+            "CPL" => Instruction::CPL,
+            "CCF" => Instruction::CCF,
+            "RETI" => Instruction::RETI,
+            "PREFIX" => Instruction::PREFIX,
+            "INC" => Instruction::INC(register(operand(0))),
+            "DEC" => Instruction::DEC(register(operand(0))),
+            "LD" if operand(1) == "SP+r8" => Instruction::LDASP(RegisterData::empty()),
+            "LD" => Instruction::LD(register(operand(0)), register(operand(1))),
+            "LDINC" => Instruction::LDINC(register(operand(0)), register(operand(1))),
+            "LDDEC" => Instruction::LDDEC(register(operand(0)), register(operand(1))),
+            "LDH" => Instruction::LDH(register(operand(0)), register(operand(1))),
+            "JR" => Instruction::JR(condition(operand(0)), RegisterData::from_reg(Register::Const8(0))),
+            "ADD" => Instruction::ADD(register(operand(0)), register(operand(1))),
+            "SUB" => Instruction::SUB(register(operand(0))),
+            "ADC" => Instruction::ADC(register(operand(1))),
+            "SBC" => Instruction::SBC(register(operand(1))),
+            "AND" => Instruction::AND(register(operand(0))),
+            "XOR" => Instruction::XOR(register(operand(0))),
+            "OR" => Instruction::OR(register(operand(0))),
+            "CP" => Instruction::CP(register(operand(0))),
+            "RET" if object["operands"].get(0).is_none() => Instruction::RET(Condition::Always),
+            "RET" => Instruction::RET(condition(operand(0))),
+            "CALL" => Instruction::CALL(condition(operand(0)), RegisterData::empty()),
+            "POP" => Instruction::POP(register(operand(0))),
+            "PUSH" => Instruction::PUSH(register(operand(0))),
+            "JP" if code == 0xE9 => Instruction::JP(Condition::Always, RegisterData::from_reg(Register::HL)),
+            "JP" => Instruction::JP(condition(operand(0)), RegisterData::empty()),
+            "RST" => Instruction::RST(u16::from_str_radix(&operand(0).replace('H', ""), 16).unwrap()),
+            "RLC" => Instruction::RLC(register(operand(0))),
+            "RRC" => Instruction::RRC(register(operand(0))),
+            "RL" => Instruction::RL(register(operand(0))),
+            "RR" => Instruction::RR(register(operand(0))),
+            "SLA" => Instruction::SLA(register(operand(0))),
+            "SRA" => Instruction::SRA(register(operand(0))),
+            "SWAP" => Instruction::SWAP(register(operand(0))),
+            "SRL" => Instruction::SRL(register(operand(0))),
+            "BIT" => Instruction::BIT(operand(0).parse().unwrap(), register(operand(1))),
+            "RES" => Instruction::RES(operand(0).parse().unwrap(), register(operand(1))),
+            "SET" => Instruction::SET(operand(0).parse().unwrap(), register(operand(1))),
+            mnemonic => panic!("Instruction doesn't exist: {}", mnemonic),
+        };
 
-            // Cycles of opcode set here 
-            let cycles = object["cycles"].as_u64().unwrap() as u8;
-            
-            // Define if extra data is needed
-            let bytes = object["bytes"].as_u64().unwrap();
-            let extra_data = match bytes {
-                1 => Take::None,
-                2 if object.get("prefix").is_none() => Take::Eight,
-                2 => Take::None,
-                3 => Take::Sixteen,
-                _ => unreachable!()
-            };
-
-            // Instruction created here
-            let instruction = match object["mnemonic"].as_str().unwrap() {
-                "NOP" => Instruction::NOP,
-                "STOP" => Instruction::STOP,
-                "HALT" => Instruction::HALT,
-                "EI" => Instruction::EI,
-                "DI" => Instruction::DI,
-                "RLCA" => Instruction::RLCA,
-                "RLA" => Instruction::RLA,
-                "RRCA" => Instruction::RRCA,
-                "RRA" => Instruction::RRA,
-                "DAA" => Instruction::DAA,
-                "SCF" => Instruction::SCF,
-                "CPL" => Instruction::CPL,
-                "CCF" => Instruction::CCF,
-                "INC" => Instruction::INC(object["operands"][0].as_str().unwrap().into()),
-                "DEC" => Instruction::DEC(object["operands"][0].as_str().unwrap().into()),
-                "LD" => {
-                    if object["operands"][1].as_str().unwrap() == "SP+r8" {
-                        Instruction::LDASP(RegisterData::empty())
-                    } else {
-                        Instruction::LD(
-                            object["operands"][0].as_str().unwrap().into(),
-                            object["operands"][1].as_str().unwrap().into(),
-                        )
-                    }
-                }
-                "LDINC" => Instruction::LDINC(
-                    object["operands"][0].as_str().unwrap().into(),
-                    object["operands"][1].as_str().unwrap().into(),
-                ),
-                "LDDEC" => Instruction::LDDEC(
-                    object["operands"][0].as_str().unwrap().into(),
-                    object["operands"][1].as_str().unwrap().into(),
-                ),
-                "JR" => {
-                    if Self::is_const(object["operands"][0].as_str().unwrap()).0 {
-                        Instruction::JR(Condition::Always, RegisterData::from_reg(Register::Const8(0)))
-                    } else {
-                        Instruction::JR(object["operands"][0].as_str().unwrap().into(), RegisterData::from_reg(Register::Const8(0)))
-                    }
-                }
-                "ADD" => Instruction::ADD(
-                    object["operands"][0].as_str().unwrap().into(),
-                    object["operands"][1].as_str().unwrap().into(),
-                ),
-                "SUB" => Instruction::SUB(object["operands"][0].as_str().unwrap().into()),
-                "ADC" => Instruction::ADC(object["operands"][1].as_str().unwrap().into()),
-                "SBC" => Instruction::SBC(object["operands"][1].as_str().unwrap().into()),
-                "AND" => Instruction::AND(object["operands"][0].as_str().unwrap().into()),
-                "XOR" => Instruction::XOR(object["operands"][0].as_str().unwrap().into()),
-                "OR" => Instruction::OR(object["operands"][0].as_str().unwrap().into()),
-                "CP" => Instruction::CP(object["operands"][0].as_str().unwrap().into()),
-                "RET" => {
-                    if object["operands"].get(0).is_none() {
-                        Instruction::RET(Condition::Always)
-                    } else {
-                        Instruction::RET(object["operands"][0].as_str().unwrap().into())
-                    }
-                },
-                "CALL" => {
-                    if Self::is_const(object["operands"][0].as_str().unwrap()).0 {
-                        Instruction::CALL(Condition::Always, RegisterData::empty())
-                    } else {
-                        Instruction::CALL(object["operands"][0].as_str().unwrap().into(), RegisterData::empty())
-                    } 
-                },
-                "POP" => Instruction::POP(object["operands"][0].as_str().unwrap().into()),
-                "PUSH" => Instruction::PUSH(object["operands"][0].as_str().unwrap().into()),
-                "JP" => {
-                    if object["opcode"].as_str().unwrap() == "0xe9" {
-                        Instruction::JP(Condition::Always, RegisterData::from_reg(Register::HL))
-                    } else if Self::is_const(object["operands"][0].as_str().unwrap()).0 {
-                        Instruction::JP(Condition::Always, RegisterData::empty())
-                    } else {
-                        Instruction::JP(object["operands"][0].as_str().unwrap().into(), RegisterData::empty())
-                    }
-                },
-                "RETI" => Instruction::RETI,
-                "RST" => Instruction::RST(u16::from_str_radix(&object["operands"][0].as_str().unwrap().replace("H", ""), 10).unwrap()),
-                "LDH" => Instruction::LDH(
-                    object["operands"][0].as_str().unwrap().into(),
-                    object["operands"][1].as_str().unwrap().into(),
-                ),
-                "RLC" => Instruction::RLC(object["operands"][0].as_str().unwrap().into()),
-                "RRC" => Instruction::RRC(object["operands"][0].as_str().unwrap().into()),
-                "RL" => Instruction::RL(object["operands"][0].as_str().unwrap().into()),
-                "RR" => Instruction::RR(object["operands"][0].as_str().unwrap().into()),
-                "SLA" => Instruction::SLA(object["operands"][0].as_str().unwrap().into()),
-                "SRA" => Instruction::SRA(object["operands"][0].as_str().unwrap().into()),
-                "SWAP" => Instruction::SWAP(object["operands"][0].as_str().unwrap().into()),
-                "SRL" => Instruction::SRL(object["operands"][0].as_str().unwrap().into()),
-                "BIT" => Instruction::BIT(
-                    object["operands"][0].as_str().unwrap().parse().unwrap(),
-                    object["operands"][1].as_str().unwrap().into()
-                ),
-                "RES" => Instruction::RES(
-                    object["operands"][0].as_str().unwrap().parse().unwrap(),
-                    object["operands"][1].as_str().unwrap().into()
-                ),
-                "SET" => Instruction::SET(
-                    object["operands"][0].as_str().unwrap().parse().unwrap(),
-                    object["operands"][1].as_str().unwrap().into()
-                ),
-                "PREFIX" => Instruction::PREFIX,
-                _ => panic!("Instruction doesn't exist: {}", object["mnemonic"].as_str().unwrap()),
-            };
-            
-            let code = u8::from_str_radix(&object["opcode"].as_str().unwrap().replace("0x", ""), 16).unwrap();
-            let opcode = OpCode {
-                code,
-                instruction,
-                flags,
-                cycles,
-                extra_data,
-            };
-            if object.get("prefix").is_none() {
-                unprefixed.insert(code, opcode);
-            } else {
-                prefixed.insert(code, opcode);
-            }
+        let extra_data = match object["bytes"].as_u64().unwrap() {
+            1 => Take::None,
+            2 if object.get("prefix").is_some() => Take::None,
+            2 if code == 0xE0 || code == 0xF8 => Take::Eight(Operand::First),
+            2 => Take::Eight(Operand::Second),
+            3 if code == 0x08 || code == 0xEA => Take::Sixteen(Operand::First),
+            3 => Take::Sixteen(Operand::Second),
+            _ => unreachable!(),
+        };
 
-            i += 1;
+        OpCode {
+            code,
+            instruction,
+            flags: Flags { z: flag(flags[0]), n: flag(flags[1]), hc: flag(flags[2]), c: flag(flags[3]) },
+            cycles: object["cycles"].as_u64().unwrap() as u8,
+            extra_data,
         }
-
-        Ok(Self {
-            unprefixed,
-            prefixed,
-        })
     }
 
-    fn create_flag(str: &str) -> Option<(bool, bool)> {
-        match  str {
-            "0" => Some((false, false)),
-            "1" => Some((false, true)),
-            _  => Some((true, false)),
+    #[test]
+    fn generated_tables_match_the_json_decoder() {
+        let json: Value = serde_json::from_str(include_str!("opcodes.json")).unwrap();
+        let mut unprefixed = vec![None; 256];
+        let mut prefixed = vec![None; 256];
+        for object in json.as_array().unwrap() {
+            let opcode = decode(object);
+            match object.get("prefix") {
+                None => unprefixed[opcode.code as usize] = Some(opcode),
+                Some(_) => prefixed[opcode.code as usize] = Some(opcode),
+            }
         }
+
+        for code in 0..256 {
+            assert_eq!(Some(PREFIXED[code]), prefixed[code], "CB {:#04x}", code);
+            match unprefixed[code] {
+                Some(opcode) => assert_eq!(UNPREFIXED[code], opcode, "{:#04x}", code),
+                None => assert!(ILLEGAL.contains(&(code as u8)), "{:#04x} is missing from the json", code),
+            }
+        }
+    }
+
+    #[test]
+    fn constants_after_the_opcode_go_into_the_right_operand() {
+        let hl = RegisterData::from_reg(Register::HL);
+        let a = RegisterData::from_reg(Register::A);
+        let sp = RegisterData::from_reg(Register::SP);
+        let pointer = RegisterData { register: Register::None, pointer: true };
+
+        // LD (a16),SP
+        assert_eq!(UNPREFIXED[0x08].instruction, Instruction::LD(pointer, sp));
+        assert_eq!(UNPREFIXED[0x08].extra_data, Take::Sixteen(Operand::First));
+        // LDH (a8),A
+        assert_eq!(UNPREFIXED[0xE0].instruction, Instruction::LDH(pointer, a));
+        assert_eq!(UNPREFIXED[0xE0].extra_data, Take::Eight(Operand::First));
+        // LD (a16),A
+        assert_eq!(UNPREFIXED[0xEA].instruction, Instruction::LD(pointer, a));
+        assert_eq!(UNPREFIXED[0xEA].extra_data, Take::Sixteen(Operand::First));
+        // LD HL,SP+r8
+        assert_eq!(UNPREFIXED[0xF8].instruction, Instruction::LDASP(RegisterData::empty()));
+        assert_eq!(UNPREFIXED[0xF8].extra_data, Take::Eight(Operand::First));
+        // JP (HL) jumps to HL itself rather than what it points at
+        assert_eq!(UNPREFIXED[0xE9].instruction, Instruction::JP(Condition::Always, hl));
+        assert_eq!(UNPREFIXED[0xE9].extra_data, Take::None);
+
+        // Everything else that takes a constant reads it into the second operand
+        assert_eq!(UNPREFIXED[0xF0].extra_data, Take::Eight(Operand::Second));
+        assert_eq!(UNPREFIXED[0xFA].extra_data, Take::Sixteen(Operand::Second));
+        assert_eq!(UNPREFIXED[0x36].extra_data, Take::Eight(Operand::Second));
+        assert_eq!(UNPREFIXED[0x18].extra_data, Take::Eight(Operand::Second));
+        // The byte after STOP is skipped, and the byte after 0xCB is the prefixed opcode
+        assert_eq!(UNPREFIXED[0x10].extra_data, Take::Eight(Operand::Second));
+        assert!(PREFIXED.iter().all(|opcode| opcode.extra_data == Take::None));
     }
 
-    fn is_const(str: &str) -> (bool, Register) {
-        match str
-            .replace("(", "")
-            .replace(")", "")
-            .as_str()
-        {
-            "d16" | "a16"=> (true, Register::Const16(0)),
-            "d8" | "r8" | "a8" => (true, Register::Const8(0)),
-            _ => (false, Register::None),
+    #[test]
+    fn only_the_missing_opcodes_are_illegal() {
+        for (code, opcode) in UNPREFIXED.iter().enumerate() {
+            assert_eq!(opcode.code as usize, code);
+            let illegal = matches!(opcode.instruction, Instruction::ILLEGAL);
+            assert_eq!(illegal, ILLEGAL.contains(&(code as u8)), "{:#04x}", code);
+            if illegal {
+                assert_eq!(opcode.extra_data, Take::None);
+                assert_eq!(opcode.cycles, 4);
+            }
         }
+        assert!(!PREFIXED.iter().any(|opcode| matches!(opcode.instruction, Instruction::ILLEGAL)));
     }
 
 }
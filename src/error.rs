@@ -0,0 +1,78 @@
+use crate::cartridge::HeaderError;
+use crate::state::StateError;
+
+#[derive(Debug)]
+/// Errors that can happen while loading a ROM and setting up the emulator
+pub enum Error {
+    /// The cartridge uses a memory bank controller that isn't emulated
+    UnsupportedMbc(u8),
+    /// The ROM is larger or smaller than its header or memory controller allows
+    BadSize { expected: usize, actual: usize },
+    /// One of the checksums in the header doesn't match the ROM
+    ChecksumMismatch { expected: u16, actual: u16 },
+    /// Any other problem with the cartridge header
+    Header(HeaderError),
+    /// A save state couldn't be restored
+    State(StateError),
+    /// The host's audio device couldn't be opened
+    Audio(String),
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedMbc(code) => write!(f, "unsupported cartridge type {:#04x}", code),
+            Self::BadSize { expected, actual } => {
+                write!(f, "ROM is {} bytes but {} bytes were expected", actual, expected)
+            },
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum mismatch, expected {:#06x} but got {:#06x}", expected, actual)
+            },
+            Self::Header(e) => write!(f, "invalid cartridge header: {}", e),
+            Self::State(e) => write!(f, "{}", e),
+            Self::Audio(e) => write!(f, "could not open audio device: {}", e),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Header(e) => Some(e),
+            Self::State(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<StateError> for Error {
+    fn from(value: StateError) -> Self {
+        Self::State(value)
+    }
+}
+
+impl From<HeaderError> for Error {
+    fn from(value: HeaderError) -> Self {
+        match value {
+            HeaderError::UnsupportedCartridgeType(code) => Self::UnsupportedMbc(code),
+            HeaderError::TooSmall(actual) => Self::BadSize { expected: 0x150, actual },
+            HeaderError::SizeMismatch { declared, actual } => Self::BadSize { expected: declared, actual },
+            HeaderError::HeaderChecksum { expected, actual } => {
+                Self::ChecksumMismatch { expected: expected as u16, actual: actual as u16 }
+            },
+            HeaderError::GlobalChecksum { expected, actual } => Self::ChecksumMismatch { expected, actual },
+            e => Self::Header(e),
+        }
+    }
+}
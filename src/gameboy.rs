@@ -0,0 +1,845 @@
+use crate::audio::AudioSink;
+use crate::cpu::Cpu;
+use crate::error::Result;
+use crate::cartridge::{ CartridgeHeader, RtcClock };
+use crate::gpu::{ ColorPixel, ColorCorrection, Palette, PpuAccuracy };
+use crate::joypad::Button;
+use crate::mmu::SerialCallback;
+use crate::state::{ SaveState, StateError, StateReader, StateWriter };
+
+use std::path::Path;
+
+/// Clock cycles the emulator runs for every frame
+pub const CYCLES_PER_FRAME: u64 = 69905;
+
+/// A whole Game Boy that can be driven without any windowing or audio crates. Frontends feed it
+/// button presses, call `run_frame` and show whatever `framebuffer` returns
+pub struct GameBoy {
+    cpu: Cpu,
+    /// Cycles the last frame ran past `CYCLES_PER_FRAME`, taken off the next one
+    overshoot: u64,
+}
+
+impl GameBoy {
+
+    /// Creates a Game Boy with the ROM inserted. Battery backed RAM is loaded from the .sav file
+    /// next to `rom_path` if one is given
+    pub fn new(rom: Vec<u8>, rom_path: Option<&Path>) -> Result<Self> {
+        Self::with_rtc_clock(rom, rom_path, RtcClock::default())
+    }
+
+    /// Like `new`, with the cartridge's real time clock counting seconds with `rtc_clock`. On
+    /// wall time the clock catches up with however long ago the .sav was written
+    pub fn with_rtc_clock(rom: Vec<u8>, rom_path: Option<&Path>, rtc_clock: RtcClock) -> Result<Self> {
+        Ok(Self {
+            cpu: Cpu::from_rom(rom, rom_path, rtc_clock)?,
+            overshoot: 0,
+        })
+    }
+
+    /// Runs a single instruction, along with any interrupt it triggers, and returns the clock
+    /// cycles it took. Cycles are always counted at normal speed, so CGB double speed gets twice
+    /// as many instructions into a frame
+    pub fn step_instruction(&mut self) -> u64 {
+        let tick_cycles = self.cpu.tick();
+        let dispatch_cycles = self.cpu.service_interupts();
+
+        let cycles = tick_cycles as u64 + dispatch_cycles as u64;
+        if self.cpu.mmu.double_speed { cycles / 2 } else { cycles }
+    }
+
+    /// Runs instructions until a frame's worth of clock cycles has passed
+    pub fn run_frame(&mut self) {
+        let mut cycles = self.overshoot;
+
+        while cycles < CYCLES_PER_FRAME {
+            cycles += self.step_instruction();
+        }
+
+        self.overshoot = cycles - CYCLES_PER_FRAME;
+    }
+
+    /// Whether the game executed STOP and is waiting for a button press. The screen doesn't
+    /// change and no audio is made until then
+    pub fn is_stopped(&self) -> bool {
+        self.cpu.stopped
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.cpu.mmu.joypad.set_button(button, pressed);
+    }
+
+    /// Switches what the cartridge's real time clock counts seconds with from now on
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.cpu.mmu.cartridge.set_rtc_clock(clock);
+    }
+
+    /// Sets the function that gets each byte the game sends over the serial port
+    pub fn set_serial_callback(&mut self, callback: SerialCallback) {
+        self.cpu.mmu.set_serial_callback(callback);
+    }
+
+    /// Sends the audio to `sink` from now on, `None` stops mixing audio altogether
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        self.cpu.mmu.apu.set_audio_sink(sink);
+    }
+
+    /// Makes `scale` times as many samples for every second of emulated time, see
+    /// `pacing::audio_rate_scale`
+    pub fn set_audio_rate_scale(&mut self, scale: f64) {
+        self.cpu.mmu.apu.set_audio_rate_scale(scale);
+    }
+
+    /// Makes the audio last `1 / speed` as long without changing its pitch, so it keeps up with
+    /// a sped up or slowed down emulator
+    pub fn set_audio_time_stretch(&mut self, speed: f64) {
+        self.cpu.mmu.apu.set_audio_time_stretch(speed);
+    }
+
+    /// The last drawn frame, `SCREEN_WIDTH` pixels per row
+    pub fn framebuffer(&self) -> &[ColorPixel] {
+        self.cpu.mmu.gpu.framebuffer()
+    }
+
+    /// Picks between the fast scanline renderer and the dot accurate pixel FIFO
+    pub fn set_ppu_accuracy(&mut self, accuracy: PpuAccuracy) {
+        self.cpu.mmu.gpu.set_accuracy(accuracy);
+    }
+
+    pub fn ppu_accuracy(&self) -> PpuAccuracy {
+        self.cpu.mmu.gpu.accuracy()
+    }
+
+    /// Changes the colors DMG games are drawn with
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.cpu.mmu.gpu.set_palette(palette);
+    }
+
+    pub fn palette(&self) -> &Palette {
+        self.cpu.mmu.gpu.palette()
+    }
+
+    /// Changes how CGB games' colors are shown
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.cpu.mmu.gpu.set_color_correction(correction);
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.cpu.mmu.gpu.color_correction()
+    }
+
+    /// Header of the inserted cartridge, if it could be read
+    pub fn header(&self) -> Option<&CartridgeHeader> {
+        self.cpu.mmu.cartridge.header()
+    }
+
+    /// Serializes the whole machine into a save state
+    pub fn save_state(&self) -> Vec<u8> {
+        let (global_checksum, header_checksum) = self.cpu.rom_checksums();
+        let mut writer = StateWriter::new(global_checksum, header_checksum);
+
+        // Where the next frame ends, so frames after loading line up with the ones before saving
+        writer.write_u64(self.overshoot);
+        self.cpu.save_state(&mut writer);
+
+        writer.into_bytes()
+    }
+
+    /// Restores the machine from a save state made with the same ROM. If the state turns out to
+    /// be broken the machine is left as it was
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<()> {
+        let (global_checksum, header_checksum) = self.cpu.rom_checksums();
+        let mut reader = StateReader::new(bytes, global_checksum, header_checksum)?;
+        let backup = self.save_state();
+
+        if let Err(e) = self.read_state(&mut reader) {
+            let mut reader = StateReader::new(&backup, global_checksum, header_checksum)?;
+            self.read_state(&mut reader)?;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> std::result::Result<(), StateError> {
+        self.overshoot = reader.read_u64()?;
+        self.cpu.load_state(reader)
+    }
+
+    /// Writes the cartridge RAM to its save file if it changed since the last write
+    pub fn flush_save(&mut self) -> std::io::Result<()> {
+        self.cpu.mmu.cartridge.flush_save()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::write_header_checksum;
+    use std::collections::HashMap;
+    use std::sync::{ Arc, Mutex };
+
+    /// Where a byte waiting on a label goes, and what it needs from the label's address
+    enum Fixup {
+        /// Both bytes of the address, little endian
+        Address(String),
+        /// The low byte of the address
+        Low(String),
+        /// The offset JR needs to get there from the byte after this one
+        Relative(String),
+    }
+
+    /// A ROM being put together from 0x150 on. Addresses are given as labels, which only get
+    /// filled in once `finish` knows where every label ended up
+    struct Assembler {
+        rom: Vec<u8>,
+        at: usize,
+        labels: HashMap<String, usize>,
+        fixups: Vec<(usize, Fixup)>,
+    }
+
+    impl Assembler {
+
+        fn new() -> Self {
+            Self { rom: vec![0; 0x8000], at: 0x150, labels: HashMap::new(), fixups: Vec::new() }
+        }
+
+        fn emit(&mut self, bytes: &[u8]) {
+            self.rom[self.at..self.at + bytes.len()].copy_from_slice(bytes);
+            self.at += bytes.len();
+        }
+
+        fn nops(&mut self, count: usize) {
+            self.emit(&vec![0x00; count]);
+        }
+
+        /// Names the address the next byte goes to
+        fn label(&mut self, name: &str) {
+            let previous = self.labels.insert(name.to_string(), self.at);
+            assert!(previous.is_none(), "{} is defined twice", name);
+        }
+
+        fn address(&mut self, label: &str) {
+            self.fixups.push((self.at, Fixup::Address(label.to_string())));
+            self.at += 2;
+        }
+
+        fn low(&mut self, label: &str) {
+            self.fixups.push((self.at, Fixup::Low(label.to_string())));
+            self.at += 1;
+        }
+
+        fn relative(&mut self, label: &str) {
+            self.fixups.push((self.at, Fixup::Relative(label.to_string())));
+            self.at += 1;
+        }
+
+        /// Emits what `emit` does, first moving it to the start of the next page if `label` would
+        /// end up with a low byte of 0xF0 or more. Cases that load TIMA with that low byte would
+        /// otherwise have it overflow while they run
+        fn keep_low_byte_under_f0(&mut self, label: &str, emit: impl Fn(&mut Self)) {
+            let start = self.at;
+            let fixups = self.fixups.len();
+            emit(self);
+
+            let low = self.labels[label] & 0xFF;
+            if low >= 0xF0 {
+                self.at = start;
+                self.fixups.truncate(fixups);
+                self.labels.retain(|_, address| *address < start);
+                self.nops(0x100 - low);
+                emit(self);
+            }
+        }
+
+        /// Fills in every label and writes the header checksum
+        fn finish(mut self) -> Vec<u8> {
+            for (at, fixup) in &self.fixups {
+                let label = match fixup {
+                    Fixup::Address(label) | Fixup::Low(label) | Fixup::Relative(label) => label,
+                };
+                let address = *self.labels.get(label).unwrap_or_else(|| panic!("{} is never defined", label));
+
+                match fixup {
+                    Fixup::Address(_) => self.rom[*at..at + 2].copy_from_slice(&(address as u16).to_le_bytes()),
+                    Fixup::Low(_) => self.rom[*at] = address as u8,
+                    Fixup::Relative(_) => {
+                        let offset = address as isize - (at + 1) as isize;
+                        let offset = i8::try_from(offset).unwrap_or_else(|_| panic!("{} is out of JR's reach", label));
+                        self.rom[*at] = offset as u8;
+                    },
+                }
+            }
+
+            write_header_checksum(&mut self.rom);
+            self.rom
+        }
+
+    }
+
+    /// LD (C100+slot),A, where each case leaves what it read
+    fn store_result(asm: &mut Assembler, slot: usize) {
+        asm.emit(&[0xEA, slot as u8, 0xC1]);
+    }
+
+    /// LD (C200+slot),A, where the reference for each case leaves what it read
+    fn store_reference(asm: &mut Assembler, slot: usize) {
+        asm.emit(&[0xEA, slot as u8, 0xC2]);
+    }
+
+    /// Resets DIV and sets TIMA to `value`, so TIMA goes up every 4 M-cycles from here on. XOR A
+    /// is used for 0 so it matches the sequence that also sets the Z flag
+    fn restart_timer(asm: &mut Assembler, value: &dyn Fn(&mut Assembler)) {
+        value(asm);
+        asm.emit(&[0xE0, 0x04, 0xE0, 0x05]);
+    }
+
+    /// XOR A, the value cases that read TIMA restart it with
+    fn zero(asm: &mut Assembler) {
+        asm.emit(&[0xAF]);
+    }
+
+    /// A case that reads TIMA with `access` `nops` M-cycles after it was restarted, then the
+    /// same read done with LDH A,(05) after `reference_nops`. The two only match if `access`
+    /// reads on the M-cycle it should
+    fn read_case(
+        asm: &mut Assembler, slot: usize, setup: &dyn Fn(&mut Assembler), nops: usize,
+        access: &dyn Fn(&mut Assembler), reference_nops: usize,
+    ) {
+        setup(asm);
+        restart_timer(asm, &zero);
+        asm.nops(nops);
+        access(asm);
+        store_result(asm, slot);
+
+        restart_timer(asm, &zero);
+        asm.nops(reference_nops);
+        asm.emit(&[0xF0, 0x05]);
+        store_reference(asm, slot);
+    }
+
+    /// A case that writes over TIMA with `access` after restarting it with `value`, and reads
+    /// back whether TIMA went up before or after the write. The reference writes with
+    /// LDH (05),A, with `gap` standing in for anything `access` runs between writing and the read
+    #[allow(clippy::too_many_arguments)]
+    fn write_case(
+        asm: &mut Assembler, slot: usize, setup: &dyn Fn(&mut Assembler), value: &dyn Fn(&mut Assembler),
+        nops: usize, access: &dyn Fn(&mut Assembler), reference_nops: usize, gap: &[u8],
+    ) {
+        setup(asm);
+        restart_timer(asm, value);
+        asm.nops(nops);
+        access(asm);
+        // LDH A,(05); LD SP,FFFE
+        asm.emit(&[0xF0, 0x05, 0x31, 0xFE, 0xFF]);
+        store_result(asm, slot);
+
+        restart_timer(asm, value);
+        asm.nops(reference_nops);
+        asm.emit(&[0xE0, 0x05]);
+        asm.emit(gap);
+        asm.emit(&[0xF0, 0x05]);
+        store_reference(asm, slot);
+    }
+
+    /// LD A,0x80, the value most cases that write TIMA restart it with
+    fn eighty(asm: &mut Assembler) {
+        asm.emit(&[0x3E, 0x80]);
+    }
+
+    /// Names of the instructions `mem_timing_rom` checks, 8 cases each in this order
+    const MEM_TIMING_NAMES: [&str; 16] = [
+        "LD A,(HL)", "LD A,(a16)", "LDH A,(C)", "ADD A,(HL)", "POP BC", "RET", "RET Z", "RETI",
+        "LD (HL),A", "LD (a16),A", "LDH (C),A", "LD (HL),d8", "PUSH BC", "CALL a16", "CALL Z,a16", "RST 38",
+    ];
+
+    /// Builds a ROM like Blargg's mem_timing. The timer is set to go up every 4 M-cycles and
+    /// each instruction reads or writes TIMA after 8 different delays, so every M-cycle it could
+    /// get the access wrong on lands on an increment for one of them. Each result is checked
+    /// against the same access done by LDH, which runs with `tick` already.
+    ///
+    /// 0 ends up at 0xC000 if everything matched, otherwise the number of the first instruction
+    /// that didn't, counting from 1. Its name is also sent over serial
+    fn mem_timing_rom() -> Vec<u8> {
+        let mut asm = Assembler::new();
+        asm.rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        asm.rom[0x134..0x134 + 10].copy_from_slice(b"MEM TIMING");
+        // CGB, so it starts straight away instead of running the boot ROM
+        asm.rom[0x143] = 0x80;
+
+        // RET lands on 0x40xx with xx coming from TIMA, then counts up B through the INC B it
+        // lands on until JP HL takes it back. 0 - B is what TIMA was
+        asm.rom[0x4000..0x4100].fill(0x04);
+        asm.rom[0x4100] = 0xE9;
+        // RST 38 goes back with JP HL as well
+        asm.rom[0x38] = 0xE9;
+
+        // DI; LD SP,FFFE; IE = 0; 0x80 at C000 while running; TAC = 16 clock cycles
+        asm.emit(&[0xF3, 0x31, 0xFE, 0xFF, 0xAF, 0xE0, 0xFF, 0x3E, 0x80, 0xEA, 0x00, 0xC0, 0x3E, 0x05, 0xE0, 0x07]);
+
+        for i in 0..8 {
+            // LD HL,FF05; LD A,(HL)
+            read_case(&mut asm, i, &|asm| asm.emit(&[0x21, 0x05, 0xFF]), 3 + i, &|asm| asm.emit(&[0x7E]), 2 + i);
+        }
+        for i in 0..8 {
+            // LD A,(FF05)
+            read_case(&mut asm, 8 + i, &|_| (), 3 + i, &|asm| asm.emit(&[0xFA, 0x05, 0xFF]), 4 + i);
+        }
+        for i in 0..8 {
+            // LD C,05; LDH A,(C)
+            read_case(&mut asm, 16 + i, &|asm| asm.emit(&[0x0E, 0x05]), 3 + i, &|asm| asm.emit(&[0xF2]), 2 + i);
+        }
+        for i in 0..8 {
+            // LD HL,FF05; ADD A,(HL)
+            read_case(&mut asm, 24 + i, &|asm| asm.emit(&[0x21, 0x05, 0xFF]), 3 + i, &|asm| asm.emit(&[0x86]), 2 + i);
+        }
+        for i in 0..8 {
+            // LD SP,FF05; POP BC; LD SP,FFFE; LD A,C
+            let access = |asm: &mut Assembler| asm.emit(&[0xC1, 0x31, 0xFE, 0xFF, 0x79]);
+            read_case(&mut asm, 32 + i, &|asm| asm.emit(&[0x31, 0x05, 0xFF]), 3 + i, &access, 2 + i);
+        }
+        // RET, RET Z with Z set by XOR A, and RETI, which pop TIMA and TMA = 0x40
+        for (group, opcode, reference_nops) in [(5, 0xC9, 2), (6, 0xC8, 3), (7, 0xD9, 2)] {
+            for i in 0..8 {
+                let back = format!("back {}", group * 8 + i);
+                // TMA = 0x40; LD B,0; LD HL,back; LD SP,FF05
+                let setup = |asm: &mut Assembler| {
+                    asm.emit(&[0x3E, 0x40, 0xE0, 0x06, 0x06, 0x00, 0x21]);
+                    asm.address(&back);
+                    asm.emit(&[0x31, 0x05, 0xFF]);
+                };
+                // back: LD SP,FFFE; XOR A; SUB B
+                let access = |asm: &mut Assembler| {
+                    asm.emit(&[opcode]);
+                    asm.label(&back);
+                    asm.emit(&[0x31, 0xFE, 0xFF, 0xAF, 0x90]);
+                };
+                read_case(&mut asm, group * 8 + i, &setup, 3 + i, &access, reference_nops + i);
+            }
+        }
+        for i in 0..8 {
+            // LD HL,FF05; LD (HL),A
+            let setup = |asm: &mut Assembler| asm.emit(&[0x21, 0x05, 0xFF]);
+            write_case(&mut asm, 64 + i, &setup, &eighty, 3 + i, &|asm| asm.emit(&[0x77]), 2 + i, &[]);
+        }
+        for i in 0..8 {
+            // LD (FF05),A
+            let access = |asm: &mut Assembler| asm.emit(&[0xEA, 0x05, 0xFF]);
+            write_case(&mut asm, 72 + i, &|_| (), &eighty, 3 + i, &access, 4 + i, &[]);
+        }
+        for i in 0..8 {
+            // LD C,05; LDH (C),A
+            let setup = |asm: &mut Assembler| asm.emit(&[0x0E, 0x05]);
+            write_case(&mut asm, 80 + i, &setup, &eighty, 3 + i, &|asm| asm.emit(&[0xE2]), 2 + i, &[]);
+        }
+        for i in 0..8 {
+            // LD HL,FF05; LD (HL),80
+            let setup = |asm: &mut Assembler| asm.emit(&[0x21, 0x05, 0xFF]);
+            write_case(&mut asm, 88 + i, &setup, &eighty, 3 + i, &|asm| asm.emit(&[0x36, 0x80]), 3 + i, &[]);
+        }
+        for i in 0..8 {
+            // LD C,80; LD SP,FF07; PUSH BC, which writes C to TIMA last
+            let setup = |asm: &mut Assembler| asm.emit(&[0x0E, 0x80, 0x31, 0x07, 0xFF]);
+            write_case(&mut asm, 96 + i, &setup, &eighty, 3 + i, &|asm| asm.emit(&[0xC5]), 4 + i, &[]);
+        }
+        // CALL and CALL Z with Z set, to the next instruction. The low byte of the return address
+        // is written to TIMA last, so TIMA starts out at that value too
+        for (group, setup, opcode) in [(13, &[0x31, 0x07, 0xFF][..], 0xCD), (14, &[0x31, 0x07, 0xFF, 0xAF][..], 0xCC)] {
+            for i in 0..8 {
+                let back = format!("back {}", group * 8 + i);
+                let value = |asm: &mut Assembler| {
+                    asm.emit(&[0x3E]);
+                    asm.low(&back);
+                };
+                let access = |asm: &mut Assembler| {
+                    asm.emit(&[opcode]);
+                    asm.address(&back);
+                    asm.label(&back);
+                };
+                asm.keep_low_byte_under_f0(&back, |asm| {
+                    write_case(asm, group * 8 + i, &|asm| asm.emit(setup), &value, 3 + i, &access, 6 + i, &[]);
+                });
+            }
+        }
+        for i in 0..8 {
+            // LD HL,back; LD SP,FF07; RST 38, which comes back through JP HL
+            let back = format!("back {}", 120 + i);
+            let setup = |asm: &mut Assembler| {
+                asm.emit(&[0x21]);
+                asm.address(&back);
+                asm.emit(&[0x31, 0x07, 0xFF]);
+            };
+            let value = |asm: &mut Assembler| {
+                asm.emit(&[0x3E]);
+                asm.low(&back);
+            };
+            let access = |asm: &mut Assembler| {
+                asm.emit(&[0xFF]);
+                asm.label(&back);
+            };
+            asm.keep_low_byte_under_f0(&back, |asm| {
+                write_case(asm, 120 + i, &setup, &value, 3 + i, &access, 4 + i, &[0x00]);
+            });
+        }
+
+        // Compares the 128 results against their references
+        asm.emit(&[0x21, 0x00, 0xC1]);    // LD HL,C100
+        asm.emit(&[0x11, 0x00, 0xC2]);    // LD DE,C200
+        asm.emit(&[0x06, 0x80]);          // LD B,80
+        asm.label("compare");
+        asm.emit(&[0x1A]);                // LD A,(DE)
+        asm.emit(&[0xBE]);                // CP (HL)
+        asm.emit(&[0x20]);                // JR NZ,failed
+        asm.relative("failed");
+        asm.emit(&[0x23]);                // INC HL
+        asm.emit(&[0x13]);                // INC DE
+        asm.emit(&[0x05]);                // DEC B
+        asm.emit(&[0x20]);                // JR NZ,compare
+        asm.relative("compare");
+        asm.emit(&[0xAF]);                // XOR A
+        asm.emit(&[0xEA, 0x00, 0xC0]);    // LD (C000),A
+        asm.emit(&[0x21]);                // LD HL,passed text
+        asm.address("passed text");
+        asm.emit(&[0xCD]);                // CALL print
+        asm.address("print");
+        asm.label("passed");
+        asm.emit(&[0x18]);                // JR passed
+        asm.relative("passed");
+
+        // The instruction that failed is the slot / 8
+        asm.label("failed");
+        asm.emit(&[0x7D]);                // LD A,L
+        asm.emit(&[0xCB, 0x3F]);          // SRL A
+        asm.emit(&[0xCB, 0x3F]);          // SRL A
+        asm.emit(&[0xCB, 0x3F]);          // SRL A
+        asm.emit(&[0x3C]);                // INC A
+        asm.emit(&[0xEA, 0x00, 0xC0]);    // LD (C000),A
+        asm.emit(&[0x3D]);                // DEC A
+        asm.emit(&[0x87]);                // ADD A,A
+        asm.emit(&[0x21]);                // LD HL,names
+        asm.address("names");
+        asm.emit(&[0x85]);                // ADD A,L
+        asm.emit(&[0x6F]);                // LD L,A
+        asm.emit(&[0x30]);                // JR NC,no carry
+        asm.relative("no carry");
+        asm.emit(&[0x24]);                // INC H
+        asm.label("no carry");
+        asm.emit(&[0x2A]);                // LD A,(HL+)
+        asm.emit(&[0x66]);                // LD H,(HL)
+        asm.emit(&[0x6F]);                // LD L,A
+        asm.emit(&[0xCD]);                // CALL print
+        asm.address("print");
+        asm.emit(&[0x21]);                // LD HL,failed text
+        asm.address("failed text");
+        asm.emit(&[0xCD]);                // CALL print
+        asm.address("print");
+        asm.label("hang");
+        asm.emit(&[0x18]);                // JR hang
+        asm.relative("hang");
+
+        // Sends the string at HL over serial
+        asm.label("print");
+        asm.emit(&[0x2A]);                // LD A,(HL+)
+        asm.emit(&[0xB7]);                // OR A
+        asm.emit(&[0xC8]);                // RET Z
+        asm.emit(&[0xE0, 0x01]);          // LDH (01),A
+        asm.emit(&[0x3E, 0x81]);          // LD A,81
+        asm.emit(&[0xE0, 0x02]);          // LDH (02),A
+        asm.emit(&[0x18]);                // JR print
+        asm.relative("print");
+
+        asm.label("passed text");
+        asm.emit(b"mem_timing\n\nPassed\n\0");
+        asm.label("failed text");
+        asm.emit(b"\n\nFailed\n\0");
+        for name in MEM_TIMING_NAMES {
+            asm.label(name);
+            asm.emit(format!("mem_timing\n\n{}\0", name).as_bytes());
+        }
+        asm.label("names");
+        for name in MEM_TIMING_NAMES {
+            asm.address(name);
+        }
+
+        asm.finish()
+    }
+
+    #[test]
+    fn memory_accesses_land_on_the_right_m_cycle() {
+        let mut gameboy = GameBoy::new(mem_timing_rom(), None).unwrap();
+        let output = serial_output(&mut gameboy);
+
+        let mut result = 0x80;
+        for _ in 0..100 {
+            gameboy.run_frame();
+            result = gameboy.cpu.mmu.read_8(0xC000);
+            if result != 0x80 {
+                break;
+            }
+        }
+        assert_eq!(result, 0, "{}", output.lock().unwrap());
+    }
+
+    /// Collects everything `gameboy` sends over serial from now on
+    fn serial_output(gameboy: &mut GameBoy) -> Arc<Mutex<String>> {
+        let output = Arc::new(Mutex::new(String::new()));
+        let sink = output.clone();
+        gameboy.set_serial_callback(Box::new(move |byte| sink.lock().unwrap().push(byte as char)));
+        output
+    }
+
+    /// Runs one of Blargg's test ROMs until it prints whether it passed, returning what it sent
+    /// over serial
+    fn run_serial_test_rom(path: &str, max_frames: u32) -> String {
+        let rom = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap();
+        let mut gameboy = GameBoy::new(rom, None).unwrap();
+        let output = serial_output(&mut gameboy);
+
+        for _ in 0..max_frames {
+            gameboy.run_frame();
+            let output = output.lock().unwrap();
+            if output.contains("Passed") || output.contains("Failed") {
+                break;
+            }
+        }
+        let output = output.lock().unwrap().clone();
+        output
+    }
+
+    #[test]
+    fn interupts_pass_blarggs_test() {
+        // EI, DI, HALT and dispatching the timer interupt
+        let output = run_serial_test_rom("roms/02-interrupts.gb", 1000);
+        assert!(output.contains("Passed"), "{}", output);
+    }
+
+    /// A Game Boy running a ROM made from `code` at 0x150 and `vectors` at their addresses. The
+    /// ROM is marked CGB so it skips the boot ROM
+    fn program(code: &[u8], vectors: &[(usize, &[u8])]) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x143] = 0x80;
+        write_header_checksum(&mut rom);
+        rom[0x150..0x150 + code.len()].copy_from_slice(code);
+        for (at, bytes) in vectors {
+            rom[*at..*at + bytes.len()].copy_from_slice(bytes);
+        }
+
+        GameBoy::new(rom, None).unwrap()
+    }
+
+    /// Runs a few frames of a ROM made by `program`, returning the byte the code left at 0xC000
+    fn run_program(code: &[u8], vectors: &[(usize, &[u8])]) -> u8 {
+        let mut gameboy = program(code, vectors);
+        run_frames(&mut gameboy, 3)
+    }
+
+    /// Runs some frames and returns the byte at 0xC000
+    fn run_frames(gameboy: &mut GameBoy, frames: u32) -> u8 {
+        for _ in 0..frames {
+            gameboy.run_frame();
+        }
+        gameboy.cpu.mmu.read_8(0xC000)
+    }
+
+    /// LD A,B; LD (C000),A; JR -2
+    const STORE_B: [u8; 6] = [0x78, 0xEA, 0x00, 0xC0, 0x18, 0xFE];
+    /// DI, then a timer interupt that is both requested and enabled
+    const TIMER_PENDING: [u8; 7] = [0xF3, 0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F];
+
+    #[test]
+    fn instruction_after_ei_runs_before_the_interupt() {
+        // LD B,0; EI; INC B; INC B, with the timer handler storing B
+        let code = [&TIMER_PENDING[..], &[0x06, 0x00, 0xFB, 0x04, 0x04, 0x18, 0xFE]].concat();
+        assert_eq!(run_program(&code, &[(0x50, &STORE_B)]), 1);
+    }
+
+    #[test]
+    fn halt_with_interupts_disabled_reads_the_next_byte_twice() {
+        // LD B,0; HALT; INC B, which runs twice
+        let code = [&TIMER_PENDING[..], &[0x06, 0x00, 0x76, 0x04], &STORE_B].concat();
+        assert_eq!(run_program(&code, &[]), 2);
+    }
+
+    #[test]
+    fn pushing_pc_onto_ie_cancels_the_dispatch() {
+        // With SP at 0 the high byte of PC (0x01) lands on IE and turns the timer interupt off
+        // before it is picked, so the CPU ends up at 0x0000 instead of the handler
+        let code = [&TIMER_PENDING[..], &[0x31, 0x00, 0x00, 0xFB, 0x00, 0x00, 0x06, 0x01], &STORE_B].concat();
+        let at_zero = [&[0x06, 0x42][..], &STORE_B].concat();
+        let handler = [&[0x06, 0x50][..], &STORE_B].concat();
+        assert_eq!(run_program(&code, &[(0x00, &at_zero), (0x50, &handler)]), 0x42);
+    }
+
+    /// Selects the action buttons in P1, then LD B,0; STOP with INC B as the byte after it;
+    /// INC B and stores B
+    const STOP_THEN_STORE_B: [u8; 13] = [0x3E, 0x10, 0xE0, 0x00, 0x06, 0x00, 0x10, 0x04, 0x04, 0x78, 0xEA, 0x00, 0xC0];
+
+    #[test]
+    fn stop_skips_a_byte_and_waits_for_a_selected_button() {
+        let code = [&STOP_THEN_STORE_B[..], &[0x18, 0xFE]].concat();
+        let mut gameboy = program(&code, &[]);
+        assert_eq!(run_frames(&mut gameboy, 2), 0);
+        assert!(gameboy.is_stopped());
+
+        // The LCD sits still along with the CPU
+        let ly = gameboy.cpu.mmu.read_8(0xFF44);
+        let pc = gameboy.cpu.registers.pc;
+        assert_eq!(run_frames(&mut gameboy, 2), 0);
+        assert_eq!(gameboy.cpu.mmu.read_8(0xFF44), ly);
+        assert_eq!(gameboy.cpu.registers.pc, pc);
+
+        // Only the directions aren't selected, so pressing one doesn't pull a line low
+        gameboy.set_button(Button::Up, true);
+        assert_eq!(run_frames(&mut gameboy, 1), 0);
+        assert!(gameboy.is_stopped());
+
+        gameboy.set_button(Button::A, true);
+        assert_eq!(run_frames(&mut gameboy, 1), 1);
+        assert!(!gameboy.is_stopped());
+    }
+
+    #[test]
+    fn stop_resets_div() {
+        // Spends a few thousand cycles counting B down before stopping
+        let code = [&[0x06, 0x00, 0x05, 0x20, 0xFD][..], &STOP_THEN_STORE_B, &[0x18, 0xFE]].concat();
+        let mut gameboy = program(&code, &[]);
+        run_frames(&mut gameboy, 1);
+        assert!(gameboy.is_stopped());
+        assert_eq!(gameboy.cpu.mmu.read_8(0xFF04), 0);
+    }
+
+    #[test]
+    fn interupts_wait_for_the_cpu_to_wake_from_stop() {
+        // IE on the timer, EI, then STOP. The handler stores 0x50
+        let code = [&[0x3E, 0x04, 0xE0, 0xFF, 0xFB][..], &STOP_THEN_STORE_B, &[0x18, 0xFE]].concat();
+        let handler = [0x3E, 0x50, 0xEA, 0x00, 0xC0, 0x18, 0xFE];
+        let mut gameboy = program(&code, &[(0x50, &handler)]);
+        run_frames(&mut gameboy, 1);
+        assert!(gameboy.is_stopped());
+
+        gameboy.cpu.mmu.write_8(0xFF0F, 0x04);
+        assert_eq!(run_frames(&mut gameboy, 2), 0);
+        assert!(gameboy.is_stopped());
+
+        gameboy.set_button(Button::Start, true);
+        assert_eq!(run_frames(&mut gameboy, 1), 0x50);
+    }
+
+    #[test]
+    fn stop_switches_speed_when_key1_asks_for_it() {
+        // Arms KEY1, then STOP and stores what KEY1 reads back
+        let code = [0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0xF0, 0x4D, 0xEA, 0x00, 0xC0, 0x18, 0xFE];
+        let mut gameboy = program(&code, &[]);
+        // Double speed with the prepare bit cleared
+        assert_eq!(run_frames(&mut gameboy, 1), 0xFE);
+        assert!(gameboy.cpu.mmu.double_speed);
+        assert!(!gameboy.is_stopped());
+
+        // Arming it again goes back to normal speed
+        let code = [&code[..11], &code[..11], &[0x18, 0xFE]].concat();
+        let mut gameboy = program(&code, &[]);
+        assert_eq!(run_frames(&mut gameboy, 1), 0x7E);
+        assert!(!gameboy.cpu.mmu.double_speed);
+    }
+
+    /// Runs Dr. Mario for a while so every component has moved away from its power on state
+    fn running_game() -> GameBoy {
+        let rom = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("roms/drMario.gb")).unwrap();
+        let mut gameboy = GameBoy::new(rom, None).unwrap();
+        for _ in 0..120 {
+            gameboy.run_frame();
+        }
+        gameboy
+    }
+
+    #[test]
+    fn state_survives_a_save_and_load() {
+        let mut gameboy = running_game();
+        let saved = gameboy.save_state();
+
+        // Load into a machine that has moved on, then save again
+        for _ in 0..30 {
+            gameboy.run_frame();
+        }
+        gameboy.load_state(&saved).unwrap();
+        assert!(gameboy.save_state() == saved);
+
+        // Both copies run the same from there on
+        let mut other = running_game();
+        other.load_state(&saved).unwrap();
+        for _ in 0..30 {
+            gameboy.run_frame();
+            other.run_frame();
+        }
+        assert!(gameboy.save_state() == other.save_state());
+        assert!(gameboy.framebuffer() == other.framebuffer());
+    }
+
+    #[test]
+    fn broken_state_leaves_the_machine_as_it_was() {
+        let mut gameboy = running_game();
+        let before = gameboy.save_state();
+
+        let mut truncated = before.clone();
+        truncated.truncate(before.len() / 2);
+        assert!(gameboy.load_state(&truncated).is_err());
+        assert!(gameboy.save_state() == before);
+    }
+
+
+    #[test]
+    fn wall_time_clock_catches_up_with_the_save_file() {
+        use std::time::{ SystemTime, UNIX_EPOCH };
+
+        let dir = std::env::temp_dir().join(format!("jemulator-gameboy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("clock.gb");
+
+        // MBC3 with a clock and 8KB of battery backed RAM, marked CGB so it skips the boot ROM
+        let mut rom = vec![0; 0x10000];
+        rom[0x143] = 0x80;
+        rom[0x147] = 0x10;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        write_header_checksum(&mut rom);
+
+        // Stopped at 0:00:00 an hour, two minutes and three seconds ago
+        let saved = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 3723;
+        let mut save = vec![0; 0x2000 + 40];
+        save.extend_from_slice(&saved.to_le_bytes());
+        std::fs::write(dir.join("clock.sav"), save).unwrap();
+
+        let clock = |gameboy: &mut GameBoy| {
+            let mmu = &mut gameboy.cpu.mmu;
+            mmu.write_8(0x0000, 0x0A);
+            mmu.write_8(0x6000, 0x00);
+            mmu.write_8(0x6000, 0x01);
+            [0x0A, 0x09, 0x08].map(|register| {
+                mmu.write_8(0x4000, register);
+                mmu.read_8(0xA000)
+            })
+        };
+
+        let mut gameboy = GameBoy::new(rom.clone(), Some(&rom_path)).unwrap();
+        assert_eq!(clock(&mut gameboy), [0, 0, 0]);
+
+        let mut gameboy = GameBoy::with_rtc_clock(rom, Some(&rom_path), RtcClock::WallTime).unwrap();
+        let [hours, minutes, seconds] = clock(&mut gameboy);
+        assert_eq!((hours, minutes), (1, 2));
+        // A second might tick over while the test runs
+        assert!((3..=4).contains(&seconds), "{}", seconds);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
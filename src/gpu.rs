@@ -2,7 +2,13 @@ use crate::mmu::{
     VRAM_START, VRAM_END, OAM_START, OAM_END,
 };
 
-use std::sync::{ Arc, Mutex, MutexGuard};
+mod cgb;
+mod fifo;
+mod palette;
+
+use cgb::PaletteRam;
+use fifo::PixelFifo;
+pub use palette::{ Palette, ColorCorrection };
 
 /// Location in memory where the current scanline is stored (read-only)
 const CURR_SCANLINE_LOC: u16 = 0xFF44;
@@ -28,15 +34,48 @@ const OBP1_LOC: u16 = 0xFF48;
 const OBP2_LOC: u16 = 0xFF49;
 /// Locatio in memory of the DMA start/address register
 const DMA_TRANSFER_LOC: u16 = 0xFF46;
+/// Location in memory of the VRAM bank register (CGB only)
+const VBK_LOC: u16 = 0xFF4F;
+/// Location in memory of the background palette index (CGB only)
+const BCPS_LOC: u16 = 0xFF68;
+/// Location in memory of the background palette data (CGB only)
+const BCPD_LOC: u16 = 0xFF69;
+/// Location in memory of the sprite palette index (CGB only)
+const OCPS_LOC: u16 = 0xFF6A;
+/// Location in memory of the sprite palette data (CGB only)
+const OCPD_LOC: u16 = 0xFF6B;
+
+/// Width of the screen in pixels
+pub const SCREEN_WIDTH: usize = 160;
+/// Height of the screen in pixels
+pub const SCREEN_HEIGHT: usize = 144;
 
 const SPRITE_TABLE_SIZE: usize = 0xA0;
+/// Most sprites the PPU can draw on one scanline
+const SPRITES_PER_LINE: usize = 10;
+/*
+ *  Sprite attributes and CGB background map attributes share their layout:
+ *  Bit 7    Background colors 1-3 are drawn above sprites
+ *  Bit 6    Y flip
+ *  Bit 5    X flip
+ *  Bit 4    OBP1 instead of OBP0 (DMG sprites only)
+ *  Bit 3    VRAM bank of the tile (CGB only)
+ *  Bit 0-2  Color palette (CGB only)
+ */
+const ATTR_BG_PRIORITY: u8 = 0b1000_0000;
+const ATTR_Y_FLIP: u8 = 0b0100_0000;
+const ATTR_X_FLIP: u8 = 0b0010_0000;
+const ATTR_DMG_PALETTE: u8 = 0b0001_0000;
+const ATTR_BANK: u8 = 0b0000_1000;
+const ATTR_CGB_PALETTE: u8 = 0b0000_0111;
+/// Size of one VRAM bank, CGB has two of them
 const VRAM_SIZE: usize = 0x2000;
 
 const HBLANK_PERIOD: u64 = 204+172+80;
 const DRAW_PERIOD: u64 = 172+80;
 const OAM_PERIOD: u64 = 80;
 
-#[derive(Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct ColorPixel {
     pub r: u8,
     pub g: u8,
@@ -51,6 +90,19 @@ enum TilePixelValue {
     Three,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// How closely the PPU follows the hardware
+pub enum PpuAccuracy {
+    /// Draws each scanline in one go at the end of the line. Fast, but register writes in the
+    /// middle of a line only show up on the next one
+    #[default]
+    Scanline,
+    /// Shifts pixels out of the background and sprite FIFOs one dot at a time like the real PPU,
+    /// so mode 3 changes length with scrolling and sprites, and mid line writes land where they
+    /// happen
+    PixelFifo,
+}
+
 #[derive(PartialEq, Eq)]
 enum Mode {
     None,
@@ -62,8 +114,8 @@ enum Mode {
 
 pub struct GPU {
     // Memory Related State
-    /// The VRAM data that holds all the sprites
-    vram: [u8; VRAM_SIZE],
+    /// The VRAM data that holds all the sprites, bank 1 only exists on CGB
+    vram: Box<[u8]>,
     /// The RAM data for sprite attributes
     sprite_ram: [u8; SPRITE_TABLE_SIZE],
     // Memory Registers
@@ -91,21 +143,77 @@ pub struct GPU {
     obj_palette1: u8,
     /// The RAM data for the DMA transfer register
     pub dma_transfer: u8,
+    /// The VRAM bank the CPU sees at 0x8000-0x9FFF
+    vram_bank: u8,
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
 
     // Internal State
+    /// True when running a Game Boy Color cartridge in CGB mode
+    cgb: bool,
+    /// Set whenever a visible line enters HBlank, the MMU takes it to run HBlank DMA
+    pub hblank_started: bool,
+    accuracy: PpuAccuracy,
+    /// Shades DMG games are drawn with
+    palette: Palette,
+    color_correction: ColorCorrection,
+    /// Fetcher and FIFO state used when the accuracy is `PpuAccuracy::PixelFifo`
+    fifo: PixelFifo,
     mode: Mode,
     ticks_on_line: u64,
+    /// Which line of the window is drawn next, only moves on lines the window shows on
+    window_line: u8,
     
-    inner_ray: [[ColorPixel; 160]; 144],
+    /// Color ids (before the palette) of the background on the current scanline, sprites check
+    /// them for priority
+    bg_line: [u8; SCREEN_WIDTH],
+    /// Priority bit from the CGB map attributes of each background pixel on the current scanline
+    bg_line_priority: [bool; SCREEN_WIDTH],
+    /// The finished pixels, row by row from the top left
+    framebuffer: Box<[ColorPixel]>,
 }
 
-type MutexPixels = Arc<Mutex<[[ColorPixel; 160]; 144]>>;
 type TileArray = [[u8; 8]; 8];
 
 impl GPU {
     
-    pub fn update_graphics(&mut self, ticks: u8, shared_array: &MutexPixels) -> u8 {
-        let interupt = self.set_status();
+    pub fn update_graphics(&mut self, ticks: u8) -> u8 {
+        match self.accuracy {
+            PpuAccuracy::Scanline => self.update_scanline(ticks),
+            PpuAccuracy::PixelFifo => self.update_fifo(ticks),
+        }
+    }
+
+    pub fn accuracy(&self) -> PpuAccuracy {
+        self.accuracy
+    }
+
+    /// Switches between renderers, the new one takes over from the start of the next line
+    pub fn set_accuracy(&mut self, accuracy: PpuAccuracy) {
+        self.accuracy = accuracy;
+        self.fifo = PixelFifo::default();
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// Changes the colors DMG games are drawn with, CGB games use their own palettes
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    /// Changes how colors from CGB palette memory are shown
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+    }
+
+    fn update_scanline(&mut self, ticks: u8) -> u8 {
+        let mut interupt = self.set_status();
         
         if (self.lcd_control & 0b1000_0000) == 0b1000_0000 {
             self.ticks_on_line += ticks as u64;
@@ -117,13 +225,18 @@ impl GPU {
             self.ticks_on_line = 0;
             
             if self.current_scanline < 144 {
-                self.draw_scan_line(shared_array);
+                self.draw_scan_line();
             }
            
             self.current_scanline += 1;
 
+            if self.current_scanline == 144 {
+                interupt |= 0b0000_0001;
+            }
+
             if self.current_scanline > 153 {
                 self.current_scanline = 0;
+                self.window_line = 0;
             }
 
         }
@@ -131,160 +244,314 @@ impl GPU {
         interupt
     }
 
-    fn set_status(&mut self) -> u8 {
-        let mut interupt = 0;
-        let mut req_interupt = false;
-        let status = self.lcd_status;
+    /// True while LCDC bit 7 has the LCD and PPU switched on
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcd_control & 0b1000_0000 == 0b1000_0000
+    }
+
+    /// Writes to sprite attribute memory from a DMA transfer, which works no matter what mode
+    /// the PPU is in
+    pub fn write_oam(&mut self, offset: usize, val: u8) {
+        self.sprite_ram[offset] = val;
+    }
 
+    /// Writes to the selected VRAM bank from a CGB HDMA transfer
+    pub fn write_vram(&mut self, offset: u16, val: u8) {
+        self.vram[self.vram_bank as usize * VRAM_SIZE + (offset as usize & (VRAM_SIZE - 1))] = val;
+    }
+
+    /// Turns on the CGB only registers, VRAM bank 1 and color palettes
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// The last drawn frame, `SCREEN_WIDTH` pixels per row
+    pub fn framebuffer(&self) -> &[ColorPixel] {
+        &self.framebuffer
+    }
+
+    fn set_status(&mut self) -> u8 {
         if (self.lcd_control & 0b1000_0000) != 0b1000_0000 {
-            self.current_scanline = 0;
-            self.lcd_status = (status & 0b1111_1100) | 1;
+            self.lcd_off();
             return 0;
         }
-        
-        let current_mode: Mode;
 
-        if self.current_scanline >= 144 {
-            
-            current_mode = Mode::VBlank;
-            self.lcd_status = (status & 0b1111_1100) | 0b0000_0001;
-            if (self.lcd_status & 0b0001_0000) == 0b0001_0000 {
-                req_interupt = true;
-            }
-            
+        let mode = if self.current_scanline >= 144 {
+            Mode::VBlank
         } else {
             match self.ticks_on_line {
-                0..=OAM_PERIOD => {
-                    current_mode = Mode::OAMScan;
-                    
-                    self.lcd_status = (status & 0b1111_1100) | 0b0000_0010;
-                    if (self.lcd_status & 0b0010_0000) == 0b0010_0000 {
-                        req_interupt = true;
-                    }
-                },
-                81..=DRAW_PERIOD => {
-                    current_mode = Mode::Draw;
-                    
-                    self.lcd_status = (status & 0b1111_1100) | 0b0000_0011;
-                },
-                _ => {
-                    current_mode = Mode::HBlank;
-                    
-                    self.lcd_status = status & 0b1111_1100;
-                    if (self.lcd_status & 0b0000_1000) == 0b0000_1000 {
-                        req_interupt = true;
-                    }
-                },
+                0..=OAM_PERIOD => Mode::OAMScan,
+                81..=DRAW_PERIOD => Mode::Draw,
+                _ => Mode::HBlank,
             }
-        }
+        };
+
+        self.set_mode(mode) | self.compare_line()
+    }
+
+    /// Resets the PPU while the LCD is turned off
+    fn lcd_off(&mut self) {
+        self.current_scanline = 0;
+        self.window_line = 0;
+        self.ticks_on_line = 0;
+        self.fifo = PixelFifo::default();
+        // The LCD reports HBlank while off so VRAM and OAM are free to use
+        self.lcd_status &= 0b1111_1100;
+        self.mode = Mode::HBlank;
+    }
+
+    /// Updates the mode in STAT, returning the STAT interrupt if the new mode has it enabled
+    fn set_mode(&mut self, mode: Mode) -> u8 {
+        let (bits, enable) = match mode {
+            Mode::HBlank | Mode::None => (0b00, 0b0000_1000),
+            Mode::VBlank => (0b01, 0b0001_0000),
+            Mode::OAMScan => (0b10, 0b0010_0000),
+            Mode::Draw => (0b11, 0),
+        };
 
-        if req_interupt && self.mode != current_mode {
-            interupt |= 0b0000_0010;
+        self.lcd_status = (self.lcd_status & 0b1111_1100) | bits;
+        let interupt = if self.lcd_status & enable != 0 && self.mode != mode {
+            0b0000_0010
+        } else {
+            0
+        };
+        if mode == Mode::HBlank && self.mode != mode && (self.current_scanline as usize) < SCREEN_HEIGHT {
+            self.hblank_started = true;
         }
+        self.mode = mode;
+
+        interupt
+    }
+
+    /// Updates the coincidence flag in STAT, returning the STAT interrupt when LY starts
+    /// matching LYC
+    fn compare_line(&mut self) -> u8 {
+        let mut interupt = 0;
 
         if self.current_scanline == self.compare {
-            self.lcd_status = (status & 0b1111_1011) | 0b0000_0100;
-            interupt |= 0b0000_0100;
+            if self.lcd_status & 0b0100_0100 == 0b0100_0000 {
+                interupt |= 0b0000_0010;
+            }
+            self.lcd_status |= 0b0000_0100;
         } else {
             self.lcd_status &= 0b1111_1011;
         }
 
-
         interupt
     }
 
-    fn draw_scan_line(&mut self, shared_array: &MutexPixels) {
+    fn draw_scan_line(&mut self) {
         let control = self.lcd_control;
 
-        if (control & 0b0000_0001) == 0b0000_0001 {
-            self.render_tiles(shared_array);
+        // On CGB bit 0 only takes away the background's priority over sprites
+        if (control & 0b0000_0001) == 0b0000_0001 || self.cgb {
+            self.render_tiles();
+        } else {
+            // With the background off the line is blank and never covers sprites
+            self.bg_line = [0; SCREEN_WIDTH];
+            self.bg_line_priority = [false; SCREEN_WIDTH];
+            let line = self.current_scanline as usize * SCREEN_WIDTH;
+            self.framebuffer[line..line + SCREEN_WIDTH].fill(self.palette.bg[0]);
         }
 
         if (control & 0b0000_0010) == 0b0000_0010 {
-            //self.render_sprites()
+            self.render_sprites();
         }
     }
 
-    fn render_tiles(&mut self, shared_array: &MutexPixels) {
-        let mut locked_array = shared_array.lock().unwrap();
-
-        let window_tile_map_start = if self.lcd_control & 0x2 == 0x2 {
-            if self.lcd_control & 0x64 == 0x64 {
-                Some(0x9C00)
-            } else {
-                Some(0x9800)
-            }
+    fn render_tiles(&mut self) {
+        let bg_tile_map_start = if self.lcd_control & 0b0000_1000 == 0b0000_1000 {
+            0x9C00
         } else {
-            None
+            0x9800
         };
 
-        let bg_tile_map_start = if self.lcd_control & 0x8 == 0x8 {
+        let window_tile_map_start = if self.lcd_control & 0b0100_0000 == 0b0100_0000 {
             0x9C00
         } else {
             0x9800
         };
 
-        let tile_data_start: u16 = if self.lcd_control & 0x10 == 0x10 {
-            0x8000
-        } else {
-            0x8800
-        };
-        
-        let sy = self.scroll_y;
-        let sx = self.scroll_x;
-        let wy = self.window_y;
-        let wx = self.window_x.wrapping_sub(7);
-        
-        let tile_y = sy.wrapping_add(self.current_scanline) / 8;
-        
-        for index in 0..160 {
-            let tile_x = sx.wrapping_add(index) / 8;
+        // WX is offset by 7 so the window can start at the very left of the screen
+        let window_start = self.window_x as i16 - 7;
+        let window_visible = self.lcd_control & 0b0010_0000 == 0b0010_0000
+            && self.current_scanline >= self.window_y
+            && window_start < SCREEN_WIDTH as i16;
 
-            let current_tile: u16 = (tile_y as u16)*32 + (tile_x as u16);
-            
-            // TODO: Update to account for scrolling
-            let tile_identifier = if wx >= sx.wrapping_add(index) && wy >= sy.wrapping_add(self.current_scanline) && window_tile_map_start.is_some() {
-                self.vram[((window_tile_map_start.unwrap()+current_tile)-VRAM_START) as usize]
-            } else {
-                self.vram[((bg_tile_map_start+current_tile)-VRAM_START) as usize]
-            };
+        let line = self.current_scanline as usize * SCREEN_WIDTH;
 
-            let tile_start = if tile_data_start == 0x8000 {
-                tile_data_start+(tile_identifier as u16)*16
+        for index in 0..SCREEN_WIDTH {
+            let (map_start, map_x, map_y) = if window_visible && index as i16 >= window_start {
+                (window_tile_map_start, (index as i16 - window_start) as u8, self.window_line)
             } else {
-                tile_data_start+(128u8.wrapping_add_signed(tile_identifier as i8) as u16)*16
+                (bg_tile_map_start, self.scroll_x.wrapping_add(index as u8), self.scroll_y.wrapping_add(self.current_scanline))
             };
 
-            let inner_tile_y = sy.wrapping_add(self.current_scanline) % 8;
-            let inner_tile_x = sx.wrapping_add(index) % 8;
+            let current_tile = (map_y as u16 / 8) * 32 + (map_x as u16 / 8);
+            let map_offset = (map_start + current_tile - VRAM_START) as usize;
+            let tile_identifier = self.vram[map_offset];
+            let attributes = self.bg_attributes(map_offset);
 
-            let tile = self.get_tile(tile_start);
-            
-            let pixel = tile[inner_tile_y as usize][inner_tile_x as usize];
-
-            let color = match pixel {
-                0 => (self.bg_palatte & 0b0000_0011),
-                1 => (self.bg_palatte & 0b0000_1100) >> 2,
-                2 => (self.bg_palatte & 0b0011_0000) >> 4,
-                3 => (self.bg_palatte & 0b1100_0000) >> 6,
-                _ => unreachable!()
-            };
+            let tile_start = self.tile_data_start(tile_identifier);
 
-            let color_pixel = match color {
-                0 => ColorPixel { r: 0xd0, g: 0xd0, b: 0x58, a: 255 },
-                1 => ColorPixel { r: 0xa0, g: 0xa8, b: 0x40, a: 255 },
-                2 => ColorPixel { r: 0x70, g: 0x80, b: 0x28, a: 255 },
-                3 => ColorPixel { r: 0x40, g: 0x50, b: 0x10, a: 255 },
-                _ => unreachable!()
-            };
+            let (mut row, mut column) = ((map_y % 8) as usize, (map_x % 8) as usize);
+            if attributes & ATTR_Y_FLIP == ATTR_Y_FLIP {
+                row = 7 - row;
+            }
+            if attributes & ATTR_X_FLIP == ATTR_X_FLIP {
+                column = 7 - column;
+            }
+            let pixel = self.get_tile(tile_start, Self::attribute_bank(attributes))[row][column];
 
-            locked_array[self.current_scanline as usize][index as usize] = color_pixel;
+            self.bg_line[index] = pixel;
+            self.bg_line_priority[index] = attributes & ATTR_BG_PRIORITY == ATTR_BG_PRIORITY;
+            self.framebuffer[line + index] = self.bg_color(attributes, pixel);
         }
 
+        // The window picks up where it left off on the next line it shows on, even if it was
+        // hidden for a few lines in between
+        if window_visible {
+            self.window_line += 1;
+        }
     }
 
     fn render_sprites(&mut self) {
-        todo!()
+        let mut claimed = [false; SCREEN_WIDTH];
+
+        for sprite in self.line_sprites() {
+            let x = self.sprite_ram[sprite + 1];
+            let attributes = self.sprite_ram[sprite + 3];
+            let (tile_start, row) = self.sprite_row(sprite);
+            let bank = if self.cgb { Self::attribute_bank(attributes) } else { 0 };
+            let pixels = self.get_tile(tile_start, bank)[row as usize];
+
+            for column in 0..8u8 {
+                let screen_x = x as i16 - 8 + column as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&screen_x) {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+
+                let pixel = if attributes & ATTR_X_FLIP == ATTR_X_FLIP {
+                    pixels[7 - column as usize]
+                } else {
+                    pixels[column as usize]
+                };
+
+                // Color 0 is transparent and lets lower priority sprites show through
+                if pixel == 0 || claimed[screen_x] {
+                    continue;
+                }
+                claimed[screen_x] = true;
+
+                if self.bg_over_sprite(self.bg_line[screen_x], self.bg_line_priority[screen_x], attributes) {
+                    continue;
+                }
+
+                self.framebuffer[self.current_scanline as usize * SCREEN_WIDTH + screen_x] = self.sprite_color(attributes, pixel);
+            }
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcd_control & 0b0000_0100 == 0b0000_0100 { 16 } else { 8 }
+    }
+
+    /// OAM offsets of the sprites on the current line in the order they are drawn. Only the
+    /// first 10 sprites in OAM that overlap the line are drawn, and on DMG the one further left
+    /// wins with ties going to the one first in OAM. CGB only goes by OAM order
+    fn line_sprites(&self) -> Vec<usize> {
+        let height = self.sprite_height() as u16;
+        // Sprite positions are stored offset by 16 on Y and 8 on X so they can scroll in
+        let line = self.current_scanline as u16 + 16;
+
+        let mut sprites: Vec<usize> = (0..SPRITE_TABLE_SIZE / 4)
+            .map(|i| i * 4)
+            .filter(|&i| {
+                let y = self.sprite_ram[i] as u16;
+                (y..y + height).contains(&line)
+            })
+            .take(SPRITES_PER_LINE)
+            .collect();
+
+        if !self.cgb {
+            sprites.sort_by_key(|&i| self.sprite_ram[i + 1]);
+        }
+        sprites
+    }
+
+    /// Start of the tile a sprite shows on the current line and the row of it to draw, with
+    /// Y flip and tall sprites taken into account
+    fn sprite_row(&self, sprite: usize) -> (u16, u8) {
+        let height = self.sprite_height();
+        let mut tile = self.sprite_ram[sprite + 2];
+        if height == 16 {
+            tile &= 0xFE;
+        }
+
+        let mut row = self.current_scanline + 16 - self.sprite_ram[sprite];
+        if self.sprite_ram[sprite + 3] & ATTR_Y_FLIP == ATTR_Y_FLIP {
+            row = height - 1 - row;
+        }
+
+        (VRAM_START + (tile as u16 + (row / 8) as u16) * 16, row % 8)
+    }
+
+    /// Start of a background or window tile, using the addressing mode from LCDC bit 4
+    fn tile_data_start(&self, tile_identifier: u8) -> u16 {
+        if self.lcd_control & 0b0001_0000 == 0b0001_0000 {
+            0x8000 + (tile_identifier as u16) * 16
+        } else {
+            0x8800 + (128u8.wrapping_add_signed(tile_identifier as i8) as u16) * 16
+        }
+    }
+
+    /// CGB attributes of a background or window tile from the same spot of the map in bank 1
+    fn bg_attributes(&self, map_offset: usize) -> u8 {
+        if self.cgb { self.vram[VRAM_SIZE + map_offset] } else { 0 }
+    }
+
+    fn attribute_bank(attributes: u8) -> u8 {
+        (attributes & ATTR_BANK) >> 3
+    }
+
+    /// True when a background pixel hides an opaque sprite pixel drawn over it
+    fn bg_over_sprite(&self, bg_color: u8, bg_priority: bool, attributes: u8) -> bool {
+        if bg_color == 0 {
+            return false;
+        }
+
+        if self.cgb {
+            // Clearing LCDC bit 0 puts every sprite on top no matter what the attributes say
+            self.lcd_control & 0b0000_0001 == 0b0000_0001
+                && (bg_priority || attributes & ATTR_BG_PRIORITY == ATTR_BG_PRIORITY)
+        } else {
+            attributes & ATTR_BG_PRIORITY == ATTR_BG_PRIORITY
+        }
+    }
+
+    /// Color of a background or window pixel, `attributes` only matters on CGB
+    fn bg_color(&self, attributes: u8, color_id: u8) -> ColorPixel {
+        if self.cgb {
+            self.bg_palettes.color(attributes & ATTR_CGB_PALETTE, color_id, self.color_correction)
+        } else {
+            Self::palette_color(self.bg_palatte, &self.palette.bg, color_id)
+        }
+    }
+
+    fn sprite_color(&self, attributes: u8, color_id: u8) -> ColorPixel {
+        if self.cgb {
+            self.obj_palettes.color(attributes & ATTR_CGB_PALETTE, color_id, self.color_correction)
+        } else if attributes & ATTR_DMG_PALETTE == ATTR_DMG_PALETTE {
+            Self::palette_color(self.obj_palette1, &self.palette.obj1, color_id)
+        } else {
+            Self::palette_color(self.obj_palette0, &self.palette.obj0, color_id)
+        }
+    }
+
+    /// Looks a color id up in one of the palette registers and returns the shade it picks
+    fn palette_color(register: u8, shades: &[ColorPixel; 4], color_id: u8) -> ColorPixel {
+        shades[((register >> (color_id * 2)) & 0b11) as usize]
     }
 
     fn get_bit_val(byte: u8, index: u32) -> u8 {
@@ -295,12 +562,13 @@ impl GPU {
         }
     }
 
-    fn get_tile(&self, tile_start: u16) -> TileArray {
+    fn get_tile(&self, tile_start: u16, bank: u8) -> TileArray {
         let mut ret_array: TileArray = [[0; 8]; 8];
+        let bank_start = bank as usize * VRAM_SIZE;
         
         for i in (0..16).filter(|x| x % 2 == 0) {
-            let first_byte = self.vram[((tile_start+i) - VRAM_START) as usize];
-            let second_byte = self.vram[((tile_start+i) - VRAM_START) as usize];
+            let first_byte = self.vram[bank_start + ((tile_start+i) - VRAM_START) as usize];
+            let second_byte = self.vram[bank_start + ((tile_start+i+1) - VRAM_START) as usize];
 
             for j in 0..8 {
                 ret_array[(i/2) as usize][7-j] = 
@@ -317,8 +585,14 @@ impl GPU {
 impl Default for GPU {
     fn default() -> Self {
         Self {
-            vram: [0; VRAM_SIZE],
+            vram: vec![0; VRAM_SIZE * 2].into_boxed_slice(),
             sprite_ram: [0; SPRITE_TABLE_SIZE],
+            cgb: false,
+            hblank_started: false,
+            accuracy: PpuAccuracy::default(),
+            palette: Palette::default(),
+            color_correction: ColorCorrection::default(),
+            fifo: PixelFifo::default(),
             mode: Mode::None,
             current_scanline: 0,
             lcd_control: 0,
@@ -330,10 +604,16 @@ impl Default for GPU {
             obj_palette0: 0,
             obj_palette1: 0,
             dma_transfer: 0,
+            vram_bank: 0,
+            bg_palettes: PaletteRam::default(),
+            obj_palettes: PaletteRam::default(),
             compare: 0,
             lcd_status: 0,
             ticks_on_line: 0,
-            inner_ray: [[ColorPixel::default(); 160]; 144],
+            window_line: 0,
+            bg_line: [0; SCREEN_WIDTH],
+            bg_line_priority: [false; SCREEN_WIDTH],
+            framebuffer: vec![ColorPixel::default(); SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
         }
     }
 }
@@ -344,7 +624,7 @@ impl crate::mmu::Memory for GPU {
         match index {
             VRAM_START..=VRAM_END => {
                 if self.mode != Mode::Draw {
-                    self.vram[(index - VRAM_START) as usize]
+                    self.vram[self.vram_bank as usize * VRAM_SIZE + (index - VRAM_START) as usize]
                 } else {
                     0xFF
                 }
@@ -368,6 +648,12 @@ impl crate::mmu::Memory for GPU {
             OBP1_LOC => self.obj_palette0,
             OBP2_LOC => self.obj_palette1,
             DMA_TRANSFER_LOC => self.dma_transfer,
+            VBK_LOC if self.cgb => 0b1111_1110 | self.vram_bank,
+            BCPS_LOC if self.cgb => self.bg_palettes.read_index(),
+            BCPD_LOC if self.cgb => self.bg_palettes.read_data(),
+            OCPS_LOC if self.cgb => self.obj_palettes.read_index(),
+            OCPD_LOC if self.cgb => self.obj_palettes.read_data(),
+            VBK_LOC | BCPS_LOC..=OCPD_LOC => 0xFF,
             _ => unreachable!("Accessing memory that is not handled by gpu")
         }
     }
@@ -376,7 +662,7 @@ impl crate::mmu::Memory for GPU {
         match index {
             VRAM_START..=VRAM_END => {
                 if self.mode != Mode::Draw {
-                    self.vram[(index - VRAM_START) as usize] = val;
+                    self.vram[self.vram_bank as usize * VRAM_SIZE + (index - VRAM_START) as usize] = val;
                 }
             },
             OAM_START..=OAM_END => {
@@ -396,8 +682,298 @@ impl crate::mmu::Memory for GPU {
             OBP1_LOC => self.obj_palette0 = val,
             OBP2_LOC => self.obj_palette1 = val,
             DMA_TRANSFER_LOC => self.dma_transfer = val,
+            VBK_LOC if self.cgb => self.vram_bank = val & 1,
+            BCPS_LOC if self.cgb => self.bg_palettes.write_index(val),
+            BCPD_LOC if self.cgb => self.bg_palettes.write_data(val),
+            OCPS_LOC if self.cgb => self.obj_palettes.write_index(val),
+            OCPD_LOC if self.cgb => self.obj_palettes.write_data(val),
+            VBK_LOC | BCPS_LOC..=OCPD_LOC => (),
             _ => unreachable!("Accessing memory that is not handled by gpu")
         }
     }
 
 }
+
+impl crate::state::SaveState for GPU {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.sprite_ram);
+
+        let registers = [
+            self.current_scanline, self.compare, self.lcd_status, self.lcd_control,
+            self.scroll_y, self.scroll_x, self.window_y, self.window_x,
+            self.bg_palatte, self.obj_palette0, self.obj_palette1, self.dma_transfer,
+        ];
+        writer.write_bytes(&registers);
+
+        let mode = match self.mode {
+            Mode::None => 0,
+            Mode::OAMScan => 1,
+            Mode::Draw => 2,
+            Mode::HBlank => 3,
+            Mode::VBlank => 4,
+        };
+        writer.write_u8(mode);
+        writer.write_u64(self.ticks_on_line);
+        writer.write_u8(self.window_line);
+        writer.write_bool(self.cgb);
+        writer.write_u8(self.vram_bank);
+        self.bg_palettes.save_state(writer);
+        self.obj_palettes.save_state(writer);
+        self.fifo.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        reader.read_bytes(&mut self.vram)?;
+        reader.read_bytes(&mut self.sprite_ram)?;
+
+        let registers = [
+            &mut self.current_scanline, &mut self.compare, &mut self.lcd_status, &mut self.lcd_control,
+            &mut self.scroll_y, &mut self.scroll_x, &mut self.window_y, &mut self.window_x,
+            &mut self.bg_palatte, &mut self.obj_palette0, &mut self.obj_palette1, &mut self.dma_transfer,
+        ];
+        for reg in registers {
+            *reg = reader.read_u8()?;
+        }
+
+        self.mode = match reader.read_u8()? {
+            0 => Mode::None,
+            1 => Mode::OAMScan,
+            2 => Mode::Draw,
+            3 => Mode::HBlank,
+            4 => Mode::VBlank,
+            _ => return Err(crate::state::StateError::Invalid("PPU mode")),
+        };
+        self.ticks_on_line = reader.read_u64()?;
+        self.window_line = reader.read_u8()?;
+        self.cgb = reader.read_bool()?;
+        self.vram_bank = reader.read_u8()? & 1;
+        self.bg_palettes.load_state(reader)?;
+        self.obj_palettes.load_state(reader)?;
+        self.fifo.load_state(reader)?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Memory;
+
+    pub(super) const ACCURACIES: [PpuAccuracy; 2] = [PpuAccuracy::Scanline, PpuAccuracy::PixelFifo];
+
+    /// A PPU with the LCD, background and sprites on and tile data at 0x8000. The palette
+    /// registers give every color id the shade with the same number
+    pub(super) fn gpu(accuracy: PpuAccuracy) -> GPU {
+        let mut gpu = GPU::default();
+        gpu.set_accuracy(accuracy);
+        gpu.handle_write(LCD_CONTROL_LOC, 0b1001_0011);
+        for register in [BGP_LOC, OBP1_LOC, OBP2_LOC] {
+            gpu.handle_write(register, 0b1110_0100);
+        }
+        gpu
+    }
+
+    /// Makes every pixel of a tile in bank 0 the color `color`
+    pub(super) fn solid_tile(gpu: &mut GPU, tile: u8, color: u8) {
+        let start = tile as usize * 16;
+        for row in 0..8 {
+            gpu.vram[start + row * 2] = if color & 1 == 1 { 0xFF } else { 0 };
+            gpu.vram[start + row * 2 + 1] = if color & 2 == 2 { 0xFF } else { 0 };
+        }
+    }
+
+    /// Puts sprite `index` at the given screen position, without the offsets OAM stores
+    pub(super) fn place_sprite(gpu: &mut GPU, index: usize, (x, y): (u8, u8), tile: u8, attributes: u8) {
+        gpu.sprite_ram[index * 4..index * 4 + 4].copy_from_slice(&[y + 16, x + 8, tile, attributes]);
+    }
+
+    /// Runs the PPU dot by dot until the current line is over
+    pub(super) fn finish_line(gpu: &mut GPU) {
+        let line = gpu.current_scanline;
+        while gpu.current_scanline == line {
+            gpu.update_graphics(1);
+        }
+    }
+
+    /// Color ids drawn on a line, going by which of the DMG shades each pixel is
+    fn color_ids(gpu: &GPU, line: usize) -> Vec<u8> {
+        gpu.framebuffer[line * SCREEN_WIDTH..(line + 1) * SCREEN_WIDTH]
+            .iter()
+            .map(|pixel| gpu.palette.bg.iter().position(|shade| shade == pixel).unwrap() as u8)
+            .collect()
+    }
+
+    #[test]
+    fn only_the_first_10_sprites_in_oam_are_drawn() {
+        for accuracy in ACCURACIES {
+            let mut gpu = gpu(accuracy);
+            solid_tile(&mut gpu, 1, 3);
+
+            // A sprite on another line doesn't use up a slot
+            place_sprite(&mut gpu, 0, (0, 50), 1, 0);
+            // Later sprites in OAM are further left, so the one that misses out is the leftmost
+            for i in 1..=11 {
+                place_sprite(&mut gpu, i, ((11 - i as u8) * 12, 0), 1, 0);
+            }
+            finish_line(&mut gpu);
+
+            let line = color_ids(&gpu, 0);
+            assert!(line[0..8].iter().all(|&id| id == 0), "{:?}", accuracy);
+            for x in (12..132).step_by(12) {
+                assert!(line[x..x + 8].iter().all(|&id| id == 3), "{:?} at {}", accuracy, x);
+            }
+        }
+    }
+
+    #[test]
+    fn leftmost_sprite_wins_with_ties_going_to_oam_order() {
+        for accuracy in ACCURACIES {
+            let mut gpu = gpu(accuracy);
+            solid_tile(&mut gpu, 1, 1);
+            solid_tile(&mut gpu, 2, 2);
+
+            place_sprite(&mut gpu, 0, (14, 0), 1, 0);
+            place_sprite(&mut gpu, 1, (10, 0), 2, 0);
+            finish_line(&mut gpu);
+            let line = color_ids(&gpu, 0);
+            assert_eq!(line[10..22], [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1], "{:?}", accuracy);
+
+            place_sprite(&mut gpu, 2, (40, 1), 1, 0);
+            place_sprite(&mut gpu, 3, (40, 1), 2, 0);
+            finish_line(&mut gpu);
+            assert!(color_ids(&gpu, 1)[40..48].iter().all(|&id| id == 1), "{:?}", accuracy);
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_and_the_priority_bit_let_the_background_through() {
+        for accuracy in ACCURACIES {
+            let mut gpu = gpu(accuracy);
+            solid_tile(&mut gpu, 1, 1);
+            solid_tile(&mut gpu, 2, 2);
+            // Background tile 1 on the left half of the map row, color 0 on the right
+            gpu.vram[0x1800..0x1800 + 10].fill(1);
+
+            // A transparent sprite over a lower priority one doesn't hide it
+            place_sprite(&mut gpu, 0, (100, 0), 0, 0);
+            place_sprite(&mut gpu, 1, (100, 0), 2, 0);
+            // Behind the background, but only its colors 1-3
+            place_sprite(&mut gpu, 2, (76, 0), 2, ATTR_BG_PRIORITY);
+            finish_line(&mut gpu);
+
+            let line = color_ids(&gpu, 0);
+            assert_eq!(line[100..108], [2; 8], "{:?}", accuracy);
+            assert_eq!(line[76..84], [1, 1, 1, 1, 2, 2, 2, 2], "{:?}", accuracy);
+        }
+    }
+
+    #[test]
+    fn window_only_moves_down_on_lines_it_is_drawn() {
+        for accuracy in ACCURACIES {
+            let mut gpu = gpu(accuracy);
+            // The window uses the map at 0x9C00, its first tile row is color 1 and the second 2
+            solid_tile(&mut gpu, 1, 1);
+            solid_tile(&mut gpu, 2, 2);
+            gpu.vram[0x1C00..0x1C20].fill(1);
+            gpu.vram[0x1C20..0x1C40].fill(2);
+            gpu.handle_write(LCD_CONTROL_LOC, 0b1111_0011);
+            gpu.handle_write(WINDOW_Y_LOC, 0);
+            gpu.handle_write(WINDOW_X_LOC, 7);
+
+            for line in 0..13 {
+                match line {
+                    // Off the right edge of the screen
+                    3 => gpu.handle_write(WINDOW_X_LOC, 167),
+                    5 => gpu.handle_write(WINDOW_X_LOC, 7),
+                    // Turned off altogether
+                    6 => gpu.handle_write(LCD_CONTROL_LOC, 0b1101_0011),
+                    8 => gpu.handle_write(LCD_CONTROL_LOC, 0b1111_0011),
+                    _ => {}
+                }
+                finish_line(&mut gpu);
+            }
+            assert_eq!(gpu.window_line, 9, "{:?}", accuracy);
+
+            let expected = [1, 1, 1, 0, 0, 1, 0, 0, 1, 1, 1, 1, 2];
+            for (line, id) in expected.iter().enumerate() {
+                assert!(color_ids(&gpu, line).iter().all(|x| x == id), "{:?} on line {}", accuracy, line);
+            }
+        }
+    }
+
+    /// A CGB PPU with a line of background tiles and sprites over it that covers every way the
+    /// background and a sprite can decide which one is on top
+    fn cgb_priority_line(accuracy: PpuAccuracy, lcd_control: u8) -> GPU {
+        let mut gpu = gpu(accuracy);
+        gpu.cgb = true;
+        gpu.handle_write(LCD_CONTROL_LOC, lcd_control);
+        for (index, data) in [(BCPS_LOC, BCPD_LOC), (OCPS_LOC, OCPD_LOC)] {
+            // Index 0 with auto increment
+            gpu.handle_write(index, 0b1000_0000);
+            for i in 0..64 {
+                gpu.handle_write(data, if data == BCPD_LOC { i } else { 0x80 | i });
+            }
+        }
+        solid_tile(&mut gpu, 1, 1);
+        solid_tile(&mut gpu, 2, 2);
+        solid_tile(&mut gpu, 3, 3);
+
+        // Color 1 up to x 80 then color 0, with the priority attribute on the first 40 pixels
+        // of each
+        gpu.vram[0x1800..0x1800 + 10].fill(1);
+        gpu.vram[VRAM_SIZE + 0x1800..VRAM_SIZE + 0x1800 + 5].fill(ATTR_BG_PRIORITY);
+        gpu.vram[VRAM_SIZE + 0x1800 + 10..VRAM_SIZE + 0x1800 + 15].fill(ATTR_BG_PRIORITY);
+
+        place_sprite(&mut gpu, 0, (16, 0), 2, 0);
+        place_sprite(&mut gpu, 1, (48, 0), 2, 0);
+        place_sprite(&mut gpu, 2, (56, 0), 2, ATTR_BG_PRIORITY);
+        place_sprite(&mut gpu, 3, (88, 0), 2, ATTR_BG_PRIORITY);
+        // Overlapping, but OAM order decides on CGB instead of X
+        place_sprite(&mut gpu, 4, (134, 0), 2, 0);
+        place_sprite(&mut gpu, 5, (130, 0), 3, 0);
+
+        finish_line(&mut gpu);
+        gpu
+    }
+
+    #[test]
+    fn cgb_background_priority_attribute_puts_colors_1_to_3_on_top() {
+        for accuracy in ACCURACIES {
+            let gpu = cgb_priority_line(accuracy, 0b1001_0011);
+            let bg = gpu.bg_color(0, 1);
+            let sprite = gpu.sprite_color(0, 2);
+            assert!(bg != sprite);
+            let pixel = |x: usize| gpu.framebuffer[x];
+
+            // Priority in the background's attributes
+            assert!(pixel(16) == bg, "{:?}", accuracy);
+            // Neither asks for priority
+            assert!(pixel(48) == sprite, "{:?}", accuracy);
+            // Priority in the sprite's attributes
+            assert!(pixel(56) == bg, "{:?}", accuracy);
+            // Both ask for priority but the background is color 0
+            assert!(pixel(88) == sprite, "{:?}", accuracy);
+
+            assert!(pixel(130) == gpu.sprite_color(0, 3), "{:?}", accuracy);
+            assert!(pixel(134) == sprite, "{:?}", accuracy);
+        }
+    }
+
+    #[test]
+    fn cgb_sprites_are_always_on_top_with_lcdc_bit_0_clear() {
+        for accuracy in ACCURACIES {
+            let gpu = cgb_priority_line(accuracy, 0b1001_0010);
+            let sprite = gpu.sprite_color(0, 2);
+
+            for x in [16, 48, 56, 88, 134] {
+                assert!(gpu.framebuffer[x] == sprite, "{:?} at {}", accuracy, x);
+            }
+            // The background is still drawn around them
+            assert!(gpu.framebuffer[0] == gpu.bg_color(0, 1), "{:?}", accuracy);
+        }
+    }
+
+}
@@ -0,0 +1,111 @@
+use super::{ ColorPixel, ColorCorrection };
+use crate::state::{ SaveState, StateReader, StateWriter, StateError };
+
+/// Bytes in each palette memory, 8 palettes of 4 colors at 2 bytes a color
+const PALETTE_RAM_SIZE: usize = 64;
+/// Bit of BCPS/OCPS that moves the index forward after every write to the data register
+const AUTO_INCREMENT: u8 = 0b1000_0000;
+const INDEX_MASK: u8 = 0b0011_1111;
+
+/// One of the two CGB color palette memories along with the index register used to reach it
+pub(super) struct PaletteRam {
+    ram: [u8; PALETTE_RAM_SIZE],
+    /// BCPS or OCPS
+    index: u8,
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        // Games are expected to set their palettes, until then everything shows up white
+        Self {
+            ram: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+        }
+    }
+}
+
+impl PaletteRam {
+
+    pub(super) fn read_index(&self) -> u8 {
+        self.index | 0b0100_0000
+    }
+
+    pub(super) fn write_index(&mut self, val: u8) {
+        self.index = val & (AUTO_INCREMENT | INDEX_MASK);
+    }
+
+    pub(super) fn read_data(&self) -> u8 {
+        self.ram[(self.index & INDEX_MASK) as usize]
+    }
+
+    pub(super) fn write_data(&mut self, val: u8) {
+        self.ram[(self.index & INDEX_MASK) as usize] = val;
+
+        if self.index & AUTO_INCREMENT == AUTO_INCREMENT {
+            self.index = AUTO_INCREMENT | (self.index.wrapping_add(1) & INDEX_MASK);
+        }
+    }
+
+    /// Looks a color id up in one of the 8 palettes, colors are stored as little endian RGB555
+    pub(super) fn color(&self, palette: u8, color_id: u8, correction: ColorCorrection) -> ColorPixel {
+        let i = palette as usize * 8 + color_id as usize * 2;
+        let rgb = u16::from_le_bytes([self.ram[i], self.ram[i + 1]]);
+        let channel = |shift: u16| ((rgb >> shift) & 0x1F) as u8;
+
+        correction.apply(channel(0), channel(5), channel(10))
+    }
+
+}
+
+impl SaveState for PaletteRam {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_u8(self.index);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes(&mut self.ram)?;
+        self.index = reader.read_u8()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_writes_move_the_index_only_with_auto_increment() {
+        let mut palettes = PaletteRam::default();
+        palettes.write_index(AUTO_INCREMENT | 0x3E);
+        palettes.write_data(0x11);
+        palettes.write_data(0x22);
+        // Wraps around to the start of palette memory
+        assert_eq!(palettes.read_index(), 0b1100_0000);
+        palettes.write_data(0x33);
+
+        palettes.write_index(0x3F);
+        assert_eq!(palettes.read_index(), 0x7F);
+        assert_eq!(palettes.read_data(), 0x22);
+        palettes.write_data(0x44);
+        palettes.write_data(0x55);
+        assert_eq!(palettes.read_data(), 0x55);
+        palettes.write_index(0x00);
+        assert_eq!(palettes.read_data(), 0x33);
+    }
+
+    #[test]
+    fn colors_are_little_endian_rgb555() {
+        let mut palettes = PaletteRam::default();
+        // Palette 2, color 3 is 0b0_10000_00100_11111
+        palettes.write_index(AUTO_INCREMENT | (2 * 8 + 3 * 2));
+        palettes.write_data(0b1001_1111);
+        palettes.write_data(0b0100_0000);
+
+        assert_eq!(palettes.color(2, 3, ColorCorrection::None), ColorPixel { r: 0xFF, g: 0x21, b: 0x84, a: 255 });
+        // Untouched colors are white until the game sets them
+        assert_eq!(palettes.color(2, 2, ColorCorrection::None), ColorPixel { r: 0xFF, g: 0xFF, b: 0xFF, a: 255 });
+    }
+}
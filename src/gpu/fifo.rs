@@ -0,0 +1,506 @@
+use super::{
+    GPU, Mode, SCREEN_WIDTH, SCREEN_HEIGHT,
+    SPRITE_TABLE_SIZE, SPRITES_PER_LINE, VRAM_SIZE, ATTR_BG_PRIORITY, ATTR_X_FLIP, ATTR_Y_FLIP,
+};
+use crate::mmu::VRAM_START;
+use crate::state::{ SaveState, StateReader, StateWriter, StateError };
+
+use std::collections::VecDeque;
+
+/*
+ *  Every visible line takes 456 dots:
+ *  Mode 2  80 dots       OAM scan picks the sprites for the line
+ *  Mode 3  172-289 dots  Pixels are shifted out to the LCD, longer with fine scrolling, the
+ *                        window and sprites
+ *  Mode 0  the rest      HBlank
+ */
+
+/// Dots the OAM scan takes at the start of every visible line
+const OAM_SCAN_DOTS: u64 = 80;
+const DOTS_PER_LINE: u64 = 456;
+const LAST_LINE: u8 = 153;
+/// Dots a sprite fetch takes once the background fetcher has finished its tile
+const SPRITE_FETCH_DOTS: u8 = 6;
+/// Most pixels the FIFOs ever hold, their save states are always this size
+const BG_FIFO_CAPACITY: usize = 16;
+const OBJ_FIFO_CAPACITY: usize = 8;
+
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    /// CGB map attributes of the tile the pixel came from
+    attributes: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SpritePixel {
+    color: u8,
+    attributes: u8,
+    /// OAM offset of the sprite, CGB gives overlapping sprites priority by it
+    oam_index: u8,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum FetchStep {
+    #[default]
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Default)]
+/// State of the background fetcher and the two pixel FIFOs during mode 3
+pub(super) struct PixelFifo {
+    /// Background pixels waiting to be shifted out
+    bg: VecDeque<BgPixel>,
+    /// Sprite pixels lined up with the front of `bg`
+    obj: VecDeque<SpritePixel>,
+    step: FetchStep,
+    /// Dots spent on the current step, every step but pushing takes two
+    step_dots: u8,
+    /// Tile column the fetcher reads next, counted from the left edge of the screen or window
+    fetch_x: u8,
+    tile_id: u8,
+    tile_attributes: u8,
+    low: u8,
+    high: u8,
+    /// The first tile of every line is fetched twice, where the 12 dots on top of the 160 pixels
+    /// come from
+    first_fetch: bool,
+    /// Pixels still to drop from the left of the line for the fine scroll of SCX
+    discard: u8,
+    /// Pixels shifted out to the LCD on this line
+    x: u8,
+    /// True once the fetcher switched over to the window on this line
+    in_window: bool,
+    /// OAM offsets of the sprites on the line that haven't been fetched yet, leftmost first
+    sprites: VecDeque<usize>,
+    /// Sprite being fetched and the dots left until its pixels are ready
+    sprite_fetch: Option<(usize, u8)>,
+}
+
+impl GPU {
+
+    pub(super) fn update_fifo(&mut self, ticks: u8) -> u8 {
+        if (self.lcd_control & 0b1000_0000) != 0b1000_0000 {
+            self.lcd_off();
+            return 0;
+        }
+
+        let mut interupt = 0;
+        for _ in 0..ticks {
+            interupt |= self.dot();
+        }
+
+        interupt
+    }
+
+    /// Runs the PPU for a single dot
+    fn dot(&mut self) -> u8 {
+        let mut interupt = 0;
+
+        if (self.current_scanline as usize) < SCREEN_HEIGHT {
+            if self.ticks_on_line == 0 {
+                interupt |= self.set_mode(Mode::OAMScan);
+            } else if self.ticks_on_line == OAM_SCAN_DOTS {
+                self.start_line();
+                interupt |= self.set_mode(Mode::Draw);
+            }
+
+            if self.mode == Mode::Draw {
+                self.draw_dot();
+
+                if self.fifo.x as usize == SCREEN_WIDTH {
+                    if self.fifo.in_window {
+                        self.window_line += 1;
+                    }
+                    interupt |= self.set_mode(Mode::HBlank);
+                }
+            }
+        }
+
+        self.ticks_on_line += 1;
+        if self.ticks_on_line == DOTS_PER_LINE {
+            self.ticks_on_line = 0;
+            self.current_scanline += 1;
+
+            if self.current_scanline as usize == SCREEN_HEIGHT {
+                interupt |= 0b0000_0001 | self.set_mode(Mode::VBlank);
+            } else if self.current_scanline > LAST_LINE {
+                self.current_scanline = 0;
+                self.window_line = 0;
+            }
+        }
+
+        interupt | self.compare_line()
+    }
+
+    /// Sets up mode 3 once the OAM scan is over
+    fn start_line(&mut self) {
+        self.fifo = PixelFifo {
+            first_fetch: true,
+            discard: self.scroll_x % 8,
+            sprites: self.line_sprites().into(),
+            ..PixelFifo::default()
+        };
+        // Sprites are fetched as the LCD reaches them even when CGB draws them in OAM order
+        self.fifo.sprites.make_contiguous().sort_by_key(|&i| self.sprite_ram[i + 1]);
+    }
+
+    fn draw_dot(&mut self) {
+        // A sprite fetch holds up the pixels, but the background fetcher gets to finish its tile
+        if let Some((sprite, dots)) = self.fifo.sprite_fetch {
+            if self.fifo.step != FetchStep::Push {
+                self.step_fetcher();
+            } else if dots > 1 {
+                self.fifo.sprite_fetch = Some((sprite, dots - 1));
+            } else {
+                self.fifo.sprite_fetch = None;
+                self.fetch_sprite(sprite);
+            }
+            return;
+        }
+
+        self.step_fetcher();
+
+        if self.fifo.bg.is_empty() {
+            return;
+        }
+
+        if self.fifo.discard == 0 && self.window_starts() {
+            // Pixels from the window only start once the fetcher has its first tile
+            if self.window_x < 7 {
+                self.fifo.discard = 7 - self.window_x;
+            }
+            self.fifo.in_window = true;
+            self.fifo.bg.clear();
+            self.fifo.fetch_x = 0;
+            self.fifo.step = FetchStep::Tile;
+            self.fifo.step_dots = 0;
+            return;
+        }
+
+        if self.fifo.discard == 0 && self.lcd_control & 0b0000_0010 == 0b0000_0010 {
+            if let Some(&sprite) = self.fifo.sprites.front() {
+                if self.sprite_ram[sprite + 1] <= self.fifo.x + 8 {
+                    self.fifo.sprites.pop_front();
+                    self.fifo.sprite_fetch = Some((sprite, SPRITE_FETCH_DOTS));
+                    return;
+                }
+            }
+        }
+
+        let bg = self.fifo.bg.pop_front().unwrap();
+        let obj = self.fifo.obj.pop_front();
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        // On DMG clearing LCDC bit 0 blanks both the background and the window
+        let bg_enabled = self.cgb || self.lcd_control & 0b0000_0001 == 0b0000_0001;
+        let bg_color = if bg_enabled { bg.color } else { 0 };
+        let bg_priority = bg.attributes & ATTR_BG_PRIORITY == ATTR_BG_PRIORITY;
+
+        let color = match obj {
+            Some(sprite) if sprite.color != 0
+                && self.lcd_control & 0b0000_0010 == 0b0000_0010
+                && !self.bg_over_sprite(bg_color, bg_priority, sprite.attributes) => {
+                self.sprite_color(sprite.attributes, sprite.color)
+            },
+            _ if bg_enabled => self.bg_color(bg.attributes, bg.color),
+            _ => self.palette.bg[0],
+        };
+
+        let line = self.current_scanline as usize * SCREEN_WIDTH;
+        self.framebuffer[line + self.fifo.x as usize] = color;
+        self.fifo.x += 1;
+    }
+
+    /// True when the next pixel is where the window starts
+    fn window_starts(&self) -> bool {
+        !self.fifo.in_window
+            && self.lcd_control & 0b0010_0000 == 0b0010_0000
+            && self.current_scanline >= self.window_y
+            && self.fifo.x as u16 + 7 >= self.window_x as u16
+    }
+
+    /// Advances the background fetcher by one dot
+    fn step_fetcher(&mut self) {
+        if self.fifo.step != FetchStep::Push {
+            self.fifo.step_dots += 1;
+            if self.fifo.step_dots < 2 {
+                return;
+            }
+            self.fifo.step_dots = 0;
+        }
+
+        match self.fifo.step {
+            FetchStep::Tile => {
+                let (map_start, column, row) = self.fetcher_position();
+                let address = map_start + (row as u16 / 8) * 32 + column as u16;
+                let map_offset = (address - VRAM_START) as usize;
+                self.fifo.tile_id = self.vram[map_offset];
+                self.fifo.tile_attributes = self.bg_attributes(map_offset);
+                self.fifo.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                self.fifo.low = self.vram[self.fetcher_data_address()];
+                self.fifo.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                self.fifo.high = self.vram[self.fetcher_data_address() + 1];
+                // Pushing can happen on the same dot the fetch finishes
+                self.fifo.step = FetchStep::Push;
+                self.step_fetcher();
+            },
+            FetchStep::Push => {
+                // The background FIFO only takes a new tile once it has run dry
+                if !self.fifo.bg.is_empty() {
+                    return;
+                }
+
+                if self.fifo.first_fetch {
+                    self.fifo.first_fetch = false;
+                } else {
+                    let attributes = self.fifo.tile_attributes;
+                    for column in 0..8 {
+                        let bit = if attributes & ATTR_X_FLIP == ATTR_X_FLIP { column } else { 7 - column };
+                        let color = (((self.fifo.high >> bit) & 1) << 1) | ((self.fifo.low >> bit) & 1);
+                        self.fifo.bg.push_back(BgPixel { color, attributes });
+                    }
+                    self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+                }
+                self.fifo.step = FetchStep::Tile;
+            },
+        }
+    }
+
+    /// Tile map, column and pixel row the fetcher reads from next
+    fn fetcher_position(&self) -> (u16, u8, u8) {
+        if self.fifo.in_window {
+            let map_start = if self.lcd_control & 0b0100_0000 == 0b0100_0000 { 0x9C00 } else { 0x9800 };
+            (map_start, self.fifo.fetch_x & 31, self.window_line)
+        } else {
+            let map_start = if self.lcd_control & 0b0000_1000 == 0b0000_1000 { 0x9C00 } else { 0x9800 };
+            let column = (self.scroll_x / 8).wrapping_add(self.fifo.fetch_x) & 31;
+            (map_start, column, self.scroll_y.wrapping_add(self.current_scanline))
+        }
+    }
+
+    /// Offset into VRAM of the low byte of the row the fetcher is on
+    fn fetcher_data_address(&self) -> usize {
+        let (_, _, row) = self.fetcher_position();
+        let attributes = self.fifo.tile_attributes;
+
+        let mut row = row % 8;
+        if attributes & ATTR_Y_FLIP == ATTR_Y_FLIP {
+            row = 7 - row;
+        }
+
+        Self::attribute_bank(attributes) as usize * VRAM_SIZE
+            + (self.tile_data_start(self.fifo.tile_id) + row as u16 * 2 - VRAM_START) as usize
+    }
+
+    /// Mixes a sprite's row into the sprite FIFO. On DMG pixels already there from sprites
+    /// further left stay on top, on CGB the sprite first in OAM does
+    fn fetch_sprite(&mut self, sprite: usize) {
+        let x = self.sprite_ram[sprite + 1];
+        let attributes = self.sprite_ram[sprite + 3];
+        let (tile_start, row) = self.sprite_row(sprite);
+        let bank = if self.cgb { Self::attribute_bank(attributes) } else { 0 };
+        let address = bank as usize * VRAM_SIZE + (tile_start + row as u16 * 2 - VRAM_START) as usize;
+        let (low, high) = (self.vram[address], self.vram[address + 1]);
+
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(SpritePixel::default());
+        }
+
+        // Sprites hanging off the left of the screen lose the pixels that would be offscreen
+        let offscreen = 8usize.saturating_sub(x as usize);
+
+        for column in offscreen..8 {
+            let bit = if attributes & ATTR_X_FLIP == ATTR_X_FLIP { column } else { 7 - column };
+            let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+
+            let slot = &mut self.fifo.obj[column - offscreen];
+            let wins = slot.color == 0 || (self.cgb && color != 0 && (sprite as u8) < slot.oam_index);
+            if wins {
+                *slot = SpritePixel { color, attributes, oam_index: sprite as u8 };
+            }
+        }
+    }
+
+}
+
+impl SaveState for PixelFifo {
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        let bg = self.bg.iter().map(|pixel| [pixel.color, pixel.attributes]);
+        write_fixed(writer, bg, BG_FIFO_CAPACITY);
+
+        let obj = self.obj.iter().map(|pixel| [pixel.color, pixel.attributes, pixel.oam_index]);
+        write_fixed(writer, obj, OBJ_FIFO_CAPACITY);
+
+        let step = match self.step {
+            FetchStep::Tile => 0,
+            FetchStep::DataLow => 1,
+            FetchStep::DataHigh => 2,
+            FetchStep::Push => 3,
+        };
+        writer.write_u8(step);
+        writer.write_bytes(&[self.step_dots, self.fetch_x, self.tile_id, self.tile_attributes, self.low, self.high]);
+        writer.write_bool(self.first_fetch);
+        writer.write_u8(self.discard);
+        writer.write_u8(self.x);
+        writer.write_bool(self.in_window);
+
+        write_fixed(writer, self.sprites.iter().map(|&sprite| [sprite as u8]), SPRITES_PER_LINE);
+        let (sprite, dots) = self.sprite_fetch.unwrap_or((0, 0));
+        writer.write_bool(self.sprite_fetch.is_some());
+        writer.write_u8(sprite as u8);
+        writer.write_u8(dots);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.bg = read_fixed(reader, BG_FIFO_CAPACITY, "background FIFO")?
+            .into_iter()
+            .map(|[color, attributes]| BgPixel { color: color & 0b11, attributes })
+            .collect();
+        self.obj = read_fixed(reader, OBJ_FIFO_CAPACITY, "sprite FIFO")?
+            .into_iter()
+            .map(|[color, attributes, oam_index]| SpritePixel { color: color & 0b11, attributes, oam_index })
+            .collect();
+
+        self.step = match reader.read_u8()? {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return Err(StateError::Invalid("pixel fetcher step")),
+        };
+
+        let fields = [&mut self.step_dots, &mut self.fetch_x, &mut self.tile_id, &mut self.tile_attributes, &mut self.low, &mut self.high];
+        for field in fields {
+            *field = reader.read_u8()?;
+        }
+        self.first_fetch = reader.read_bool()?;
+        self.discard = reader.read_u8()?;
+        self.x = reader.read_u8()?;
+        self.in_window = reader.read_bool()?;
+
+        let valid_sprite = |sprite: usize| sprite.is_multiple_of(4) && sprite < SPRITE_TABLE_SIZE;
+
+        let sprites = read_fixed(reader, SPRITES_PER_LINE, "sprite list")?;
+        if !sprites.iter().all(|&[sprite]| valid_sprite(sprite as usize)) {
+            return Err(StateError::Invalid("sprite list"));
+        }
+        self.sprites = sprites.into_iter().map(|[sprite]| sprite as usize).collect();
+
+        let fetching = reader.read_bool()?;
+        let sprite = reader.read_u8()? as usize;
+        let dots = reader.read_u8()?;
+        if fetching && !valid_sprite(sprite) {
+            return Err(StateError::Invalid("sprite fetch"));
+        }
+        self.sprite_fetch = fetching.then_some((sprite, dots));
+
+        Ok(())
+    }
+
+}
+
+/// Writes a count followed by room for `capacity` entries, so the state is the same size however
+/// full the FIFOs are and rewind deltas line up from one frame to the next
+fn write_fixed<const N: usize>(writer: &mut StateWriter, entries: impl Iterator<Item = [u8; N]>, capacity: usize) {
+    let mut block = vec![0; capacity * N];
+    let mut count = 0;
+    for (slot, entry) in block.chunks_exact_mut(N).zip(entries) {
+        slot.copy_from_slice(&entry);
+        count += 1;
+    }
+
+    writer.write_u8(count);
+    writer.write_bytes(&block);
+}
+
+fn read_fixed<const N: usize>(reader: &mut StateReader, capacity: usize, what: &'static str) -> Result<Vec<[u8; N]>, StateError> {
+    let count = reader.read_u8()? as usize;
+    let mut block = vec![0; capacity * N];
+    reader.read_bytes(&mut block)?;
+
+    if count > capacity {
+        return Err(StateError::Invalid(what));
+    }
+
+    Ok(block
+        .chunks_exact(N)
+        .take(count)
+        .map(|entry| entry.try_into().unwrap())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{ PpuAccuracy, SCROLL_X_LOC };
+    use crate::gpu::tests::{ gpu, place_sprite, solid_tile };
+    use crate::mmu::Memory;
+
+    /// Dots spent drawing the first line
+    fn mode_3_length(gpu: &mut GPU) -> u64 {
+        let mut dots = 0;
+        while gpu.current_scanline == 0 {
+            gpu.update_graphics(1);
+            if gpu.mode == Mode::Draw {
+                dots += 1;
+            }
+        }
+        dots
+    }
+
+    fn with_sprites(positions: &[u8]) -> u64 {
+        let mut gpu = gpu(PpuAccuracy::PixelFifo);
+        solid_tile(&mut gpu, 1, 3);
+        for (i, &x) in positions.iter().enumerate() {
+            place_sprite(&mut gpu, i, (x, 0), 1, 0);
+        }
+        mode_3_length(&mut gpu)
+    }
+
+    #[test]
+    fn fine_scroll_adds_a_dot_for_each_pixel_thrown_away() {
+        let base = mode_3_length(&mut gpu(PpuAccuracy::PixelFifo));
+
+        for scroll in 0..16 {
+            let mut gpu = gpu(PpuAccuracy::PixelFifo);
+            gpu.handle_write(SCROLL_X_LOC, scroll);
+            assert_eq!(mode_3_length(&mut gpu), base + (scroll % 8) as u64, "SCX {}", scroll);
+        }
+    }
+
+    #[test]
+    fn sprites_stall_the_background_fetcher() {
+        let base = with_sprites(&[]);
+
+        // Screen x, so 0 is OAM x 8
+        let aligned = with_sprites(&[0]);
+        assert!(aligned >= base + SPRITE_FETCH_DOTS as u64);
+        // Landing part way through a background tile waits less for the fetch in progress
+        assert!(with_sprites(&[7]) < aligned);
+        // Off the right edge of the screen, OAM x 168 and over
+        assert_eq!(with_sprites(&[160, 200]), base);
+
+        let ten = with_sprites(&[0; 10]);
+        assert!(ten >= base + 10 * SPRITE_FETCH_DOTS as u64);
+        // An 11th on the line isn't fetched
+        assert_eq!(with_sprites(&[0; 11]), ten);
+
+        let spread: Vec<u8> = (0..10).map(|i| i * 16).collect();
+        assert!(with_sprites(&spread) > ten);
+        assert!(with_sprites(&spread) < DOTS_PER_LINE - OAM_SCAN_DOTS);
+    }
+
+}
@@ -0,0 +1,308 @@
+use super::ColorPixel;
+use crate::cartridge::header::CartridgeHeader;
+
+/// Builds a color from a 0xRRGGBB value
+const fn rgb(color: u32) -> ColorPixel {
+    ColorPixel {
+        r: (color >> 16) as u8,
+        g: (color >> 8) as u8,
+        b: color as u8,
+        a: 255,
+    }
+}
+
+const fn shades(colors: [u32; 4]) -> [ColorPixel; 4] {
+    [rgb(colors[0]), rgb(colors[1]), rgb(colors[2]), rgb(colors[3])]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The colors DMG games are drawn in. BGP, OBP0 and OBP1 pick which of their four shades each
+/// color id ends up as, lightest first
+pub struct Palette {
+    pub bg: [ColorPixel; 4],
+    pub obj0: [ColorPixel; 4],
+    pub obj1: [ColorPixel; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::DMG_GREEN
+    }
+}
+
+impl Palette {
+
+    /// The green tint of the original Game Boy screen
+    pub const DMG_GREEN: Self = Self::uniform(shades([0xD0D058, 0xA0A840, 0x708028, 0x405010]));
+    /// The slightly tinted gray of the Game Boy Pocket screen
+    pub const POCKET: Self = Self::uniform(shades([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]));
+    /// Pure black and white with evenly spaced grays in between
+    pub const HIGH_CONTRAST: Self = Self::uniform(shades([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]));
+
+    /// Uses the same four shades for the background and both sprite palettes
+    pub const fn uniform(shades: [ColorPixel; 4]) -> Self {
+        Self { bg: shades, obj0: shades, obj1: shades }
+    }
+
+    /// The palette a CGB colors an older game with, picked by the boot ROM from the title
+    /// checksum of games published by Nintendo. Everything else gets the default palette
+    pub fn cgb_compatibility(header: &CartridgeHeader) -> Self {
+        let nintendo = header.old_licensee_code == 0x01
+            || (header.old_licensee_code == 0x33 && header.new_licensee_code.as_deref() == Some("01"));
+        if !nintendo {
+            return Self::combination(0);
+        }
+
+        let fourth_letter = header.title.as_bytes().get(3).copied().unwrap_or(0);
+
+        // Searched in order like the boot ROM does, so the first title that fits wins
+        let index = TITLE_CHECKSUMS.iter().enumerate().position(|(i, &checksum)| {
+            checksum == header.title_checksum
+                && (i < FIRST_SHARED_CHECKSUM || FOURTH_LETTERS[i - FIRST_SHARED_CHECKSUM] == fourth_letter)
+        });
+
+        Self::combination(index.map_or(0, |i| PALETTE_PER_CHECKSUM[i] as usize))
+    }
+
+    /// One of the boot ROM's sprite and background palette combinations
+    fn combination(index: usize) -> Self {
+        let (obj0, obj1, bg) = COMBINATIONS[index];
+        let shades = |first: usize| -> [ColorPixel; 4] {
+            std::array::from_fn(|i| {
+                let color = COLORS[first + i];
+                ColorCorrection::None.apply((color & 0x1F) as u8, ((color >> 5) & 0x1F) as u8, (color >> 10) as u8)
+            })
+        };
+
+        Self { bg: shades(bg), obj0: shades(obj0), obj1: shades(obj1) }
+    }
+
+}
+
+/// Title checksums the boot ROM has a palette for, in the order it searches them. The checksums
+/// from `FIRST_SHARED_CHECKSUM` on belong to more than one title, so they only match when the
+/// fourth letter of the title does too
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3,
+];
+
+const FIRST_SHARED_CHECKSUM: usize = 65;
+
+/// The fourth letter of the title each shared checksum stands for
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// Which of `COMBINATIONS` each checksum in `TITLE_CHECKSUMS` is colored with
+const PALETTE_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 14, 2, 37, 34, 42, 5, 42,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 34, 23, 18, 29,
+    22,
+];
+
+/// The OBJ0, OBJ1 and BG palettes of each combination, as the index in `COLORS` of their
+/// lightest shade. A few start partway through a palette, borrowing the last shade of the one
+/// before it
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (16, 16, 116), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0),
+    (108, 108, 108), (20, 20, 20), (48, 48, 48), (104, 104, 104), (64, 32, 32), (16, 112, 112),
+    (16, 8, 8), (12, 16, 16), (16, 116, 116), (112, 16, 112), (8, 68, 8), (64, 64, 32),
+    (16, 16, 28), (16, 16, 72), (16, 16, 80), (76, 76, 36), (15, 15, 44), (68, 68, 8),
+    (16, 16, 8), (16, 16, 12), (112, 112, 0), (12, 12, 0), (0, 0, 4), (72, 88, 72),
+    (80, 88, 80), (96, 88, 96), (64, 88, 32), (68, 16, 52), (111, 0, 56), (111, 16, 60),
+    (76, 88, 36), (64, 112, 40), (16, 92, 112), (68, 88, 8), (16, 0, 8), (16, 112, 12),
+    (112, 12, 0), (12, 112, 16), (84, 112, 16), (12, 112, 0), (100, 12, 112), (0, 112, 32),
+    (16, 12, 112), (112, 12, 24), (16, 112, 116),
+];
+
+/// Every shade the boot ROM's palettes use, as CGB colors, four to a palette
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// How CGB colors are turned into the colors sent to the screen
+pub enum ColorCorrection {
+    /// Scales each channel straight up to 8 bits, which looks a lot more saturated than the
+    /// real screen
+    #[default]
+    None,
+    /// Bleeds the channels into each other and tones down the brightest colors like the CGB LCD
+    Lcd,
+}
+
+impl ColorCorrection {
+
+    /// Converts the 5 bit channels of a CGB color
+    pub(super) fn apply(self, r: u8, g: u8, b: u8) -> ColorPixel {
+        match self {
+            Self::None => {
+                let scale = |c: u8| (c << 3) | (c >> 2);
+                ColorPixel { r: scale(r), g: scale(g), b: scale(b), a: 255 }
+            },
+            Self::Lcd => {
+                let (r, g, b) = (r as u16, g as u16, b as u16);
+                // The weights add up to 32 so each channel tops out at 992 before being clamped
+                let mix = |value: u16| (value.min(960) >> 2) as u8;
+                ColorPixel {
+                    r: mix(r * 26 + g * 4 + b * 2),
+                    g: mix(g * 24 + b * 8),
+                    b: mix(r * 6 + g * 4 + b * 22),
+                    a: 255,
+                }
+            },
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{ GPU, BGP_LOC, OBP1_LOC, ATTR_DMG_PALETTE, SCREEN_WIDTH };
+    use crate::gpu::tests::{ gpu, finish_line, place_sprite, solid_tile, ACCURACIES };
+    use crate::cartridge::write_header_checksum;
+    use crate::mmu::Memory;
+
+    /// Draws a line of background color 1 with an OBP0 sprite at x 0 and an OBP1 sprite at x 8,
+    /// both color 2
+    fn draw_line(gpu: &mut GPU) -> &[ColorPixel] {
+        solid_tile(gpu, 1, 1);
+        solid_tile(gpu, 2, 2);
+        gpu.vram[0x1800..0x1800 + 32].fill(1);
+        place_sprite(gpu, 0, (0, 0), 2, 0);
+        place_sprite(gpu, 1, (8, 0), 2, ATTR_DMG_PALETTE);
+        finish_line(gpu);
+        &gpu.framebuffer[..SCREEN_WIDTH]
+    }
+
+    #[test]
+    fn preset_and_custom_palettes_reach_the_framebuffer() {
+        let custom = Palette {
+            bg: shades([0xFFFFFF, 0xFF0000, 0x00FF00, 0x0000FF]),
+            obj0: shades([0x111111, 0x222222, 0x333333, 0x444444]),
+            obj1: shades([0x555555, 0x666666, 0x777777, 0x888888]),
+        };
+
+        for accuracy in ACCURACIES {
+            for palette in [Palette::DMG_GREEN, Palette::POCKET, Palette::HIGH_CONTRAST, custom] {
+                let mut gpu = gpu(accuracy);
+                gpu.set_palette(palette);
+                let line = draw_line(&mut gpu);
+                assert!(line[0] == palette.obj0[2], "{:?}", accuracy);
+                assert!(line[8] == palette.obj1[2], "{:?}", accuracy);
+                assert!(line[16] == palette.bg[1], "{:?}", accuracy);
+            }
+
+            // The palette registers still pick which shade each color id gets
+            let mut gpu = gpu(accuracy);
+            gpu.set_palette(custom);
+            gpu.handle_write(BGP_LOC, 0b0001_1011);
+            gpu.handle_write(OBP1_LOC, 0b0000_0000);
+            let line = draw_line(&mut gpu);
+            assert!(line[0] == custom.obj0[0], "{:?}", accuracy);
+            assert!(line[16] == custom.bg[2], "{:?}", accuracy);
+        }
+    }
+
+    /// The header of a game by `licensee` whose title starts with `title` and is padded so the
+    /// boot ROM's title checksum comes out as `checksum`
+    fn header(title: &[u8], licensee: u8, checksum: u8) -> CartridgeHeader {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        let sum = title.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        rom[0x143] = checksum.wrapping_sub(sum);
+        rom[0x14B] = licensee;
+        write_header_checksum(&mut rom);
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title_checksum, checksum);
+        header
+    }
+
+    #[test]
+    fn compatibility_palette_comes_from_the_title_checksum() {
+        // 0x88 belongs to a single title so the fourth letter doesn't matter
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCD", 0x01, 0x88)), Palette::combination(4));
+        assert_eq!(Palette::cgb_compatibility(&header(b"WXYZ", 0x01, 0x88)), Palette::combination(4));
+
+        // Only games Nintendo published are looked up
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCD", 0x08, 0x88)), Palette::combination(0));
+
+        // As is an unknown checksum
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCD", 0x01, 0x02)), Palette::combination(0));
+    }
+
+    #[test]
+    fn shared_checksums_are_told_apart_by_the_fourth_letter() {
+        // 0xB3 is shared by three titles, each with its own palette
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCB", 0x01, 0xB3)), Palette::combination(36));
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCU", 0x01, 0xB3)), Palette::combination(17));
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCR", 0x01, 0xB3)), Palette::combination(22));
+        // Nothing with that checksum has this letter
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCZ", 0x01, 0xB3)), Palette::combination(0));
+
+        // 0x46 matches an 'E' or an 'R', the first fitting entry in the table wins
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCE", 0x01, 0x46)), Palette::combination(22));
+        assert_eq!(Palette::cgb_compatibility(&header(b"ABCR", 0x01, 0x46)), Palette::combination(46));
+    }
+
+    #[test]
+    fn no_correction_scales_channels_to_8_bits() {
+        assert_eq!(ColorCorrection::None.apply(31, 0, 16), rgb(0xFF0084));
+    }
+
+    #[test]
+    fn lcd_correction_matches_the_reference_colors() {
+        // Values from higan's CGB color table, which uses the same weights
+        let lcd = |r, g, b| ColorCorrection::Lcd.apply(r, g, b);
+        assert_eq!(lcd(0, 0, 0), rgb(0x000000));
+        assert_eq!(lcd(31, 0, 0), rgb(0xC9002E));
+        assert_eq!(lcd(0, 31, 0), rgb(0x1FBA1F));
+        assert_eq!(lcd(0, 0, 31), rgb(0x0F3EAA));
+        assert_eq!(lcd(31, 31, 0), rgb(0xE8BA4D));
+
+        // White adds up to 992 in each channel and is clamped to 960 before scaling
+        assert_eq!(lcd(31, 31, 31), rgb(0xF0F0F0));
+        assert_eq!(lcd(31, 31, 28), rgb(0xF0F0E7));
+    }
+}
@@ -32,7 +32,8 @@ impl crate::mmu::Memory for InteruptState {
     fn handle_read(&self, index: u16) -> u8 {
         match index {
             IE_LOC => self.ie,
-            IF_LOC => self.if_r,
+            // Only the low 5 bits exist, the rest read as 1
+            IF_LOC => self.if_r | 0b1110_0000,
             _ => unreachable!("InteruptState does not handle this memory"),
         }
     }
@@ -40,7 +41,7 @@ impl crate::mmu::Memory for InteruptState {
     fn handle_write(&mut self, index: u16, val: u8) {
         match index {
             IE_LOC => self.ie = val,
-            IF_LOC => self.if_r = val,
+            IF_LOC => self.if_r = val & 0b0001_1111,
             _ => unreachable!("InteruptState does not handle this memory"),
         }
     }
@@ -51,11 +52,9 @@ impl InteruptState {
         self.if_r |= flags;
     }
 
-    pub fn do_interupts(&mut self, ignore_master: bool) -> Interupt {
-        if !self.master && !ignore_master {
-            return Interupt::None;
-        }
-
+    /// Acknowledges the highest priority interupt that is both requested and enabled, clearing
+    /// its request. IME is up to the caller
+    pub fn take_interupt(&mut self) -> Interupt {
         for i in [1, 2, 4, 8, 16] {
             if (self.ie & i) == i && (self.if_r & i) == i {
                 self.if_r &= !i;
@@ -83,3 +82,20 @@ impl InteruptState {
         }
     }
 }
+
+impl crate::state::SaveState for InteruptState {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_u8(self.ie);
+        writer.write_u8(self.if_r);
+        writer.write_bool(self.master);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        self.ie = reader.read_u8()?;
+        self.if_r = reader.read_u8()?;
+        self.master = reader.read_bool()?;
+        Ok(())
+    }
+
+}
@@ -10,8 +10,9 @@ pub struct Joypad {
     pub interupt_possible: bool,
 }
 
-pub enum ButtonEvent {
-    None,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The eight inputs of the Game Boy
+pub enum Button {
     Start,
     Select,
     A,
@@ -22,27 +23,14 @@ pub enum ButtonEvent {
     Right,
 }
 
-impl ButtonEvent {
+impl Button {
 
     pub fn is_button(&self) -> bool {
-        match self {
-            Self::A | Self::B | Self::Select | Self::Start => true,
-            _ => false,
-        }
+        matches!(self, Self::A | Self::B | Self::Select | Self::Start)
     }
 
     pub fn is_direction(&self) -> bool {
-        match self {
-            Self::Right | Self::Left | Self::Up | Self::Down => true,
-            _ => false,
-        }
-    }
-
-    pub fn is_none(&self) -> bool {
-        match self {
-            Self::None => true,
-            _ => false,
-        }
+        !self.is_button()
     }
     
 }
@@ -55,11 +43,6 @@ const DOWN_START: u8 = 0b0000_1000;
 const BUTTON: u8 = 0b0010_0000;
 const DIRECTION: u8 = 0b0001_0000;
 
-pub struct ButtonEventWrapper {
-    pub event: ButtonEvent,
-    pub new_state: winit::event::ElementState,
-}
-
 impl Default for Joypad {
     fn default() -> Self {
         Self {
@@ -77,7 +60,7 @@ impl crate::mmu::Memory for Joypad {
         if index != JOYPAD_REG_LOC {
             unreachable!("Joypad does not manage this memory");
         } else {
-            self.joypad_reg = self.joypad_reg & 0b0000_1111 + val & 0b1111_0000;
+            self.joypad_reg = (self.joypad_reg & 0b0000_1111) | (val & 0b1111_0000);
         }
 
     }
@@ -99,56 +82,69 @@ impl crate::mmu::Memory for Joypad {
 }
 
 impl Joypad {
+
+    /// Whether a pressed input is pulling one of P10-P13 low on the selected lines, which is
+    /// what wakes the CPU from STOP
+    pub fn lines_low(&self) -> bool {
+        let mut low = 0;
+        if self.joypad_reg & DIRECTION == 0 {
+            low |= !self.direction_byte & 0xF;
+        }
+        if self.joypad_reg & BUTTON == 0 {
+            low |= !self.button_byte & 0xF;
+        }
+        low != 0
+    }
     
-    pub fn update_state(&mut self, wrapper: ButtonEventWrapper) {
-        // true means the button is NOT PRESSED
-        
-        let curr_state = match wrapper.event {
-            ButtonEvent::Down => self.direction_byte & DOWN_START,
-            ButtonEvent::Start => self.button_byte & DOWN_START,
-            ButtonEvent::Up => self.direction_byte & UP_SELECT,
-            ButtonEvent::Select => self.button_byte & UP_SELECT,
-            ButtonEvent::Left => self.direction_byte & LEFT_B,
-            ButtonEvent::B => self.button_byte & LEFT_B,
-            ButtonEvent::Right => self.direction_byte & RIGHT_A,
-            ButtonEvent::A => self.direction_byte & RIGHT_A,
-            ButtonEvent::None => unreachable!()
+    /// Presses or releases one of the inputs
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        // A set bit means the button is NOT PRESSED
+        let bit = match button {
+            Button::A | Button::Right => RIGHT_A,
+            Button::Left | Button::B => LEFT_B,
+            Button::Select | Button::Up => UP_SELECT,
+            Button::Down | Button::Start => DOWN_START,
         };
 
-        let base = match wrapper.event {
-            ButtonEvent::A | ButtonEvent::Right => RIGHT_A,
-            ButtonEvent::Left | ButtonEvent::B => LEFT_B,
-            ButtonEvent::Select | ButtonEvent::Up => UP_SELECT,
-            ButtonEvent::Down | ButtonEvent::Start => DOWN_START,
-            ButtonEvent::None => unreachable!()
+        let byte = if button.is_button() {
+            &mut self.button_byte
+        } else {
+            &mut self.direction_byte
         };
 
-        let new_state = match wrapper.new_state {
-            winit::event::ElementState::Pressed => false,
-            winit::event::ElementState::Released => true, 
-        };
+        let was_released = *byte & bit > 0;
+        if pressed {
+            *byte &= !bit;
+        } else {
+            *byte |= bit;
+        }
 
-        if curr_state > 0 && !new_state {
-            if self.joypad_reg & BUTTON == 0 && wrapper.event.is_button() {
+        if was_released && pressed {
+            if self.joypad_reg & BUTTON == 0 && button.is_button() {
                 self.interupt_possible = true;
-            } else if self.joypad_reg & DIRECTION == 0 && wrapper.event.is_direction() {
+            } else if self.joypad_reg & DIRECTION == 0 && button.is_direction() {
                 self.interupt_possible = true;
             }
         }
-        
-        if new_state {
-            if wrapper.event.is_button() {
-                self.button_byte |= base & 0xF;
-            } else {
-                self.direction_byte |= base & 0xF;
-            }
-        } else {
-            if wrapper.event.is_button() {
-                self.button_byte &= !base;
-            } else {
-                self.direction_byte &= !base;
-            }
-        }
     }
     
 }
+
+impl crate::state::SaveState for Joypad {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_u8(self.joypad_reg);
+        writer.write_u8(self.direction_byte);
+        writer.write_u8(self.button_byte);
+        writer.write_bool(self.interupt_possible);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        self.joypad_reg = reader.read_u8()?;
+        self.direction_byte = reader.read_u8()?;
+        self.button_byte = reader.read_u8()?;
+        self.interupt_possible = reader.read_bool()?;
+        Ok(())
+    }
+
+}
@@ -8,4 +8,11 @@ pub mod interupts;
 pub mod cartridge;
 pub mod joypad;
 pub mod apu;
+pub mod audio;
 pub mod util;
+pub mod error;
+pub mod state;
+pub mod rewind;
+pub mod gameboy;
+pub mod pacing;
+pub mod profile;
@@ -1,13 +1,16 @@
-use JEmulator::dissasembler::Dissasembler;
-use JEmulator::cpu::Cpu;
-use JEmulator::joypad::{ ButtonEvent, ButtonEventWrapper };
-use JEmulator::gpu::ColorPixel;
+use JEmulator::cartridge::RtcClock;
+use JEmulator::gameboy::GameBoy;
+use JEmulator::joypad::Button;
+use JEmulator::gpu::{ ColorPixel, ColorCorrection, Palette, PpuAccuracy, SCREEN_WIDTH, SCREEN_HEIGHT };
+use JEmulator::gameboy::CYCLES_PER_FRAME;
+use JEmulator::pacing::{ FastForwardAudio, FramePacer, PacingMode, SpeedMeter, SPEED_STEPS, audio_rate_scale };
+use JEmulator::rewind::{ RewindBuffer, RewindConfig };
 
-use std::time::Instant;
 use std::sync::{ Arc, Mutex };
 use std::sync::mpsc::channel;
 use std::thread::Builder;
 use std::fs::read;
+use std::path::{ Path, PathBuf };
 
 use winit::event_loop::EventLoop;
 use winit::window::WindowBuilder;
@@ -17,11 +20,180 @@ use winit::event::{ Event, WindowEvent, VirtualKeyCode, ElementState };
 use pixels::{ SurfaceTexture, Pixels };
 use pixels::wgpu::Color;
 
-const MAX_CYCLES: u64 = 69905;
+/// How many frames pass between writes of battery backed RAM to disk
+const SAVE_FLUSH_FRAMES: u64 = 300;
+/// Most memory the rewind history can use
+const REWIND_BUDGET: usize = 32 * 1024 * 1024;
+/// Frames between rewind snapshots, also how far back each rewound frame goes
+const REWIND_INTERVAL: u32 = 2;
+/// Most audio that is buffered ahead of the sound card, pacing keeps it about half full
+#[cfg(feature = "audio")]
+const AUDIO_LATENCY: std::time::Duration = std::time::Duration::from_millis(100);
 
 const WIDTH: u32 = 160;
 const HEIGHT: u32 = 144;
 const PIXEL_SIZE: u32 = 3;
+const TITLE: &str = "Jack's Emulator!";
+/// Index into `SPEED_STEPS` of full speed
+const NORMAL_SPEED: usize = 2;
+/// Index into `SPEED_STEPS` that fast-forward starts out at
+const FAST_FORWARD_SPEED: usize = 4;
+
+/// Messages sent from the window to the emulation thread
+enum EmulatorMessage {
+    LoadRom(PathBuf, Vec<u8>),
+    SaveState,
+    LoadState,
+    /// Sent when the rewind key is pressed and released
+    Rewind(bool),
+    /// Switches between the fast and the accurate PPU
+    TogglePpuAccuracy,
+    /// Moves on to the next of `PalettePreset`
+    CyclePalette,
+    ToggleColorCorrection,
+    /// Switches the cartridge clock between emulated time and the host's clock
+    ToggleRtcClock,
+    /// Moves on to the next `PacingMode`
+    CyclePacing,
+    /// Steps the speed up or down through `SPEED_STEPS`
+    ChangeSpeed(bool),
+    /// Switches between full speed and the last other speed picked
+    ToggleFastForward,
+    /// Moves on to the next `FastForwardAudio`
+    CycleFastForwardAudio,
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Palettes DMG games can be shown in, picked with F10
+enum PalettePreset {
+    DmgGreen,
+    Pocket,
+    HighContrast,
+    /// Whatever a CGB would color the running game with
+    CgbCompatibility,
+}
+
+impl PalettePreset {
+
+    fn next(self) -> Self {
+        match self {
+            Self::DmgGreen => Self::Pocket,
+            Self::Pocket => Self::HighContrast,
+            Self::HighContrast => Self::CgbCompatibility,
+            Self::CgbCompatibility => Self::DmgGreen,
+        }
+    }
+
+    fn palette(self, gameboy: &GameBoy) -> Palette {
+        match self {
+            Self::DmgGreen => Palette::DMG_GREEN,
+            Self::Pocket => Palette::POCKET,
+            Self::HighContrast => Palette::HIGH_CONTRAST,
+            Self::CgbCompatibility => gameboy.header().map_or(Palette::default(), Palette::cgb_compatibility),
+        }
+    }
+
+}
+
+/// The host's audio output. There never is one unless built with the `audio` feature
+struct HostAudio {
+    #[cfg(feature = "audio")]
+    device: Option<JEmulator::audio::AudioDevice>,
+}
+
+impl HostAudio {
+
+    #[cfg(feature = "audio")]
+    fn open() -> Self {
+        let device = JEmulator::audio::AudioDevice::open(AUDIO_LATENCY)
+            .map_err(|e| log::error!("{}", e))
+            .ok();
+        Self { device }
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn open() -> Self {
+        Self {}
+    }
+
+    /// Sends the audio of `gameboy` to the device
+    #[cfg(feature = "audio")]
+    fn attach(&self, gameboy: &mut GameBoy) {
+        if let Some(device) = &self.device {
+            device.clear();
+            gameboy.set_audio_sink(Some(Box::new(device.sink())));
+        }
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn attach(&self, _gameboy: &mut GameBoy) {}
+
+    /// Sets up the audio of `gameboy` for running at `speed`. Returns whether there is any audio
+    /// playing to pace the emulator by
+    fn configure(&self, gameboy: &mut GameBoy, speed: f64, handling: FastForwardAudio) -> bool {
+        let muted = speed.is_infinite() || (speed != 1.0 && handling == FastForwardAudio::Mute);
+        if muted {
+            gameboy.set_audio_sink(None);
+            return false;
+        }
+
+        self.attach(gameboy);
+        if handling == FastForwardAudio::TimeStretch {
+            gameboy.set_audio_time_stretch(speed);
+        }
+        self.fill().is_some()
+    }
+
+    /// How full the device's buffer is, if there is a device
+    fn fill(&self) -> Option<f32> {
+        #[cfg(feature = "audio")]
+        if let Some(device) = &self.device {
+            return Some(device.fill());
+        }
+        None
+    }
+
+}
+
+/// Writes a save state of the running game next to its ROM
+fn save_state(gameboy: &GameBoy, rom_path: &Path) {
+    let path = rom_path.with_extension("state");
+    match std::fs::write(&path, gameboy.save_state()) {
+        Ok(()) => log::info!("Saved state to {}", path.display()),
+        Err(e) => log::error!("Could not write save state {}: {}", path.display(), e),
+    }
+}
+
+/// Restores the save state next to the ROM of the running game
+fn load_state(gameboy: &mut GameBoy, rom_path: &Path) {
+    let path = rom_path.with_extension("state");
+    let result = read(&path)
+        .map_err(JEmulator::error::Error::from)
+        .and_then(|bytes| gameboy.load_state(&bytes));
+
+    match result {
+        Ok(()) => log::info!("Loaded state from {}", path.display()),
+        Err(e) => log::error!("Could not load save state {}: {}", path.display(), e),
+    }
+}
+
+/// The other of the two cartridge clock sources
+fn toggle_rtc_clock(clock: RtcClock) -> RtcClock {
+    let clock = match clock {
+        RtcClock::Cycles => RtcClock::WallTime,
+        RtcClock::WallTime => RtcClock::Cycles,
+    };
+    log::info!("Cartridge clock set to {:?}", clock);
+    clock
+}
+
+/// Writes the cartridge RAM to its save file, if it has one
+fn flush_save(gameboy: &mut GameBoy) {
+    if let Err(e) = gameboy.flush_save() {
+        log::error!("Could not write save file: {}", e);
+    }
+}
 
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::new().filter_or("", "info")).init();
@@ -30,7 +202,7 @@ fn main() {
     let window = {
         let size = LogicalSize::new((WIDTH*PIXEL_SIZE) as f64, (HEIGHT*PIXEL_SIZE) as f64);
         WindowBuilder::new()
-            .with_title("Jack's Emulator!")
+            .with_title(TITLE)
             .with_inner_size(size)
             .with_resizable(false)
             .build(&event_loop)
@@ -43,101 +215,252 @@ fn main() {
         Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
     };
 
-    let pixel_array1 = Arc::new(Mutex::new([[ColorPixel::default(); WIDTH as usize]; HEIGHT as usize]));
+    let pixel_array1 = Arc::new(Mutex::new(vec![ColorPixel::default(); SCREEN_WIDTH * SCREEN_HEIGHT]));
     let pixel_array2 = Arc::clone(&pixel_array1);
     
-    let (event_sender, event_receiver) = channel::<ButtonEventWrapper>();
+    let (event_sender, event_receiver) = channel::<(Button, bool)>();
     let (render_sender, render_receiver) = channel::<()>();
-    let (rom_sender, rom_receiver) = channel::<Vec<u8>>();
+    let (message_sender, message_receiver) = channel::<EmulatorMessage>();
+    let (speed_sender, speed_receiver) = channel::<(f64, f64)>();
     
-    Builder::new()
+    let emulation_thread = Builder::new()
         .name("Emulation Thread".to_string())
         .spawn(move || {
-        let d = Dissasembler::new().unwrap();
-        let bytes = rom_receiver.recv().unwrap();
-        let mut cpu = Cpu::from_rom(bytes);
+        let mut rtc_clock = RtcClock::default();
+
+        // Wait for the first ROM that loads successfully
+        let (mut gameboy, mut rom_path) = loop {
+            match message_receiver.recv().unwrap() {
+                EmulatorMessage::LoadRom(path, bytes) => match GameBoy::with_rtc_clock(bytes, Some(&path), rtc_clock) {
+                    Ok(gameboy) => break (gameboy, path),
+                    Err(e) => log::error!("Could not load {}: {}", path.display(), e),
+                },
+                EmulatorMessage::ToggleRtcClock => {
+                    rtc_clock = toggle_rtc_clock(rtc_clock);
+                },
+                EmulatorMessage::Quit => return,
+                _ => (),
+            }
+        };
+        let audio = HostAudio::open();
+        let mut audio_playing = audio.configure(&mut gameboy, 1.0, FastForwardAudio::default());
+        let mut pacer = FramePacer::new(if audio_playing { PacingMode::Audio } else { PacingMode::Timer });
+        let mut speed = SpeedMeter::default();
+        let mut speed_index = NORMAL_SPEED;
+        let mut fast_forward_index = FAST_FORWARD_SPEED;
+        let mut fast_forward_audio = FastForwardAudio::default();
+        let mut stopped = false;
+        let mut frames: u64 = 0;
+        let mut rewind = RewindBuffer::new(RewindConfig { memory_budget: REWIND_BUDGET, interval: REWIND_INTERVAL });
+        let mut rewinding = false;
+        let mut accuracy = PpuAccuracy::default();
+        let mut palette = PalettePreset::DmgGreen;
+        let mut correction = ColorCorrection::default();
         
         loop {
-            if let Ok(bytes) = rom_receiver.try_recv() {
-                cpu = Cpu::from_rom(bytes);
+            match message_receiver.try_recv() {
+                Ok(EmulatorMessage::LoadRom(path, bytes)) => {
+                    // Written out first so loading the same ROM again picks up its current save.
+                    // A bad ROM keeps the current game running
+                    flush_save(&mut gameboy);
+                    match GameBoy::with_rtc_clock(bytes, Some(&path), rtc_clock) {
+                        Ok(new_gameboy) => {
+                            gameboy = new_gameboy;
+                            gameboy.set_ppu_accuracy(accuracy);
+                            gameboy.set_palette(palette.palette(&gameboy));
+                            gameboy.set_color_correction(correction);
+                            audio_playing = audio.configure(&mut gameboy, pacer.speed(), fast_forward_audio);
+                            rom_path = path;
+                            rewind.clear();
+                        },
+                        Err(e) => log::error!("Could not load {}: {}", path.display(), e),
+                    }
+                },
+                Ok(EmulatorMessage::SaveState) => save_state(&gameboy, &rom_path),
+                Ok(EmulatorMessage::LoadState) => load_state(&mut gameboy, &rom_path),
+                Ok(EmulatorMessage::Rewind(held)) => rewinding = held,
+                Ok(EmulatorMessage::TogglePpuAccuracy) => {
+                    accuracy = match accuracy {
+                        PpuAccuracy::Scanline => PpuAccuracy::PixelFifo,
+                        PpuAccuracy::PixelFifo => PpuAccuracy::Scanline,
+                    };
+                    gameboy.set_ppu_accuracy(accuracy);
+                    log::info!("PPU accuracy set to {:?}", accuracy);
+                },
+                Ok(EmulatorMessage::CyclePalette) => {
+                    palette = palette.next();
+                    gameboy.set_palette(palette.palette(&gameboy));
+                    log::info!("Palette set to {:?}", palette);
+                },
+                Ok(EmulatorMessage::ToggleColorCorrection) => {
+                    correction = match correction {
+                        ColorCorrection::None => ColorCorrection::Lcd,
+                        ColorCorrection::Lcd => ColorCorrection::None,
+                    };
+                    gameboy.set_color_correction(correction);
+                    log::info!("Color correction set to {:?}", correction);
+                },
+                Ok(EmulatorMessage::ToggleRtcClock) => {
+                    rtc_clock = toggle_rtc_clock(rtc_clock);
+                    gameboy.set_rtc_clock(rtc_clock);
+                },
+                Ok(EmulatorMessage::CyclePacing) => {
+                    pacer.set_mode(pacer.mode().next());
+                    log::info!("Pacing set to {:?}", pacer.mode());
+                },
+                Ok(message @ (EmulatorMessage::ChangeSpeed(_) | EmulatorMessage::ToggleFastForward)) => {
+                    speed_index = match message {
+                        EmulatorMessage::ChangeSpeed(true) => (speed_index + 1).min(SPEED_STEPS.len() - 1),
+                        EmulatorMessage::ChangeSpeed(false) => speed_index.saturating_sub(1),
+                        _ if speed_index == NORMAL_SPEED => fast_forward_index,
+                        _ => NORMAL_SPEED,
+                    };
+                    if speed_index != NORMAL_SPEED {
+                        fast_forward_index = speed_index;
+                    }
+
+                    pacer.set_speed(SPEED_STEPS[speed_index]);
+                    audio_playing = audio.configure(&mut gameboy, pacer.speed(), fast_forward_audio);
+                    log::info!("Speed set to {}x", pacer.speed());
+                },
+                Ok(EmulatorMessage::CycleFastForwardAudio) => {
+                    fast_forward_audio = fast_forward_audio.next();
+                    audio_playing = audio.configure(&mut gameboy, pacer.speed(), fast_forward_audio);
+                    log::info!("Fast-forward audio set to {:?}", fast_forward_audio);
+                },
+                Ok(EmulatorMessage::Quit) => {
+                    flush_save(&mut gameboy);
+                    return;
+                },
+                Err(_) => (),
             }
             
-            let start = Instant::now();
-            let mut cycles = 0;
+            for (button, pressed) in event_receiver.try_iter() {
+                gameboy.set_button(button, pressed);
+            }
 
-            while cycles < MAX_CYCLES {
-                for event in event_receiver.try_iter() {
-                    cpu.mmu.joypad.update_state(event);
+            // While rewinding each frame steps back to the previous snapshot and runs from there
+            // to draw it. Once the history runs out the game stays paused until the key is let go
+            if !rewinding || rewind.rewind(&mut gameboy) {
+                gameboy.run_frame();
+            }
+
+            if gameboy.is_stopped() != stopped {
+                stopped = gameboy.is_stopped();
+                if stopped {
+                    log::info!("The game executed STOP, press a button to wake it up");
+                } else {
+                    log::info!("Woke up from STOP");
                 }
-                
-                let (tick_cycles, ignore_master) = cpu.tick(&d);
-                cycles += tick_cycles as u64;
+            }
 
-                let interupt_request = cpu.mmu.tick(tick_cycles, ignore_master, &pixel_array1);
-                cycles += cpu.service_interupts(interupt_request) as u64;
+            // No audio is made while stopped, so there is nothing to pace to
+            let fill = || audio.fill().unwrap_or(0.0);
+            pacer.wait((audio_playing && !stopped).then_some(&fill as &dyn Fn() -> f32));
+            if let (true, Some(fill)) = (audio_playing, audio.fill()) {
+                // Pitch shifting is just playing the audio as fast as it is made
+                let pitch = match fast_forward_audio {
+                    FastForwardAudio::PitchShift => 1.0 / pacer.speed(),
+                    _ => 1.0,
+                };
+                gameboy.set_audio_rate_scale(audio_rate_scale(fill) * pitch);
             }
 
-            'here: loop {
-                if start.elapsed().as_nanos() >= 16_666_667 {
-                    break 'here;
-                }
+            if speed.record_frame(CYCLES_PER_FRAME) {
+                let _ = speed_sender.send((speed.speed(), speed.fps()));
             }
 
-            render_sender.send(()).unwrap();
-            
+            if pacer.should_render() {
+                pixel_array1.lock().unwrap().copy_from_slice(gameboy.framebuffer());
+                render_sender.send(()).unwrap();
+            }
+
+            if !rewinding {
+                rewind.record_frame(&gameboy);
+            }
+
+            frames += 1;
+            if frames.is_multiple_of(SAVE_FLUSH_FRAMES) {
+                flush_save(&mut gameboy);
+            }
         }
     }).unwrap();
+    let mut emulation_thread = Some(emulation_thread);
 
     event_loop.run(move |event, _, control_flow| {
 
         if render_receiver.try_recv().is_ok() {
             let locked_array = pixel_array2.lock().unwrap();
             
-            for (i, ray) in pixels.frame_mut().chunks_exact_mut(4).enumerate() {
-                let x = i % WIDTH as usize;
-                let y = i / WIDTH as usize;
-                
-                if y < 144 {
-                    let color = locked_array[y][x];
-                    
-                    let rgba = [color.r, color.g, color.b, color.a];
-
-                    ray.copy_from_slice(&rgba);
-                }
+            for (ray, color) in pixels.frame_mut().chunks_exact_mut(4).zip(locked_array.iter()) {
+                ray.copy_from_slice(&[color.r, color.g, color.b, color.a]);
             }
 
             pixels.render().unwrap();
         }
+
+        if let Some((speed, fps)) = speed_receiver.try_iter().last() {
+            window.set_title(&format!("{} - {:.0}% ({:.1} fps)", TITLE, speed * 100.0, fps));
+        }
         
         control_flow.set_poll();
         
         match event {
             Event::WindowEvent { window_id, event } => {
                 match event {
-                    WindowEvent::CloseRequested => std::process::exit(0),
+                    WindowEvent::CloseRequested => {
+                        // Let the emulation thread write out the save file before exiting
+                        let _ = message_sender.send(EmulatorMessage::Quit);
+                        if let Some(thread) = emulation_thread.take() {
+                            let _ = thread.join();
+                        }
+                        std::process::exit(0)
+                    },
                     WindowEvent::KeyboardInput { device_id, input, is_synthetic } => {
                         if let Some(code) = input.virtual_keycode {
-                            let event = match code {
-                                VirtualKeyCode::A => ButtonEvent::A,
-                                VirtualKeyCode::S => ButtonEvent::B,
-                                VirtualKeyCode::Return => ButtonEvent::Start,
-                                VirtualKeyCode::Space => ButtonEvent::Select,
-                                VirtualKeyCode::Right => ButtonEvent::Right,
-                                VirtualKeyCode::Left => ButtonEvent::Left,
-                                VirtualKeyCode::Up => ButtonEvent::Up,
-                                VirtualKeyCode::Down => ButtonEvent::Down,
-                                _ => ButtonEvent::None,
+                            if input.state == ElementState::Pressed {
+                                match code {
+                                    VirtualKeyCode::F5 => message_sender.send(EmulatorMessage::SaveState).unwrap(),
+                                    VirtualKeyCode::F6 => message_sender.send(EmulatorMessage::CyclePacing).unwrap(),
+                                    VirtualKeyCode::F7 => message_sender.send(EmulatorMessage::CycleFastForwardAudio).unwrap(),
+                                    VirtualKeyCode::Tab => message_sender.send(EmulatorMessage::ToggleFastForward).unwrap(),
+                                    VirtualKeyCode::Minus => message_sender.send(EmulatorMessage::ChangeSpeed(false)).unwrap(),
+                                    VirtualKeyCode::Equals => message_sender.send(EmulatorMessage::ChangeSpeed(true)).unwrap(),
+                                    VirtualKeyCode::F8 => message_sender.send(EmulatorMessage::LoadState).unwrap(),
+                                    VirtualKeyCode::F9 => message_sender.send(EmulatorMessage::TogglePpuAccuracy).unwrap(),
+                                    VirtualKeyCode::F10 => message_sender.send(EmulatorMessage::CyclePalette).unwrap(),
+                                    VirtualKeyCode::F11 => message_sender.send(EmulatorMessage::ToggleColorCorrection).unwrap(),
+                                    VirtualKeyCode::F12 => message_sender.send(EmulatorMessage::ToggleRtcClock).unwrap(),
+                                    _ => (),
+                                }
+                            }
+
+                            if code == VirtualKeyCode::Back {
+                                let held = input.state == ElementState::Pressed;
+                                message_sender.send(EmulatorMessage::Rewind(held)).unwrap();
+                            }
+
+                            let button = match code {
+                                VirtualKeyCode::A => Some(Button::A),
+                                VirtualKeyCode::S => Some(Button::B),
+                                VirtualKeyCode::Return => Some(Button::Start),
+                                VirtualKeyCode::Space => Some(Button::Select),
+                                VirtualKeyCode::Right => Some(Button::Right),
+                                VirtualKeyCode::Left => Some(Button::Left),
+                                VirtualKeyCode::Up => Some(Button::Up),
+                                VirtualKeyCode::Down => Some(Button::Down),
+                                _ => None,
                             };
                             
-                            if !event.is_none() {
-                                event_sender.send(ButtonEventWrapper { event, new_state: input.state }).unwrap();
+                            if let Some(button) = button {
+                                event_sender.send((button, input.state == ElementState::Pressed)).unwrap();
                             }
                         }
                     },
                     WindowEvent::DroppedFile(path) => {
-                        let droppped_file = read(path);
-                        if let Ok(bytes) = droppped_file {
-                            rom_sender.send(bytes).unwrap();
+                        match read(&path) {
+                            Ok(bytes) => message_sender.send(EmulatorMessage::LoadRom(path, bytes)).unwrap(),
+                            Err(e) => log::error!("Could not read {}: {}", path.display(), e),
                         }
                     }
                     _ => ()
@@ -1,6 +1,8 @@
+use crate::cartridge::RtcClock;
+use crate::cartridge::header::CgbSupport;
 use crate::util::{ BitOperations, le_combine };
-use crate::gpu::ColorPixel;
-use std::sync::{ Arc, Mutex };
+use crate::profile::{ self, Subsystem };
+use std::path::Path;
 
 /*
  *  Memory map from nocash-s pandocs:
@@ -52,28 +54,72 @@ macro_rules! apu_stuff(
 
 macro_rules! gpu_stuff(
     () => {
-        0xFF40..=0xFF49 | 0xFF4A | 0xFF4B
+        0xFF40..=0xFF49 | 0xFF4A | 0xFF4B | 0xFF4F | 0xFF68..=0xFF6B
     }
 );
 
-const WRAM_SIZE: usize = 0x2000;
+/// Location in memory of the CGB speed switch register
+const KEY1_LOC: u16 = 0xFF4D;
+/// Locations in memory of the CGB HDMA source, destination and length/start registers
+const HDMA1_LOC: u16 = 0xFF51;
+const HDMA2_LOC: u16 = 0xFF52;
+const HDMA3_LOC: u16 = 0xFF53;
+const HDMA4_LOC: u16 = 0xFF54;
+const HDMA5_LOC: u16 = 0xFF55;
+/// Location in memory of the CGB WRAM bank register
+const SVBK_LOC: u16 = 0xFF70;
+
+/// Size of one WRAM bank, DMG has 2 of them and CGB has 8
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
 const HRAM_SIZE: usize = 0x7F;
+/// Bytes HDMA copies every HBlank
+const HDMA_BLOCK_SIZE: u16 = 0x10;
+/// Clock cycles the CPU is paused for while HDMA copies a block, twice as many in double speed
+const HDMA_BLOCK_CYCLES: u32 = 32;
 
 pub trait Memory {
     fn handle_write(&mut self, index: u16, val: u8);
     fn handle_read(&self, index: u16) -> u8;
 }
 
+#[derive(Default)]
+/// CGB DMA into VRAM, either all at once or 16 bytes every HBlank
+struct Hdma {
+    source: u16,
+    /// Offset into VRAM
+    destination: u16,
+    /// Blocks left to copy minus one, as read back from HDMA5
+    remaining: u8,
+    /// True while an HBlank transfer is running
+    active: bool,
+    /// Clock cycles the CPU owes for blocks copied since it last checked
+    stall: u32,
+}
+
+/// Called with each byte a game sends over the serial port, test ROMs report their results there
+pub type SerialCallback = Box<dyn FnMut(u8) + Send>;
+
 pub struct MMU {
-    gpu: crate::gpu::GPU,
+    pub gpu: crate::gpu::GPU,
     timer: crate::timer::Timer,
     pub interupt: crate::interupts::InteruptState,
     pub joypad: crate::joypad::Joypad,
-    apu: crate::apu::APU,
-    cartridge: crate::cartridge::Cartridge,
-    wram: [u8; WRAM_SIZE],
+    pub apu: crate::apu::APU,
+    pub cartridge: crate::cartridge::Cartridge,
+    wram: Box<[u8]>,
     hram: [u8; HRAM_SIZE],
     serial: char,
+    serial_callback: Option<SerialCallback>,
+    /// True when running a Game Boy Color cartridge in CGB mode
+    pub cgb: bool,
+    /// The WRAM bank at 0xD000-0xDFFF, always 1 outside of CGB mode
+    wram_bank: u8,
+    /// True while the CPU runs at twice the normal speed
+    pub double_speed: bool,
+    /// Set through KEY1 so the next STOP switches speed
+    speed_switch_armed: bool,
+    hdma: Hdma,
 }
 
 impl MMU {
@@ -89,17 +135,35 @@ impl MMU {
             joypad: Joypad::default(),
             apu: APU::default(),
             cartridge: Cartridge::default(),
-            wram: [0; WRAM_SIZE],
+            wram: vec![0; WRAM_BANK_SIZE * WRAM_BANKS].into_boxed_slice(),
             hram: [0; HRAM_SIZE],
             serial: ' ',
+            serial_callback: None,
+            cgb: false,
+            wram_bank: 1,
+            double_speed: false,
+            speed_switch_armed: false,
+            hdma: Hdma::default(),
         }
     }
     
-    /// Creates an instance of MMU with all default values initialized
-    pub fn new(rom: Vec<u8>) -> Self {
+    /// Creates an instance of MMU with all default values initialized. The ROM path is used to
+    /// find the save file of battery backed cartridges
+    pub fn new(rom: Vec<u8>, rom_path: Option<&Path>, rtc_clock: RtcClock) -> crate::error::Result<Self> {
         let mut mmu = Self::empty();
 
-        mmu.cartridge.load_rom(rom);
+        // Set before loading so a clock on wall time catches up with the time in the .sav
+        mmu.cartridge.set_rtc_clock(rtc_clock);
+        mmu.cartridge.load_rom(rom, rom_path)?;
+
+        // Cartridges that know about the CGB get its extra hardware, there is no CGB boot ROM so
+        // they start straight from the cartridge
+        let cgb = mmu.cartridge.header().is_some_and(|header| header.cgb != CgbSupport::None);
+        if cgb {
+            mmu.cgb = true;
+            mmu.gpu.set_cgb(true);
+            mmu.cartridge.booting = false;
+        }
         
         mmu.write_8(0xFF05, 0x00);
         mmu.write_8(0xFF06, 0x00);
@@ -133,7 +197,7 @@ impl MMU {
         mmu.write_8(0xFF4B, 0x00);
         mmu.write_8(0xFFFF, 0x00);
 
-        mmu
+        Ok(mmu)
     }
     
     /// Wrties a u8 to the indexed point in memory
@@ -149,8 +213,8 @@ impl MMU {
             ROM_START..=ROM_END => self.cartridge.handle_write(index, value),
             VRAM_START..=VRAM_END => self.gpu.handle_write(index, value),
             CARTRIDGE_RAM_START..=CARTRIDGE_RAM_END => self.cartridge.handle_write(index, value),
-            WORK_RAM_START..=WORK_RAM_END => self.wram[(index - WORK_RAM_START) as usize] = value,
-            ECHO_RAM_START..=ECHO_RAM_END => self.wram[(index - ECHO_RAM_START) as usize] = value,
+            WORK_RAM_START..=WORK_RAM_END => self.wram[self.wram_offset(index)] = value,
+            ECHO_RAM_START..=ECHO_RAM_END => self.wram[self.wram_offset(index)] = value,
             OAM_START..=OAM_END => self.gpu.handle_write(index, value),
             UNUSABLE_START..=UNUSABLE_END => (),
             IO_START..=IO_END => io = true,
@@ -164,12 +228,24 @@ impl MMU {
 
         match index {
             0xFF01 => self.serial = value as char,
-            0xFF02 => print!("{}", self.serial),
+            0xFF02 => {
+                print!("{}", self.serial);
+                if let Some(callback) = &mut self.serial_callback {
+                    callback(self.serial as u8);
+                }
+            },
             crate::joypad::JOYPAD_REG_LOC => self.joypad.handle_write(index, value),
             IF_LOC => self.interupt.handle_write(index, value),
             timer_stuff!() => self.timer.handle_write(index, value),
             apu_stuff!() => self.apu.handle_write(index, value),
             gpu_stuff!() => self.gpu.handle_write(index, value),
+            KEY1_LOC if self.cgb => self.speed_switch_armed = value & 1 == 1,
+            HDMA1_LOC if self.cgb => self.hdma.source = (self.hdma.source & 0x00FF) | (value as u16) << 8,
+            HDMA2_LOC if self.cgb => self.hdma.source = (self.hdma.source & 0xFF00) | (value & 0xF0) as u16,
+            HDMA3_LOC if self.cgb => self.hdma.destination = (self.hdma.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+            HDMA4_LOC if self.cgb => self.hdma.destination = (self.hdma.destination & 0xFF00) | (value & 0xF0) as u16,
+            HDMA5_LOC if self.cgb => self.start_hdma(value),
+            SVBK_LOC if self.cgb => self.wram_bank = (value & 0b111).max(1),
             _ => { log::warn!("Tried to write to non existent register {:x}", index)},
         }
 
@@ -196,8 +272,8 @@ impl MMU {
             ROM_START..=ROM_END => self.cartridge.handle_read(index),
             VRAM_START..=VRAM_END => self.gpu.handle_read(index),
             CARTRIDGE_RAM_START..=CARTRIDGE_RAM_END => self.cartridge.handle_read(index),
-            WORK_RAM_START..=WORK_RAM_END => self.wram[(index - WORK_RAM_START) as usize],
-            ECHO_RAM_START..=ECHO_RAM_END => self.wram[(index - ECHO_RAM_START) as usize],
+            WORK_RAM_START..=WORK_RAM_END => self.wram[self.wram_offset(index)],
+            ECHO_RAM_START..=ECHO_RAM_END => self.wram[self.wram_offset(index)],
             OAM_START..=OAM_END => self.gpu.handle_read(index),
             UNUSABLE_START..=UNUSABLE_END => 0xFF,
             IO_START..=IO_END => {
@@ -207,6 +283,9 @@ impl MMU {
                     timer_stuff!() => self.timer.handle_read(index),
                     apu_stuff!() => self.apu.handle_read(index),
                     gpu_stuff!() => self.gpu.handle_read(index),
+                    KEY1_LOC if self.cgb => (self.double_speed as u8) << 7 | 0b0111_1110 | self.speed_switch_armed as u8,
+                    HDMA5_LOC if self.cgb => (!self.hdma.active as u8) << 7 | self.hdma.remaining,
+                    SVBK_LOC if self.cgb => 0b1111_1000 | self.wram_bank,
                     _ => {log::warn!("Reading from memory that doesn't exist: {}", index); 0xFF}
                 }
             },
@@ -223,17 +302,32 @@ impl MMU {
         le_combine(ls, ms)
     }
 
-    pub fn tick(&mut self, ticks: u8, ignore_master: bool, shared_array: &Arc<Mutex<[[ColorPixel; 160]; 144]>>) -> crate::interupts::Interupt {
+    pub fn tick(&mut self, ticks: u8) {
         let mut interupts = 0;
+        // In double speed the CPU and timer run twice as fast as the PPU and RTC
+        let dots = if self.double_speed { ticks / 2 } else { ticks };
         
         interupts |= self.timer.update_time(ticks);
-        if self.joypad.interupt_possible {
+        self.cartridge.tick(dots);
+        if std::mem::take(&mut self.joypad.interupt_possible) {
             interupts |= 0b0001_0000;
         }
-        interupts |= self.gpu.update_graphics(ticks, shared_array);
+        interupts |= profile::time(Subsystem::Graphics, || self.gpu.update_graphics(dots));
+
+        // The frame sequencer follows DIV, which runs twice as fast in double speed
+        let sequencer_bit = if self.double_speed { 0b0010_0000 } else { 0b0001_0000 };
+        self.apu.tick(dots, self.timer.divider() & sequencer_bit != 0);
+
+        if std::mem::take(&mut self.gpu.hblank_started) && self.hdma.active {
+            self.hdma_block();
+        }
+
+        // Without the LCD there are no HBlanks to wait for, so the rest of the transfer goes at once
+        while self.hdma.active && !self.gpu.lcd_enabled() {
+            self.hdma_block();
+        }
 
         self.interupt.update_interupts(interupts);
-        self.interupt.do_interupts(ignore_master)
     }
 
     pub fn enable_interupts(&mut self) {
@@ -245,11 +339,135 @@ impl MMU {
     }
 
     pub fn dma_transfer(&mut self) {
-        let adress = (self.gpu.dma_transfer as u16) * 100;
+        let adress = (self.gpu.dma_transfer as u16) << 8;
         for i in 0..0xA0 {
-            self.write_8(0xFE00+i, self.read_8(adress+i));
+            self.gpu.write_oam(i as usize, self.read_8(adress+i));
         }
         self.gpu.dma_transfer = 0;
     }
 
+    /// What STOP does outside the CPU: DIV is reset and a CGB switches speed if that was asked
+    /// for through KEY1. Returns true if the speed was switched
+    pub fn stop(&mut self) -> bool {
+        self.timer.reset_divider();
+        self.switch_speed()
+    }
+
+    /// Switches between normal and double speed if it was asked for through KEY1
+    fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+
+    /// Offset into `wram` for an address in work RAM or its echo
+    fn wram_offset(&self, index: u16) -> usize {
+        let offset = (index & 0x1FFF) as usize;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank as usize * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    /// Handles a write to HDMA5, bit 7 picks between copying everything now and a block every
+    /// HBlank. Clearing bit 7 while an HBlank transfer runs stops it
+    fn start_hdma(&mut self, value: u8) {
+        if self.hdma.active && value & 0b1000_0000 == 0 {
+            self.hdma.active = false;
+            return;
+        }
+
+        self.hdma.remaining = value & 0b0111_1111;
+        self.hdma.active = true;
+
+        if value & 0b1000_0000 == 0 {
+            while self.hdma.active {
+                self.hdma_block();
+            }
+        }
+    }
+
+    /// Sets the function that gets each byte sent over the serial port
+    pub fn set_serial_callback(&mut self, callback: SerialCallback) {
+        self.serial_callback = Some(callback);
+    }
+
+    /// Clock cycles the CPU has to wait for HDMA transfers that happened since the last call
+    pub fn take_dma_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.hdma.stall)
+    }
+
+    /// Copies the next 16 bytes of an HDMA transfer
+    fn hdma_block(&mut self) {
+        for i in 0..HDMA_BLOCK_SIZE {
+            let value = self.read_8(self.hdma.source.wrapping_add(i));
+            self.gpu.write_vram(self.hdma.destination.wrapping_add(i), value);
+        }
+        self.hdma.source = self.hdma.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.hdma.destination = self.hdma.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FF0;
+        self.hdma.stall += if self.double_speed { HDMA_BLOCK_CYCLES * 2 } else { HDMA_BLOCK_CYCLES };
+
+        // Counting down past 0 leaves 0x7F, so HDMA5 reads 0xFF once the transfer is done
+        self.hdma.remaining = self.hdma.remaining.wrapping_sub(1) & 0b0111_1111;
+        if self.hdma.remaining == 0b0111_1111 {
+            self.hdma.active = false;
+        }
+    }
+
+}
+
+impl crate::state::SaveState for MMU {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_bytes(&self.wram);
+        writer.write_bytes(&self.hram);
+        writer.write_u8(self.serial as u8);
+        writer.write_bool(self.cgb);
+        writer.write_u8(self.wram_bank);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_u16(self.hdma.source);
+        writer.write_u16(self.hdma.destination);
+        writer.write_u8(self.hdma.remaining);
+        writer.write_bool(self.hdma.active);
+        writer.write_u32(self.hdma.stall);
+
+        self.gpu.save_state(writer);
+        self.timer.save_state(writer);
+        self.interupt.save_state(writer);
+        self.joypad.save_state(writer);
+        self.apu.save_state(writer);
+        self.cartridge.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        reader.read_bytes(&mut self.wram)?;
+        reader.read_bytes(&mut self.hram)?;
+        self.serial = reader.read_u8()? as char;
+        self.cgb = reader.read_bool()?;
+        self.wram_bank = reader.read_u8()? & 0b111;
+        if self.wram_bank == 0 {
+            return Err(crate::state::StateError::Invalid("WRAM bank"));
+        }
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+        self.hdma.source = reader.read_u16()?;
+        self.hdma.destination = reader.read_u16()? & 0x1FF0;
+        self.hdma.remaining = reader.read_u8()? & 0b0111_1111;
+        self.hdma.active = reader.read_bool()?;
+        self.hdma.stall = reader.read_u32()?;
+
+        self.gpu.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.interupt.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.cartridge.load_state(reader)
+    }
+
 }
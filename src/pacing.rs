@@ -0,0 +1,286 @@
+use crate::apu::CLOCK_SPEED;
+use crate::gameboy::CYCLES_PER_FRAME;
+
+use std::time::{ Duration, Instant };
+
+/// How long a frame lasts on real hardware
+pub const FRAME_DURATION: Duration = Duration::from_nanos(CYCLES_PER_FRAME * 1_000_000_000 / CLOCK_SPEED);
+/// How far behind the timer can fall before it gives up catching up and starts counting from now
+const MAX_LAG: Duration = Duration::from_millis(100);
+/// How full the audio buffer is kept when pacing to it
+const TARGET_FILL: f32 = 0.5;
+/// Longest the audio buffer can go without changing before the device is assumed to have
+/// stalled, and frames are paced by the timer until it moves again. Comfortably longer than the
+/// gap between two callbacks of a sound card
+const AUDIO_STALL: Duration = Duration::from_millis(50);
+/// Most the audio sample rate is sped up or slowed down to keep the buffer at `TARGET_FILL`.
+/// Half a percent is well under what anyone can hear as a change in pitch
+const MAX_RATE_ADJUST: f64 = 0.005;
+/// How often `SpeedMeter` works out a new speed
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+/// Speeds the emulator can be set to, from slow motion up to as fast as the host can go
+pub const SPEED_STEPS: [f64; 7] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, f64::INFINITY];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What decides when the next frame runs
+pub enum PacingMode {
+    /// Waits for the audio device to play back the buffered audio, so the emulator runs at
+    /// whatever speed the sound card's clock says. Falls back to `Timer` without audio, or while
+    /// the device isn't playing anything back
+    Audio,
+    /// Sleeps until each frame is due
+    #[default]
+    Timer,
+    /// Runs frames as fast as the host can
+    Unthrottled,
+}
+
+impl PacingMode {
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Audio => Self::Timer,
+            Self::Timer => Self::Unthrottled,
+            Self::Unthrottled => Self::Audio,
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What happens to the audio while the emulator runs faster or slower than a real Game Boy
+pub enum FastForwardAudio {
+    /// Plays the audio as it comes, higher or lower depending on the speed
+    PitchShift,
+    /// Keeps the pitch by skipping over or repeating short bits of the audio
+    #[default]
+    TimeStretch,
+    Mute,
+}
+
+impl FastForwardAudio {
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::PitchShift => Self::TimeStretch,
+            Self::TimeStretch => Self::Mute,
+            Self::Mute => Self::PitchShift,
+        }
+    }
+
+}
+
+/// Keeps the emulator running at the speed of a real Game Boy, or some multiple of it
+pub struct FramePacer {
+    mode: PacingMode,
+    /// 1.0 is full speed, infinity runs as fast as the host can
+    speed: f64,
+    /// When the next frame is due to start
+    deadline: Instant,
+    /// When a frame was last shown
+    last_render: Instant,
+    /// The audio buffer's fill the last time it was checked, and when it last changed
+    last_fill: f32,
+    fill_changed: Instant,
+}
+
+impl FramePacer {
+
+    pub fn new(mode: PacingMode) -> Self {
+        Self {
+            mode,
+            speed: 1.0,
+            deadline: Instant::now(),
+            last_render: Instant::now(),
+            last_fill: 0.0,
+            fill_changed: Instant::now(),
+        }
+    }
+
+    pub fn mode(&self) -> PacingMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PacingMode) {
+        self.mode = mode;
+        self.deadline = Instant::now();
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.deadline = Instant::now();
+    }
+
+    /// Waits after a frame until the next one should run. `audio_fill` reports how full the
+    /// audio buffer is between 0.0 and 1.0, when there is one. The audio has to be kept playing
+    /// in real time at any speed other than 1.0, or left out
+    pub fn wait(&mut self, audio_fill: Option<&dyn Fn() -> f32>) {
+        if self.speed.is_infinite() {
+            self.deadline = Instant::now();
+            return;
+        }
+
+        match (self.mode, audio_fill) {
+            (PacingMode::Audio, Some(fill)) => {
+                loop {
+                    let current = fill();
+                    if current != self.last_fill {
+                        self.last_fill = current;
+                        self.fill_changed = Instant::now();
+                    }
+
+                    if current <= TARGET_FILL {
+                        self.deadline = Instant::now();
+                        return;
+                    }
+                    if self.fill_changed.elapsed() >= AUDIO_STALL {
+                        // Nothing is playing the buffer back, so it can't be what sets the pace
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                self.wait_for_deadline();
+            },
+            (PacingMode::Audio | PacingMode::Timer, _) => self.wait_for_deadline(),
+            (PacingMode::Unthrottled, _) => self.deadline = Instant::now(),
+        }
+    }
+
+    /// Sleeps until the next frame is due going by the timer
+    fn wait_for_deadline(&mut self) {
+        self.deadline += FRAME_DURATION.div_f64(self.speed);
+
+        let now = Instant::now();
+        if now > self.deadline + MAX_LAG {
+            // Running this far behind usually means the host was busy or asleep, so rushing
+            // through the missed frames would only make things worse
+            self.deadline = now;
+        } else if self.deadline > now {
+            std::thread::sleep(self.deadline - now);
+        }
+    }
+
+    /// Frame skipping: whether the frame that just ran should be shown. Above full speed frames
+    /// come faster than a screen can show them, so only about one a refresh gets through
+    pub fn should_render(&mut self) -> bool {
+        // A little under a whole frame, so jitter just above full speed doesn't drop frames
+        if self.speed > 1.0 && self.last_render.elapsed() < FRAME_DURATION * 3 / 4 {
+            return false;
+        }
+
+        self.last_render = Instant::now();
+        true
+    }
+
+}
+
+/// Dynamic rate control: how much to scale the audio sample rate by so an audio buffer that is
+/// `fill` full drifts back towards `TARGET_FILL`. Emptier buffers get a few more samples per
+/// frame and fuller ones a few less, see `GameBoy::set_audio_rate_scale`
+pub fn audio_rate_scale(fill: f32) -> f64 {
+    let error = (TARGET_FILL - fill.clamp(0.0, 1.0)) as f64 / TARGET_FILL as f64;
+    1.0 + MAX_RATE_ADJUST * error.clamp(-1.0, 1.0)
+}
+
+/// Measures how fast the emulator actually runs compared to a real Game Boy
+pub struct SpeedMeter {
+    start: Instant,
+    cycles: u64,
+    frames: u32,
+    /// Emulated time divided by real time over the last interval
+    speed: f64,
+    fps: f64,
+}
+
+impl Default for SpeedMeter {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            cycles: 0,
+            frames: 0,
+            speed: 0.0,
+            fps: 0.0,
+        }
+    }
+}
+
+impl SpeedMeter {
+
+    /// Counts a frame that ran for `cycles` clock cycles. Returns true every time a new
+    /// measurement is ready
+    pub fn record_frame(&mut self, cycles: u64) -> bool {
+        self.cycles += cycles;
+        self.frames += 1;
+
+        let elapsed = self.start.elapsed();
+        if elapsed < MEASURE_INTERVAL {
+            return false;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        self.speed = self.cycles as f64 / CLOCK_SPEED as f64 / seconds;
+        self.fps = self.frames as f64 / seconds;
+
+        self.start = Instant::now();
+        self.cycles = 0;
+        self.frames = 0;
+        true
+    }
+
+    /// 1.0 is full speed, 2.0 twice as fast as a Game Boy
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Frames run per second of real time
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn audio_pacing_waits_for_the_buffer_to_drain() {
+        let mut pacer = FramePacer::new(PacingMode::Audio);
+
+        // Played back a bit every time it's checked
+        let level = Cell::new(0.9);
+        let draining = || {
+            level.set(level.get() - 0.01);
+            level.get()
+        };
+        pacer.wait(Some(&draining));
+        assert!(level.get() <= TARGET_FILL);
+        assert!(level.get() > TARGET_FILL - 0.02);
+
+        // A buffer that is already low doesn't hold anything up
+        let start = Instant::now();
+        pacer.wait(Some(&|| 0.1));
+        assert!(start.elapsed() < FRAME_DURATION);
+    }
+
+    #[test]
+    fn stalled_audio_falls_back_to_the_timer() {
+        let mut pacer = FramePacer::new(PacingMode::Audio);
+        let start = Instant::now();
+
+        // Nothing is taking audio out of the buffer, so it never gets down to the target
+        for _ in 0..10 {
+            pacer.wait(Some(&|| 0.8));
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= FRAME_DURATION * 9, "{:?}", elapsed);
+        // Only the first frame waits to see whether the buffer moves
+        assert!(elapsed < FRAME_DURATION * 10 + AUDIO_STALL + Duration::from_millis(100), "{:?}", elapsed);
+    }
+
+}
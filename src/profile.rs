@@ -0,0 +1,88 @@
+//! Where emulation time goes, split up by subsystem for the benchmarks. Only measured when built
+//! with the `profile` feature, otherwise `time` just runs what it's given
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Parts of the emulator that are timed separately. Each only counts its own time, so the
+/// graphics run during a memory read are taken off the read
+pub enum Subsystem {
+    /// Fetching and looking up opcodes and reading their operands
+    Decode,
+    /// `MMU::read_8` for the CPU
+    MemoryRead,
+    /// `MMU::write_8` for the CPU
+    MemoryWrite,
+    /// `GPU::update_graphics`
+    Graphics,
+    /// The rest of `MMU::tick`, the timer, APU, cartridge and DMA
+    Hardware,
+}
+
+impl Subsystem {
+
+    pub const ALL: [Self; 5] = [Self::Decode, Self::MemoryRead, Self::MemoryWrite, Self::Graphics, Self::Hardware];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Decode => "CPU decode",
+            Self::MemoryRead => "MMU::read_8",
+            Self::MemoryWrite => "MMU::write_8",
+            Self::Graphics => "GPU::update_graphics",
+            Self::Hardware => "other hardware",
+        }
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    pub time: Duration,
+    pub calls: u64,
+}
+
+/// Whether this build measures anything
+pub const ENABLED: bool = cfg!(feature = "profile");
+
+#[cfg(feature = "profile")]
+thread_local! {
+    static TIMINGS: std::cell::RefCell<[Timing; 5]> = Default::default();
+    /// Time spent in timed subsystems since the innermost one started
+    static NESTED: std::cell::Cell<Duration> = Default::default();
+}
+
+#[cfg(feature = "profile")]
+/// Runs `f`, counting the time it takes towards `subsystem`
+pub fn time<T>(subsystem: Subsystem, f: impl FnOnce() -> T) -> T {
+    let outer = NESTED.with(|nested| nested.replace(Duration::ZERO));
+    let start = std::time::Instant::now();
+
+    let result = f();
+
+    let elapsed = start.elapsed();
+    let nested = NESTED.with(|nested| nested.replace(outer + elapsed));
+    TIMINGS.with(|timings| {
+        let timing = &mut timings.borrow_mut()[subsystem as usize];
+        timing.time += elapsed.saturating_sub(nested);
+        timing.calls += 1;
+    });
+
+    result
+}
+
+#[cfg(not(feature = "profile"))]
+#[inline(always)]
+/// Runs `f`, counting the time it takes towards `subsystem`
+pub fn time<T>(_subsystem: Subsystem, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// The time spent in each subsystem on this thread since the last call, in the order of
+/// `Subsystem::ALL`. All zeros without the `profile` feature
+pub fn take() -> [Timing; 5] {
+    #[cfg(feature = "profile")]
+    return TIMINGS.with(|timings| std::mem::take(&mut *timings.borrow_mut()));
+
+    #[cfg(not(feature = "profile"))]
+    [Timing::default(); 5]
+}
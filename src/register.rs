@@ -135,3 +135,24 @@ impl Registers {
         (((num & 0xFF00) >> 8) as u8, (num & 0xFF) as u8)
     }
 }
+
+impl crate::state::SaveState for Registers {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        for val in [self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            writer.write_u8(val);
+        }
+        writer.write_u16(self.sp);
+        writer.write_u16(self.pc);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        for reg in [&mut self.a, &mut self.f, &mut self.b, &mut self.c, &mut self.d, &mut self.e, &mut self.h, &mut self.l] {
+            *reg = reader.read_u8()?;
+        }
+        self.sp = reader.read_u16()?;
+        self.pc = reader.read_u16()?;
+        Ok(())
+    }
+
+}
@@ -0,0 +1,262 @@
+use crate::gameboy::GameBoy;
+
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy)]
+/// Settings for how much history the rewind buffer keeps
+pub struct RewindConfig {
+    /// Most bytes the buffer can use before old snapshots are dropped
+    pub memory_budget: usize,
+    /// Frames that pass between two snapshots, rewinding steps back this many frames at a time
+    pub interval: u32,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        Self {
+            memory_budget: 32 * 1024 * 1024,
+            interval: 2,
+        }
+    }
+}
+
+/// Ring buffer of whole machine snapshots used to rewind gameplay.
+///
+/// Only the newest snapshot is kept in full, every older one is stored as the run length encoded
+/// XOR against the snapshot after it. Most of memory doesn't change between frames so the XOR
+/// is mostly zeroes and each delta ends up a small fraction of a full save state.
+pub struct RewindBuffer {
+    config: RewindConfig,
+    /// Newest snapshot, the last delta turns it into the one before it
+    current: Option<Vec<u8>>,
+    /// Oldest delta at the front, newest at the back
+    deltas: VecDeque<Vec<u8>>,
+    /// Bytes used by all the deltas
+    used: usize,
+    /// Frames recorded since the last snapshot
+    frames: u32,
+}
+
+impl RewindBuffer {
+
+    pub fn new(config: RewindConfig) -> Self {
+        Self {
+            config,
+            current: None,
+            deltas: VecDeque::new(),
+            used: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    /// Changes the budget and interval, dropping old snapshots if they no longer fit
+    pub fn set_config(&mut self, config: RewindConfig) {
+        self.config = config;
+        self.trim();
+    }
+
+    /// Drops all history, for example when a new ROM is loaded
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.frames = 0;
+    }
+
+    /// Number of snapshots that can currently be stepped back to
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Bytes used by the buffer including the newest full snapshot
+    pub fn memory_used(&self) -> usize {
+        self.used + self.current.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Called once per emulated frame, takes a snapshot every `interval` frames
+    pub fn record_frame(&mut self, gameboy: &GameBoy) {
+        self.frames += 1;
+        if self.frames < self.config.interval.max(1) {
+            return;
+        }
+        self.frames = 0;
+
+        self.push(gameboy.save_state());
+    }
+
+    /// Adds a snapshot as the newest entry
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(current) = &self.current {
+            if current.len() == snapshot.len() {
+                let delta = encode_delta(current, &snapshot);
+                self.used += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // A different ROM or format, nothing older is usable anymore
+                self.deltas.clear();
+                self.used = 0;
+            }
+        }
+
+        self.current = Some(snapshot);
+        self.trim();
+    }
+
+    /// Steps back one snapshot and loads it into the machine. Returns false when there is no
+    /// more history to go back to
+    pub fn rewind(&mut self, gameboy: &mut GameBoy) -> bool {
+        let (Some(current), Some(delta)) = (&mut self.current, self.deltas.pop_back()) else {
+            return false;
+        };
+
+        self.used -= delta.len();
+        apply_delta(current, &delta);
+        self.frames = 0;
+
+        match gameboy.load_state(current) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Could not rewind: {}", e);
+                self.clear();
+                false
+            },
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.memory_used() > self.config.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+}
+
+/// Encodes `older ^ newer` as pairs of (zero run, literal run) lengths with the literal bytes
+/// following each pair
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < older.len() {
+        let zero_start = i;
+        while i < older.len() && older[i] == newer[i] {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < older.len() && older[i] != newer[i] {
+            i += 1;
+        }
+
+        write_varint(&mut out, literal_start - zero_start);
+        write_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(|j| older[j] ^ newer[j]));
+    }
+
+    out
+}
+
+/// XORs a delta made by `encode_delta` into a snapshot
+fn apply_delta(snapshot: &mut [u8], delta: &[u8]) {
+    let mut position = 0;
+    let mut i = 0;
+
+    while i < delta.len() {
+        position += read_varint(delta, &mut i);
+        let literals = read_varint(delta, &mut i);
+
+        for byte in &delta[i..i + literals] {
+            snapshot[position] ^= byte;
+            position += 1;
+        }
+        i += literals;
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(bytes: &[u8], i: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = bytes[*i];
+        *i += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_turns_the_newer_snapshot_back_into_the_older_one() {
+        let older: Vec<u8> = (0..5000u32).map(|i| (i * 7 + i / 300) as u8).collect();
+        let mut newer = older.clone();
+        // A changed byte at each end, a run longer than one varint byte and some scattered ones
+        newer[0] ^= 0xFF;
+        newer[4999] = newer[4999].wrapping_add(1);
+        for byte in &mut newer[1000..1300] {
+            *byte = !*byte;
+        }
+        for i in (2000..4000).step_by(97) {
+            newer[i] = 0;
+        }
+
+        let delta = encode_delta(&older, &newer);
+        assert!(delta.len() < older.len() / 4);
+
+        let mut snapshot = newer.clone();
+        apply_delta(&mut snapshot, &delta);
+        assert_eq!(snapshot, older);
+    }
+
+    #[test]
+    fn identical_snapshots_are_a_single_zero_run() {
+        let snapshot = vec![0x5A; 300];
+        let delta = encode_delta(&snapshot, &snapshot);
+        // 300 zeroes as a two byte varint, then no literals
+        assert_eq!(delta, [0xAC, 0x02, 0x00]);
+
+        let mut applied = snapshot.clone();
+        apply_delta(&mut applied, &delta);
+        assert_eq!(applied, snapshot);
+    }
+
+    #[test]
+    fn varints_survive_a_round_trip() {
+        let values = [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 58013, usize::MAX >> 1];
+        let mut bytes = Vec::new();
+        for value in values {
+            write_varint(&mut bytes, value);
+        }
+
+        let mut i = 0;
+        for value in values {
+            assert_eq!(read_varint(&bytes, &mut i), value);
+        }
+        assert_eq!(i, bytes.len());
+    }
+
+}
@@ -0,0 +1,245 @@
+/*
+ *  Save state layout:
+ *  0000-0003 Magic "JEMS"
+ *  0004-0005 Format version (little endian)
+ *  0006-0007 Global checksum of the ROM the state was made with
+ *  0008      Header checksum of the ROM the state was made with
+ *  0009-     Component data, in the order the components write it
+ */
+
+/// Bytes every save state starts with
+const MAGIC: &[u8; 4] = b"JEMS";
+/// Bumped whenever the layout of any component changes, older states are rejected
+pub const FORMAT_VERSION: u16 = 13;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Reasons a save state can't be restored
+pub enum StateError {
+    /// The data doesn't start with the save state magic bytes
+    NotAState,
+    /// The state was written by a different version of the format
+    UnsupportedVersion(u16),
+    /// The state was made with a different ROM than the one that is loaded
+    RomMismatch,
+    /// The data ended before every component was read
+    UnexpectedEnd,
+    /// A value in the state is out of range for the component reading it
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAState => write!(f, "data is not a save state"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported, expected {}", version, FORMAT_VERSION)
+            },
+            Self::RomMismatch => write!(f, "save state was made with a different ROM"),
+            Self::UnexpectedEnd => write!(f, "save state ended early"),
+            Self::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Implemented by every component that is part of a save state
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+/// Appends little endian values to a save state buffer
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+
+    /// Creates a writer that already holds the header for a ROM with the given checksums
+    pub fn new(global_checksum: u16, header_checksum: u8) -> Self {
+        let mut writer = Self::default();
+        writer.write_bytes(MAGIC);
+        writer.write_u16(FORMAT_VERSION);
+        writer.write_u16(global_checksum);
+        writer.write_u8(header_checksum);
+        writer
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.bytes.push(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Writes a fixed size block, the reader has to know its length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Writes a block prefixed by its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+}
+
+/// Reads little endian values back out of a save state buffer
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+
+    /// Checks the header of a save state and returns a reader positioned at the component data
+    pub fn new(bytes: &'a [u8], global_checksum: u16, header_checksum: u8) -> Result<Self, StateError> {
+        let mut reader = Self { bytes, position: 0 };
+
+        let mut magic = [0; 4];
+        reader.read_bytes(&mut magic).map_err(|_| StateError::NotAState)?;
+        if &magic != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let version = reader.read_u16()?;
+        if version != FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        if reader.read_u16()? != global_checksum || reader.read_u8()? != header_checksum {
+            return Err(StateError::RomMismatch);
+        }
+
+        Ok(reader)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position + len;
+        if end > self.bytes.len() {
+            return Err(StateError::UnexpectedEnd);
+        }
+
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Fills a fixed size block written with `write_bytes`
+    pub fn read_bytes(&mut self, into: &mut [u8]) -> Result<(), StateError> {
+        into.copy_from_slice(self.take(into.len())?);
+        Ok(())
+    }
+
+    /// Reads a block written with `write_vec`
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Fills a block written with `write_vec`, failing if the length doesn't match
+    pub fn read_vec_into(&mut self, into: &mut [u8]) -> Result<(), StateError> {
+        let len = self.read_u32()? as usize;
+        if len != into.len() {
+            return Err(StateError::Invalid("block length"));
+        }
+        self.read_bytes(into)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_read_back_in_the_order_written() {
+        let mut writer = StateWriter::new(0x1234, 0x56);
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0xBEEF);
+        writer.write_u32(0xDEAD_BEEF);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_vec(&[4, 5]);
+        writer.write_vec(&[6, 7, 8]);
+        let bytes = writer.into_bytes();
+        assert_eq!(&bytes[..4], MAGIC);
+
+        let mut reader = StateReader::new(&bytes, 0x1234, 0x56).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0xBEEF));
+        assert_eq!(reader.read_u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(reader.read_u64(), Ok(u64::MAX - 1));
+        let mut fixed = [0; 3];
+        reader.read_bytes(&mut fixed).unwrap();
+        assert_eq!(fixed, [1, 2, 3]);
+        assert_eq!(reader.read_vec(), Ok(vec![4, 5]));
+        let mut wrong_length = [0; 2];
+        assert_eq!(reader.read_vec_into(&mut wrong_length), Err(StateError::Invalid("block length")));
+        let mut rest = [0; 3];
+        reader.read_bytes(&mut rest).unwrap();
+        assert_eq!(rest, [6, 7, 8]);
+        assert_eq!(reader.read_u8(), Err(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn header_must_match_the_loaded_rom_and_version() {
+        let bytes = StateWriter::new(0x1234, 0x56).into_bytes();
+
+        assert!(StateReader::new(&bytes, 0x1234, 0x56).is_ok());
+        assert_eq!(StateReader::new(&bytes, 0x1235, 0x56).err(), Some(StateError::RomMismatch));
+        assert_eq!(StateReader::new(&bytes, 0x1234, 0x57).err(), Some(StateError::RomMismatch));
+        assert_eq!(StateReader::new(b"JEM", 0x1234, 0x56).err(), Some(StateError::NotAState));
+        assert_eq!(StateReader::new(b"SAVE0000", 0x1234, 0x56).err(), Some(StateError::NotAState));
+
+        let mut old = bytes.clone();
+        old[4..6].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+        assert_eq!(
+            StateReader::new(&old, 0x1234, 0x56).err(),
+            Some(StateError::UnsupportedVersion(FORMAT_VERSION - 1)),
+        );
+    }
+
+}
@@ -14,80 +14,242 @@ pub struct Timer {
     tima: u8,
     tma: u8,
     tmc: u8,
-    /// Divide corresponds to the divide register
-    divide: u8,
+    /// Internal counter that goes up every clock cycle, DIV is its upper byte
+    counter: u16,
 
-    time_elapsed: u32,
-    divide_elapsed: u32,
+    /// TIMA overflowed during the last M-cycle and reads as 0 until it gets reloaded
+    overflowed: bool,
+    /// TIMA is being reloaded from TMA this M-cycle, writes to TIMA are ignored and writes to
+    /// TMA go through to TIMA as well
+    reloading: bool,
 }
 
 impl crate::mmu::Memory for Timer {
 
     fn handle_read(&self, index: u16) -> u8 {
         match index {
-            DIVIDE_LOC => self.divide,
+            DIVIDE_LOC => self.divider(),
             TIMA_LOC => self.tima,
             TMA_LOC => self.tma,
-            TMC_LOC => self.tmc,
+            TMC_LOC => self.tmc | 0b1111_1000,
             _ => unreachable!("Timer does not handle this memory")
         }
     }
 
     fn handle_write(&mut self, index: u16, val: u8) {
         match index {
-            DIVIDE_LOC => self.divide = 0,
-            TIMA_LOC => self.tima = val,
-            TMA_LOC => self.tma = val,
-            TMC_LOC => self.tmc = val,
+            DIVIDE_LOC => self.set_counter(0),
+            TIMA_LOC => {
+                // Writing during the overflow cycle cancels the reload and the interupt
+                if !self.reloading {
+                    self.tima = val;
+                    self.overflowed = false;
+                }
+            },
+            TMA_LOC => {
+                self.tma = val;
+                if self.reloading {
+                    self.tima = val;
+                }
+            },
+            TMC_LOC => {
+                // Turning the timer off or picking another bit can look like a falling edge
+                let before = self.timer_bit();
+                self.tmc = val & 0b111;
+                if before && !self.timer_bit() {
+                    self.increment_tima();
+                }
+            },
             _ => unreachable!("Timer does not handle this memory")
         };
     }
    
 }
 
-const HZ_4069: u32 = 1024;
-const HZ_262144: u32 = 16;
-const HZ_65536: u32 = 64;
-const HZ_16384: u32 =  256;
+/*
+ *  TIMA goes up every time the bit of the internal counter picked by TMC falls from 1 to 0,
+ *  which happens at these rates:
+ *  TMC    0       1         2        3
+ *  Bit    9       3         5        7
+ *  Rate   4096Hz  262144Hz  65536Hz  16384Hz
+ */
+const TIMER_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+/// Clock cycles in an M-cycle, the timer is stepped this many at a time
+const M_CYCLE: u8 = 4;
 
 impl Timer {
 
+    /// The value of DIV, which the APU's frame sequencer runs off
+    pub fn divider(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    /// Sets DIV and the rest of the internal counter back to 0, like writing to DIV does
+    pub fn reset_divider(&mut self) {
+        self.set_counter(0);
+    }
+
+    /// Runs the timer for some clock cycles, which are always a whole number of M-cycles.
+    /// Returns the timer interupt flag if it was raised
     pub fn update_time(&mut self, ticks: u8) -> u8 {
         let mut ret = 0;
 
-        self.divide_elapsed += ticks as u32;
-        
-        if self.divide_elapsed >= 255 {
-            self.divide_elapsed = 0;
-            self.divide = self.divide.wrapping_add(1);
-        }
-        
-        // If timer enabled
-        if (self.tmc & 0b0000_0100) == 0b0000_0100 {
-            let frequency = match self.tmc & 0b0000_0011 {
-                0b0000_0000 => HZ_4069,
-                0b0000_0001 => HZ_262144,
-                0b0000_0010 => HZ_65536,
-                0b0000_0011 => HZ_16384,
-                _ => unreachable!()
-            };
-            
-            self.time_elapsed += ticks as u32;
-
-            while self.time_elapsed >= frequency {
-
-                if self.tima == 255 {
-                    self.tima = self.tma;
-                    ret |= 0b0000_0100;
-                    
-                } else {
-                    self.tima += 1;
-                }
-                
-                self.time_elapsed -= frequency;
+        for _ in 0..ticks / M_CYCLE {
+            self.reloading = false;
+
+            // The reload happens an M-cycle after the overflow, until then TIMA reads as 0
+            if self.overflowed {
+                self.overflowed = false;
+                self.reloading = true;
+                self.tima = self.tma;
+                ret |= 0b0000_0100;
             }
+
+            self.set_counter(self.counter.wrapping_add(M_CYCLE as u16));
         }
 
         ret
     }
+
+    /// Whether the counter bit TIMA follows is set, always false while the timer is off
+    fn timer_bit(&self) -> bool {
+        self.tmc & 0b0000_0100 == 0b0000_0100 && self.counter & TIMER_BITS[(self.tmc & 0b11) as usize] != 0
+    }
+
+    /// Changes the internal counter, incrementing TIMA if that made its bit fall
+    fn set_counter(&mut self, counter: u16) {
+        let before = self.timer_bit();
+        self.counter = counter;
+        if before && !self.timer_bit() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflowed {
+            self.overflowed = true;
+        }
+    }
+}
+
+impl crate::state::SaveState for Timer {
+
+    fn save_state(&self, writer: &mut crate::state::StateWriter) {
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tmc);
+        writer.write_u16(self.counter);
+        writer.write_bool(self.overflowed);
+        writer.write_bool(self.reloading);
+    }
+
+    fn load_state(&mut self, reader: &mut crate::state::StateReader) -> Result<(), crate::state::StateError> {
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tmc = reader.read_u8()? & 0b111;
+        self.counter = reader.read_u16()?;
+        self.overflowed = reader.read_bool()?;
+        self.reloading = reader.read_bool()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Memory;
+
+    /// A timer on the 16 clock rate, with its counter at `counter`
+    fn timer_at(counter: u16) -> Timer {
+        let mut timer = Timer::default();
+        timer.handle_write(TMC_LOC, 0b101);
+        timer.counter = counter;
+        timer
+    }
+
+    #[test]
+    fn tima_goes_up_when_the_bit_falls() {
+        let mut timer = timer_at(0);
+
+        // Bit 3 rises after 8 clock cycles, which doesn't count
+        timer.update_time(8);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0);
+        timer.update_time(8);
+        assert_eq!(timer.handle_read(TIMA_LOC), 1);
+
+        timer.update_time(160);
+        assert_eq!(timer.handle_read(TIMA_LOC), 11);
+        assert_eq!(timer.handle_read(DIVIDE_LOC), 0);
+        timer.update_time(80);
+        assert_eq!(timer.handle_read(DIVIDE_LOC), 1);
+    }
+
+    #[test]
+    fn resetting_div_can_make_the_bit_fall() {
+        let mut timer = timer_at(0b1000);
+        timer.handle_write(DIVIDE_LOC, 0x12);
+        assert_eq!(timer.handle_read(TIMA_LOC), 1);
+
+        // With the bit already clear nothing happens
+        timer.handle_write(DIVIDE_LOC, 0x12);
+        assert_eq!(timer.handle_read(TIMA_LOC), 1);
+    }
+
+    #[test]
+    fn changing_tac_can_make_the_bit_fall() {
+        // Bit 9 is set but bit 3 isn't, so moving from the slowest rate to the fastest one
+        // looks like a falling edge
+        let mut timer = timer_at(1 << 9);
+        timer.handle_write(TMC_LOC, 0b100);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0);
+        timer.handle_write(TMC_LOC, 0b101);
+        assert_eq!(timer.handle_read(TIMA_LOC), 1);
+
+        // So does turning the timer off while the bit is set
+        let mut timer = timer_at(0b1000);
+        timer.handle_write(TMC_LOC, 0b001);
+        assert_eq!(timer.handle_read(TIMA_LOC), 1);
+        assert_eq!(timer.handle_read(TMC_LOC), 0b1111_1001);
+
+        // Turning it off with the bit clear doesn't
+        let mut timer = timer_at(0);
+        timer.handle_write(TMC_LOC, 0b001);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0);
+    }
+
+    #[test]
+    fn overflow_reloads_from_tma_an_m_cycle_later() {
+        let mut timer = timer_at(0);
+        timer.handle_write(TMA_LOC, 0x80);
+        timer.handle_write(TIMA_LOC, 0xFF);
+
+        assert_eq!(timer.update_time(16), 0);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0);
+        assert_eq!(timer.update_time(4), 0b100);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0x80);
+
+        // TIMA writes are ignored on the reload cycle, TMA writes go through to TIMA
+        timer.handle_write(TIMA_LOC, 0x10);
+        timer.handle_write(TMA_LOC, 0x90);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0x90);
+        timer.update_time(4);
+        timer.handle_write(TIMA_LOC, 0x10);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0x10);
+    }
+
+    #[test]
+    fn writing_tima_while_it_reads_0_cancels_the_reload() {
+        let mut timer = timer_at(0);
+        timer.handle_write(TMA_LOC, 0x80);
+        timer.handle_write(TIMA_LOC, 0xFF);
+
+        timer.update_time(16);
+        timer.handle_write(TIMA_LOC, 0x42);
+        assert_eq!(timer.update_time(4), 0);
+        assert_eq!(timer.handle_read(TIMA_LOC), 0x42);
+    }
+
 }